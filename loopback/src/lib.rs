@@ -4,11 +4,16 @@ use alloc::boxed::Box;
 use alloc::collections::VecDeque;
 use alloc::vec;
 use alloc::vec::Vec;
-use netcore::common::NetError;
-use netcore::{EthernetAddress, Medium, NetBufOps, NetDriverOps};
+use core::any::Any;
+use netcore::common::{NetError, IPV4_MIN_MTU};
+use netcore::{ChecksumOffload, EthernetAddress, Medium, NetBufOps, NetDriverOps, TcpSegmentation};
+
+/// The MTU of the loopback device, as Linux `lo` has.
+pub const LOOPBACK_MTU: usize = 64 * 1024;
 
 pub struct LoopbackDev {
-    queue: VecDeque<Vec<u8>>,
+    queue: VecDeque<NetBuf>,
+    mtu: usize,
 }
 
 impl Default for LoopbackDev {
@@ -21,6 +26,7 @@ impl LoopbackDev {
     pub fn new() -> Self {
         Self {
             queue: VecDeque::new(),
+            mtu: LOOPBACK_MTU,
         }
     }
 }
@@ -59,34 +65,108 @@ impl NetDriverOps for LoopbackDev {
     }
 
     fn transmit(&mut self, tx_buf: Box<dyn NetBufOps>) -> Result<(), NetError> {
-        self.queue.push_back(tx_buf.packet().to_vec());
+        let tx_buf =
+            unsafe { core::mem::transmute::<Box<dyn NetBufOps>, Box<dyn Any + Send>>(tx_buf) };
+        let mut tx_buf = tx_buf.downcast::<NetBuf>().unwrap();
+        // do what a NIC does with the checksum left to it.
+        if let Some((start, offset)) = tx_buf.checksum_partial.take() {
+            let checksum = !checksum(&tx_buf.buf[start..]);
+            tx_buf.buf[start + offset..start + offset + 2].copy_from_slice(&checksum.to_be_bytes());
+        }
+        // a packet to segment is received whole, as if coalesced again.
+        self.queue.push_back(*tx_buf);
         Ok(())
     }
 
     fn receive(&mut self) -> Result<Box<dyn NetBufOps>, NetError> {
         let buf = self.queue.pop_front().unwrap();
-        Ok(Box::new(NetBuf(buf)))
+        Ok(Box::new(buf))
     }
 
     fn alloc_tx_buffer(&mut self, size: usize) -> Result<Box<dyn NetBufOps>, NetError> {
-        let mut buffer = vec![0; size];
-        buffer.resize(size, 0);
-        Ok(Box::new(NetBuf(buffer)))
+        Ok(Box::new(NetBuf {
+            buf: vec![0; size],
+            checksum_partial: None,
+            segmentation: None,
+        }))
+    }
+
+    fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    fn set_mtu(&mut self, mtu: usize) -> Result<(), NetError> {
+        if !(IPV4_MIN_MTU..=LOOPBACK_MTU).contains(&mtu) {
+            return Err(NetError::InvalidInput);
+        }
+        self.mtu = mtu;
+        Ok(())
+    }
+
+    fn checksum_offload(&self) -> ChecksumOffload {
+        // packets never leave memory, there is nothing to check, and the
+        // checksums left to the device are filled in on transmit.
+        ChecksumOffload::all()
+    }
+
+    fn tcp_segmentation_offload(&self) -> bool {
+        // nothing limits the size of a packet in memory, so packets of any
+        // size go through whole, like with TSO on Linux `lo`.
+        true
     }
 }
 
-struct NetBuf(Vec<u8>);
+struct NetBuf {
+    buf: Vec<u8>,
+    /// Where to checksum the packet on transmit, as `csum_start` and
+    /// `csum_offset`.
+    checksum_partial: Option<(usize, usize)>,
+    /// How the packet would have been segmented on the wire.
+    segmentation: Option<TcpSegmentation>,
+}
 
 impl NetBufOps for NetBuf {
     fn packet(&self) -> &[u8] {
-        self.0.as_slice()
+        self.buf.as_slice()
     }
 
     fn packet_mut(&mut self) -> &mut [u8] {
-        self.0.as_mut_slice()
+        self.buf.as_mut_slice()
     }
 
     fn packet_len(&self) -> usize {
-        self.0.len()
+        self.buf.len()
+    }
+
+    fn checksum_verified(&self) -> bool {
+        true
+    }
+
+    fn set_checksum_partial(&mut self, start: usize, offset: usize) {
+        self.checksum_partial = Some((start, offset));
     }
+
+    fn segmentation(&self) -> Option<TcpSegmentation> {
+        self.segmentation
+    }
+
+    fn set_segmentation(&mut self, segmentation: TcpSegmentation) {
+        self.segmentation = Some(segmentation);
+    }
+}
+
+/// The one's complement sum of `data`, not complemented yet.
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum = data
+        .chunks(2)
+        .map(|word| match *word {
+            [hi, lo] => u16::from_be_bytes([hi, lo]) as u32,
+            [hi] => u16::from_be_bytes([hi, 0]) as u32,
+            _ => unreachable!(),
+        })
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
 }
@@ -0,0 +1,188 @@
+//! Future-based wrappers around [`TcpSocket`] and [`UdpSocket`] for async
+//! executors.
+//!
+//! The futures never poll the interface themselves. The kernel must keep
+//! calling [`poll_interfaces`](crate::poll_interfaces), e.g. from the NIC
+//! interrupt or a timer, and the tasks are woken as it makes progress.
+
+use core::future::poll_fn;
+use core::net::SocketAddr;
+use core::task::{Context, Poll};
+
+use crate::common::{NetError, NetResult};
+use crate::poller::NetPollable;
+use crate::tcp::TcpSocket;
+use crate::udp::UdpSocket;
+
+/// Runs the nonblocking operation `f`, and if it would block, registers the
+/// task to be woken when `socket` changes.
+///
+/// `f` is retried once after registering, so a change that happens in
+/// between is not lost.
+pub(crate) fn poll_io<S, T, F>(socket: &S, cx: &mut Context<'_>, mut f: F) -> Poll<NetResult<T>>
+where
+    S: NetPollable,
+    F: FnMut() -> NetResult<T>,
+{
+    match f() {
+        Err(NetError::WouldBlock) => {}
+        res => return Poll::Ready(res),
+    }
+    socket.register_waker(cx.waker());
+    match f() {
+        Err(NetError::WouldBlock) => Poll::Pending,
+        res => Poll::Ready(res),
+    }
+}
+
+/// An async TCP connection.
+pub struct AsyncTcpStream {
+    inner: TcpSocket,
+}
+
+impl AsyncTcpStream {
+    fn from_socket(inner: TcpSocket) -> Self {
+        inner.set_nonblocking(true);
+        Self { inner }
+    }
+
+    /// Opens a connection to `remote_addr`.
+    pub async fn connect(remote_addr: SocketAddr) -> NetResult<Self> {
+        let stream = Self::from_socket(TcpSocket::new());
+        match stream.inner.connect(remote_addr) {
+            Ok(()) | Err(NetError::WouldBlock) => {}
+            Err(e) => return Err(e),
+        }
+        poll_fn(|cx| {
+            poll_io(&stream.inner, cx, || {
+                let state = stream.inner.poll()?;
+                if state.error {
+                    Err(stream
+                        .inner
+                        .take_error()
+                        .unwrap_or(NetError::ConnectionRefused))
+                } else if state.writable {
+                    Ok(())
+                } else {
+                    Err(NetError::WouldBlock)
+                }
+            })
+        })
+        .await?;
+        Ok(stream)
+    }
+
+    /// Reads some data into `buf`, returning 0 once the peer has closed.
+    pub async fn read(&self, buf: &mut [u8]) -> NetResult<usize> {
+        poll_fn(|cx| poll_io(&self.inner, cx, || self.inner.recv(buf))).await
+    }
+
+    /// Writes some data from `buf`, returning how much was queued.
+    pub async fn write(&self, buf: &[u8]) -> NetResult<usize> {
+        poll_fn(|cx| poll_io(&self.inner, cx, || self.inner.send(buf))).await
+    }
+
+    /// Closes the connection.
+    pub fn shutdown(&self) -> NetResult<()> {
+        self.inner.shutdown()
+    }
+
+    /// Returns the local address and port.
+    pub fn local_addr(&self) -> NetResult<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    /// Returns the remote address and port.
+    pub fn peer_addr(&self) -> NetResult<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    /// The underlying socket, for options and readiness.
+    pub fn socket(&self) -> &TcpSocket {
+        &self.inner
+    }
+}
+
+/// An async TCP server socket.
+pub struct AsyncTcpListener {
+    inner: TcpSocket,
+}
+
+impl AsyncTcpListener {
+    /// Binds to `local_addr` and starts listening.
+    pub fn bind(local_addr: SocketAddr) -> NetResult<Self> {
+        let inner = TcpSocket::new();
+        inner.set_nonblocking(true);
+        inner.bind(local_addr)?;
+        inner.listen()?;
+        Ok(Self { inner })
+    }
+
+    /// Waits for a new connection.
+    pub async fn accept(&self) -> NetResult<(AsyncTcpStream, SocketAddr)> {
+        let socket = poll_fn(|cx| poll_io(&self.inner, cx, || self.inner.accept())).await?;
+        let peer_addr = socket.peer_addr()?;
+        Ok((AsyncTcpStream::from_socket(socket), peer_addr))
+    }
+
+    /// Returns the local address and port.
+    pub fn local_addr(&self) -> NetResult<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    /// The underlying socket, for options and readiness.
+    pub fn socket(&self) -> &TcpSocket {
+        &self.inner
+    }
+}
+
+/// An async UDP socket.
+pub struct AsyncUdpSocket {
+    inner: UdpSocket,
+}
+
+impl AsyncUdpSocket {
+    /// Creates a UDP socket bound to `local_addr`.
+    pub fn bind(local_addr: SocketAddr) -> NetResult<Self> {
+        let inner = UdpSocket::new();
+        inner.set_nonblocking(true);
+        inner.bind(local_addr)?;
+        Ok(Self { inner })
+    }
+
+    /// Sets the default destination of [`send`](Self::send) and the only
+    /// source [`recv`](Self::recv) accepts.
+    pub fn connect(&self, addr: SocketAddr) -> NetResult<()> {
+        self.inner.connect(addr)
+    }
+
+    /// Sends a datagram to `remote_addr`.
+    pub async fn send_to(&self, buf: &[u8], remote_addr: SocketAddr) -> NetResult<usize> {
+        poll_fn(|cx| poll_io(&self.inner, cx, || self.inner.send_to(buf, remote_addr))).await
+    }
+
+    /// Receives a datagram, returning its length and origin.
+    pub async fn recv_from(&self, buf: &mut [u8]) -> NetResult<(usize, SocketAddr)> {
+        poll_fn(|cx| poll_io(&self.inner, cx, || self.inner.recv_from(buf))).await
+    }
+
+    /// Sends a datagram to the connected address.
+    pub async fn send(&self, buf: &[u8]) -> NetResult<usize> {
+        poll_fn(|cx| poll_io(&self.inner, cx, || self.inner.send(buf))).await
+    }
+
+    /// Receives a datagram from the connected address.
+    pub async fn recv(&self, buf: &mut [u8]) -> NetResult<usize> {
+        poll_fn(|cx| poll_io(&self.inner, cx, || self.inner.recv(buf))).await
+    }
+
+    /// Returns the local address and port.
+    pub fn local_addr(&self) -> NetResult<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    /// The underlying socket, for options and readiness.
+    pub fn socket(&self) -> &UdpSocket {
+        &self.inner
+    }
+}
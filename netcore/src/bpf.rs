@@ -0,0 +1,433 @@
+//! Classic BPF, the socket filters of `SO_ATTACH_FILTER`.
+//!
+//! A program is checked once by [`BpfProgram::new`], so that running it on
+//! a packet can neither loop nor read outside of its scratch memory.
+
+use alloc::vec::Vec;
+
+use log::warn;
+
+use crate::common::{NetError, NetResult};
+
+/// The longest program accepted, like Linux's `BPF_MAXINSNS`.
+pub const BPF_MAXINSNS: usize = 4096;
+/// The number of words of scratch memory, `BPF_MEMWORDS`.
+const BPF_MEMWORDS: usize = 16;
+
+// instruction classes
+const BPF_LD: u16 = 0x00;
+const BPF_LDX: u16 = 0x01;
+const BPF_ST: u16 = 0x02;
+const BPF_STX: u16 = 0x03;
+const BPF_ALU: u16 = 0x04;
+const BPF_JMP: u16 = 0x05;
+const BPF_RET: u16 = 0x06;
+const BPF_MISC: u16 = 0x07;
+
+// load sizes
+const BPF_W: u16 = 0x00;
+const BPF_H: u16 = 0x08;
+const BPF_B: u16 = 0x10;
+
+// load modes
+const BPF_IMM: u16 = 0x00;
+const BPF_ABS: u16 = 0x20;
+const BPF_IND: u16 = 0x40;
+const BPF_MEM: u16 = 0x60;
+const BPF_LEN: u16 = 0x80;
+const BPF_MSH: u16 = 0xa0;
+
+// ALU operations
+const BPF_ADD: u16 = 0x00;
+const BPF_SUB: u16 = 0x10;
+const BPF_MUL: u16 = 0x20;
+const BPF_DIV: u16 = 0x30;
+const BPF_OR: u16 = 0x40;
+const BPF_AND: u16 = 0x50;
+const BPF_LSH: u16 = 0x60;
+const BPF_RSH: u16 = 0x70;
+const BPF_NEG: u16 = 0x80;
+const BPF_MOD: u16 = 0x90;
+const BPF_XOR: u16 = 0xa0;
+
+// jumps
+const BPF_JA: u16 = 0x00;
+const BPF_JEQ: u16 = 0x10;
+const BPF_JGT: u16 = 0x20;
+const BPF_JGE: u16 = 0x30;
+const BPF_JSET: u16 = 0x40;
+
+// operand sources
+const BPF_K: u16 = 0x00;
+const BPF_X: u16 = 0x08;
+/// The accumulator, as the value of a return.
+const BPF_A: u16 = 0x10;
+
+// misc operations
+const BPF_TAX: u16 = 0x00;
+const BPF_TXA: u16 = 0x80;
+
+/// One classic BPF instruction, laid out like Linux's `struct sock_filter`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SockFilter {
+    pub code: u16,
+    pub jt: u8,
+    pub jf: u8,
+    pub k: u32,
+}
+
+/// A verified classic BPF program.
+#[derive(Debug, Clone)]
+pub struct BpfProgram {
+    insns: Vec<SockFilter>,
+}
+
+impl BpfProgram {
+    /// Checks `insns` and builds a program from them.
+    ///
+    /// Returns [`Err(InvalidInput)`](NetError::InvalidInput) if the program
+    /// is empty or too long, has an unknown instruction, jumps out of the
+    /// program, uses scratch memory out of range, divides by a zero constant,
+    /// or does not end with a return.
+    pub fn new(insns: &[SockFilter]) -> NetResult<Self> {
+        if insns.is_empty() || insns.len() > BPF_MAXINSNS {
+            warn!("BPF program rejected: {} instructions", insns.len());
+            return Err(NetError::InvalidInput);
+        }
+        for (pc, insn) in insns.iter().enumerate() {
+            if !check_insn(insn, insns.len() - pc - 1) {
+                warn!("BPF program rejected: bad instruction {} {:?}", pc, insn);
+                return Err(NetError::InvalidInput);
+            }
+        }
+        if insns[insns.len() - 1].code & 0x07 != BPF_RET {
+            warn!("BPF program rejected: no final return");
+            return Err(NetError::InvalidInput);
+        }
+        Ok(Self {
+            insns: insns.to_vec(),
+        })
+    }
+
+    /// Runs the program on `packet`, returning how many bytes of it to
+    /// keep. 0 drops the packet.
+    ///
+    /// A load beyond the end of the packet, or a division by zero, drops the
+    /// packet too.
+    pub fn run(&self, packet: &[u8]) -> u32 {
+        self.exec(packet).unwrap_or(0)
+    }
+
+    fn exec(&self, packet: &[u8]) -> Option<u32> {
+        let mut a: u32 = 0;
+        let mut x: u32 = 0;
+        let mut mem = [0u32; BPF_MEMWORDS];
+        let mut pc = 0;
+        loop {
+            // the verifier ensures that `pc` stays in the program.
+            let insn = self.insns[pc];
+            let k = insn.k;
+            pc += 1;
+            match insn.code & 0x07 {
+                BPF_LD => {
+                    a = match insn.code & 0xe0 {
+                        BPF_IMM => k,
+                        BPF_ABS => load(packet, k as usize, insn.code & 0x18)?,
+                        BPF_IND => load(packet, x.wrapping_add(k) as usize, insn.code & 0x18)?,
+                        BPF_MEM => mem[k as usize],
+                        BPF_LEN => packet.len() as u32,
+                        _ => unreachable!(),
+                    }
+                }
+                BPF_LDX => {
+                    x = match insn.code & 0xe0 {
+                        BPF_IMM => k,
+                        BPF_MEM => mem[k as usize],
+                        BPF_LEN => packet.len() as u32,
+                        BPF_MSH => (*packet.get(k as usize)? as u32 & 0x0f) * 4,
+                        _ => unreachable!(),
+                    }
+                }
+                BPF_ST => mem[k as usize] = a,
+                BPF_STX => mem[k as usize] = x,
+                BPF_ALU => {
+                    let operand = if insn.code & BPF_X != 0 { x } else { k };
+                    a = match insn.code & 0xf0 {
+                        BPF_ADD => a.wrapping_add(operand),
+                        BPF_SUB => a.wrapping_sub(operand),
+                        BPF_MUL => a.wrapping_mul(operand),
+                        BPF_DIV => a.checked_div(operand)?,
+                        BPF_MOD => a.checked_rem(operand)?,
+                        BPF_OR => a | operand,
+                        BPF_AND => a & operand,
+                        BPF_XOR => a ^ operand,
+                        BPF_LSH => a.checked_shl(operand).unwrap_or(0),
+                        BPF_RSH => a.checked_shr(operand).unwrap_or(0),
+                        BPF_NEG => a.wrapping_neg(),
+                        _ => unreachable!(),
+                    }
+                }
+                BPF_JMP => {
+                    let operand = if insn.code & BPF_X != 0 { x } else { k };
+                    let taken = match insn.code & 0xf0 {
+                        BPF_JA => {
+                            pc += k as usize;
+                            continue;
+                        }
+                        BPF_JEQ => a == operand,
+                        BPF_JGT => a > operand,
+                        BPF_JGE => a >= operand,
+                        BPF_JSET => a & operand != 0,
+                        _ => unreachable!(),
+                    };
+                    pc += if taken { insn.jt } else { insn.jf } as usize;
+                }
+                BPF_RET => {
+                    return Some(match insn.code & 0x18 {
+                        BPF_K => k,
+                        BPF_X => x,
+                        _ => a,
+                    })
+                }
+                BPF_MISC => {
+                    if insn.code & 0xf8 == BPF_TAX {
+                        x = a;
+                    } else {
+                        a = x;
+                    }
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+}
+
+/// Whether `insn` is valid, with `remaining` instructions after it.
+fn check_insn(insn: &SockFilter, remaining: usize) -> bool {
+    let code = insn.code;
+    let k = insn.k as usize;
+    match code & 0x07 {
+        BPF_LD => match (code & 0xe0, code & 0x18) {
+            (BPF_ABS | BPF_IND, BPF_W | BPF_H | BPF_B) => code & !0xf8 == BPF_LD,
+            (BPF_IMM | BPF_LEN, BPF_W) => code == (code & 0xe0) | BPF_LD,
+            (BPF_MEM, BPF_W) => code == BPF_MEM | BPF_LD && k < BPF_MEMWORDS,
+            _ => false,
+        },
+        BPF_LDX => match code {
+            0x01 | 0x81 => true,      // LDX|W|IMM, LDX|W|LEN
+            0x61 => k < BPF_MEMWORDS, // LDX|W|MEM
+            0xb1 => true,             // LDX|B|MSH
+            _ => false,
+        },
+        BPF_ST | BPF_STX => code & !0x07 == 0 && k < BPF_MEMWORDS,
+        BPF_ALU => {
+            let op = code & 0xf0;
+            let src = code & BPF_X;
+            if code & !0xf8 != BPF_ALU || op > BPF_XOR {
+                return false;
+            }
+            match (op, src) {
+                (BPF_NEG, _) => src == BPF_K,
+                (BPF_DIV | BPF_MOD, BPF_K) => k != 0,
+                (BPF_LSH | BPF_RSH, BPF_K) => k < 32,
+                _ => true,
+            }
+        }
+        BPF_JMP => {
+            let op = code & 0xf0;
+            if code & !0xf8 != BPF_JMP || op > BPF_JSET {
+                return false;
+            }
+            if op == BPF_JA {
+                code == BPF_JMP && k < remaining
+            } else {
+                (insn.jt as usize) < remaining && (insn.jf as usize) < remaining
+            }
+        }
+        BPF_RET => matches!(code & !0x07, BPF_K | BPF_X | BPF_A),
+        BPF_MISC => matches!(code & !0x07, BPF_TAX | BPF_TXA),
+        _ => unreachable!(),
+    }
+}
+
+/// Loads a big-endian word, half-word or byte at `offset` of `packet`.
+fn load(packet: &[u8], offset: usize, size: u16) -> Option<u32> {
+    let len = match size {
+        BPF_W => 4,
+        BPF_H => 2,
+        _ => 1,
+    };
+    let bytes = packet.get(offset..offset.checked_add(len)?)?;
+    Some(bytes.iter().fold(0, |word, &byte| word << 8 | byte as u32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insn(code: u16, jt: u8, jf: u8, k: u32) -> SockFilter {
+        SockFilter { code, jt, jf, k }
+    }
+
+    fn stmt(code: u16, k: u32) -> SockFilter {
+        insn(code, 0, 0, k)
+    }
+
+    /// The start of an IPv4 header with protocol `protocol` and a header of
+    /// `ihl` words.
+    fn ipv4_header(protocol: u8, ihl: u8) -> [u8; 20] {
+        let mut header = [0; 20];
+        header[0] = 0x40 | ihl;
+        header[9] = protocol;
+        header
+    }
+
+    /// Accepts UDP packets whole and drops the others, like
+    /// `tcpdump -dd udp` on a raw socket.
+    fn udp_only() -> BpfProgram {
+        BpfProgram::new(&[
+            stmt(BPF_LD | BPF_B | BPF_ABS, 9),
+            insn(BPF_JMP | BPF_JEQ | BPF_K, 0, 1, 17),
+            stmt(BPF_RET | BPF_K, 0xffff),
+            stmt(BPF_RET | BPF_K, 0),
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn rejects_empty_and_oversized_programs() {
+        assert_eq!(BpfProgram::new(&[]).err(), Some(NetError::InvalidInput));
+        let long = alloc::vec![stmt(BPF_RET | BPF_K, 0); BPF_MAXINSNS + 1];
+        assert_eq!(BpfProgram::new(&long).err(), Some(NetError::InvalidInput));
+        let longest = alloc::vec![stmt(BPF_RET | BPF_K, 0); BPF_MAXINSNS];
+        assert!(BpfProgram::new(&longest).is_ok());
+    }
+
+    #[test]
+    fn rejects_unterminated_programs() {
+        let insns = [stmt(BPF_RET | BPF_K, 0), stmt(BPF_LD | BPF_IMM, 1)];
+        assert!(BpfProgram::new(&insns).is_err());
+        assert!(BpfProgram::new(&[stmt(BPF_MISC | BPF_TAX, 0)]).is_err());
+    }
+
+    #[test]
+    fn checks_jump_bounds() {
+        // a conditional jump may land on the last instruction, not past it.
+        let last = [
+            insn(BPF_JMP | BPF_JEQ | BPF_K, 1, 0, 0),
+            stmt(BPF_RET | BPF_K, 1),
+            stmt(BPF_RET | BPF_K, 2),
+        ];
+        assert!(BpfProgram::new(&last).is_ok());
+        let past_true = [insn(BPF_JMP | BPF_JEQ | BPF_K, 2, 0, 0), last[1], last[2]];
+        assert!(BpfProgram::new(&past_true).is_err());
+        let past_false = [insn(BPF_JMP | BPF_JEQ | BPF_K, 0, 2, 0), last[1], last[2]];
+        assert!(BpfProgram::new(&past_false).is_err());
+        // and so does an unconditional one, whose offset is `k`.
+        let ja = [stmt(BPF_JMP | BPF_JA, 1), last[1], last[2]];
+        assert!(BpfProgram::new(&ja).is_ok());
+        let ja_past = [stmt(BPF_JMP | BPF_JA, 2), last[1], last[2]];
+        assert!(BpfProgram::new(&ja_past).is_err());
+        let ja_wrapped = [stmt(BPF_JMP | BPF_JA, u32::MAX), last[1], last[2]];
+        assert!(BpfProgram::new(&ja_wrapped).is_err());
+    }
+
+    #[test]
+    fn rejects_illegal_instructions() {
+        let ret = stmt(BPF_RET | BPF_K, 0);
+        for bad in [
+            // unknown opcodes
+            stmt(0xffff, 0),
+            stmt(BPF_ALU | 0xb0 | BPF_K, 0),
+            stmt(BPF_JMP | 0x50 | BPF_K, 0),
+            stmt(BPF_LD | BPF_MSH | BPF_B, 0),
+            stmt(BPF_LDX | BPF_ABS | BPF_W, 0),
+            stmt(BPF_RET | 0x18, 0),
+            // scratch memory out of range
+            stmt(BPF_LD | BPF_MEM, BPF_MEMWORDS as u32),
+            stmt(BPF_LDX | BPF_MEM, BPF_MEMWORDS as u32),
+            stmt(BPF_ST, BPF_MEMWORDS as u32),
+            stmt(BPF_STX, u32::MAX),
+            // division by a zero constant, and shifts out of the word
+            stmt(BPF_ALU | BPF_DIV | BPF_K, 0),
+            stmt(BPF_ALU | BPF_MOD | BPF_K, 0),
+            stmt(BPF_ALU | BPF_LSH | BPF_K, 32),
+            stmt(BPF_ALU | BPF_NEG | BPF_X, 0),
+        ] {
+            assert!(BpfProgram::new(&[bad, ret]).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn filters_by_protocol() {
+        let filter = udp_only();
+        assert_eq!(filter.run(&ipv4_header(17, 5)), 0xffff);
+        assert_eq!(filter.run(&ipv4_header(6, 5)), 0);
+    }
+
+    #[test]
+    fn drops_on_out_of_bounds_loads() {
+        let filter = udp_only();
+        assert_eq!(filter.run(&ipv4_header(17, 5)[..9]), 0);
+        let word = BpfProgram::new(&[stmt(BPF_LD | BPF_W | BPF_ABS, 17), stmt(BPF_RET | BPF_K, 1)])
+            .unwrap();
+        assert_eq!(word.run(&[0; 21]), 1);
+        assert_eq!(word.run(&[0; 20]), 0);
+        let indirect = BpfProgram::new(&[
+            stmt(BPF_LDX | BPF_IMM, u32::MAX),
+            stmt(BPF_LD | BPF_H | BPF_IND, 2),
+            stmt(BPF_RET | BPF_K, 1),
+        ])
+        .unwrap();
+        assert_eq!(indirect.run(&[0; 4]), 1);
+        assert_eq!(indirect.run(&[0; 2]), 0);
+    }
+
+    #[test]
+    fn drops_on_division_by_zero() {
+        let filter = BpfProgram::new(&[
+            stmt(BPF_LD | BPF_IMM, 10),
+            stmt(BPF_LDX | BPF_LEN, 0),
+            stmt(BPF_ALU | BPF_DIV | BPF_X, 0),
+            stmt(BPF_RET | BPF_A, 0),
+        ])
+        .unwrap();
+        assert_eq!(filter.run(&[0; 2]), 5);
+        assert_eq!(filter.run(&[]), 0);
+    }
+
+    #[test]
+    fn runs_loads_stores_and_alu() {
+        // the length of the IPv4 header, through the scratch memory.
+        let filter = BpfProgram::new(&[
+            stmt(BPF_LDX | BPF_B | BPF_MSH, 0),
+            stmt(BPF_STX, 3),
+            stmt(BPF_LD | BPF_MEM, 3),
+            stmt(BPF_ALU | BPF_ADD | BPF_K, 1),
+            stmt(BPF_ALU | BPF_LSH | BPF_K, 1),
+            stmt(BPF_MISC | BPF_TAX, 0),
+            stmt(BPF_RET | BPF_X, 0),
+        ])
+        .unwrap();
+        assert_eq!(filter.run(&ipv4_header(17, 5)), 42);
+        assert_eq!(filter.run(&ipv4_header(17, 15)), 122);
+    }
+
+    #[test]
+    fn jumps() {
+        let filter = BpfProgram::new(&[
+            stmt(BPF_LD | BPF_LEN, 0),
+            insn(BPF_JMP | BPF_JGT | BPF_K, 0, 1, 4),
+            stmt(BPF_JMP | BPF_JA, 1),
+            stmt(BPF_RET | BPF_K, 1),
+            insn(BPF_JMP | BPF_JSET | BPF_K, 0, 1, 1),
+            stmt(BPF_RET | BPF_K, 2),
+            stmt(BPF_RET | BPF_K, 3),
+        ])
+        .unwrap();
+        assert_eq!(filter.run(&[0; 4]), 1);
+        assert_eq!(filter.run(&[0; 5]), 2);
+        assert_eq!(filter.run(&[0; 6]), 3);
+    }
+}
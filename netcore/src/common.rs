@@ -1,12 +1,47 @@
+use crate::{KERNEL_NET_FUNC, SOCKET_SET};
+
 pub const TCP_RX_BUF_LEN: usize = 64 * 1024;
 pub const TCP_TX_BUF_LEN: usize = 64 * 1024;
 pub const UDP_RX_BUF_LEN: usize = 64 * 1024;
 pub const UDP_TX_BUF_LEN: usize = 64 * 1024;
+pub const ICMP_RX_BUF_LEN: usize = 16 * 1024;
+pub const ICMP_TX_BUF_LEN: usize = 16 * 1024;
+pub const RAW_RX_BUF_LEN: usize = 64 * 1024;
+pub const RAW_TX_BUF_LEN: usize = 64 * 1024;
+/// The IP protocol of raw sockets that send any protocol (`IPPROTO_RAW`).
+///
+/// smoltcp rebuilds the header of the packets its raw sockets send, so
+/// netcore hands it the packets of raw sockets wrapped in one of this
+/// protocol, which smoltcp never sends itself, and the device sends the
+/// packet inside as it is.
+pub const IPPROTO_RAW: u8 = 255;
+pub const PACKET_RX_BUF_LEN: usize = 64 * 1024;
 pub const LISTEN_QUEUE_SIZE: usize = 512;
+/// The MTU of Ethernet, and of the drivers that don't report theirs.
 pub const STANDARD_MTU: usize = 1500;
+/// How long the fragments of an IPv4 datagram wait for the rest of it.
+pub const IPV4_REASSEMBLY_TIMEOUT_SECS: u64 = 30;
+/// The most payload bytes held for IPv4 datagrams being reassembled.
+pub const MAX_REASSEMBLY_BYTES: usize = 256 * 1024;
+/// The most IPv4 datagrams reassembled at once.
+pub const MAX_REASSEMBLY_DATAGRAMS: usize = 64;
+/// How long a path MTU learned from ICMP is kept.
+pub const PMTU_EXPIRY_SECS: u64 = 10 * 60;
+/// The lowest MTU every IPv4 link must carry (RFC 791).
+pub const IPV4_MIN_MTU: usize = 68;
+/// The lowest path MTU an ICMP message can set.
+pub const MIN_PMTU: usize = 552;
+/// The most destinations the path MTU cache remembers.
+pub const MAX_PMTU_ENTRIES: usize = 256;
+/// The most TCP connections whose MSS is remembered for TSO.
+pub const MAX_MSS_ENTRIES: usize = 1024;
 pub const SOCKET_RECV_BUFFER_SIZE: usize = 64 * 1024;
 pub const SOCKET_SEND_BUFFER_SIZE: usize = 64 * 1024;
+/// The TCP MSS over the [`STANDARD_MTU`]. netcore derives the real one from
+/// the MTU the driver reports.
 pub const MAX_SEGMENT_SIZE: usize = 1460;
+/// The index of the only interface of netcore, as reported to sockets.
+pub const IFACE_INDEX: u32 = 1;
 
 pub type NetResult<T> = Result<T, NetError>;
 
@@ -24,13 +59,68 @@ pub enum NetError {
     Interrupted,
     Again,
     DeviceError,
+    NotFound,
+    TimedOut,
+    NoBufs,
+    PermissionDenied,
+    HostUnreachable,
+    NetworkUnreachable,
 }
 
 /// Struct for poll result.
-#[derive(Debug, Default)]
+///
+/// There is no urgent data readiness (`POLLPRI`): smoltcp ignores the urgent
+/// pointer of TCP, and delivers urgent bytes inline with the rest of the
+/// stream, as with `SO_OOBINLINE`.
+#[derive(Debug, Default, Clone, Copy)]
 pub struct NetPollState {
     /// Object can be read now.
     pub readable: bool,
     /// Object can be writen now.
     pub writable: bool,
+    /// An error is pending and can be fetched with `take_error` (`POLLERR`).
+    pub error: bool,
+    /// Object is closed in both directions, or was never connected (`POLLHUP`).
+    pub hangup: bool,
+    /// The peer has shut down its writing half (`POLLRDHUP`).
+    pub read_hangup: bool,
+}
+
+/// Block the current thread until the given function completes or fails.
+///
+/// If the socket is `nonblocking`, it calls the function once and returns
+/// immediately. Otherwise, it may call the function multiple times if it
+/// returns [`Err(WouldBlock)`](NetError::WouldBlock), and returns
+/// [`Err(Interrupted)`](NetError::Interrupted) if a signal arrives meanwhile.
+pub(crate) fn block_on<F, T>(nonblocking: bool, mut f: F) -> NetResult<T>
+where
+    F: FnMut() -> NetResult<T>,
+{
+    if nonblocking {
+        f()
+    } else {
+        loop {
+            SOCKET_SET.poll_interfaces();
+            match f() {
+                Ok(t) => return Ok(t),
+                Err(NetError::WouldBlock) => {
+                    let kernel_func = KERNEL_NET_FUNC.get().unwrap();
+                    let has_signal = kernel_func.yield_now();
+                    if !has_signal {
+                        continue;
+                    }
+                    return Err(NetError::Interrupted);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Copies a datagram, message or frame into `buf`, truncating it if `buf` is
+/// too small. Returns the number of bytes copied.
+pub(crate) fn copy_truncated(buf: &mut [u8], data: &[u8]) -> usize {
+    let len = data.len().min(buf.len());
+    buf[..len].copy_from_slice(&data[..len]);
+    len
 }
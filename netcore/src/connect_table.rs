@@ -0,0 +1,74 @@
+use alloc::collections::BTreeMap;
+
+use smoltcp::wire::{IpEndpoint, TcpSeqNumber};
+
+use crate::common::NetError;
+use kernel_sync::TicketMutex as Mutex;
+
+/// A TCP connection attempt.
+struct ConnectAttempt {
+    /// The sequence number of the SYN, the only one in flight.
+    iss: TcpSeqNumber,
+    /// The ICMP error that aborted the attempt.
+    error: Option<NetError>,
+}
+
+/// The TCP connection attempts, by local and remote endpoint.
+///
+/// smoltcp neither tells the sequence numbers of a socket, which tell the
+/// ICMP errors about its SYN from forged ones, nor keeps why an attempt
+/// failed, so netcore records both here.
+pub struct ConnectTable {
+    attempts: Mutex<BTreeMap<(IpEndpoint, IpEndpoint), ConnectAttempt>>,
+}
+
+impl Default for ConnectTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConnectTable {
+    pub const fn new() -> Self {
+        Self {
+            attempts: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Record a SYN with the sequence number `iss` sent from `local` to
+    /// `remote`, which starts a new attempt.
+    pub fn outgoing_syn(&self, local: IpEndpoint, remote: IpEndpoint, iss: TcpSeqNumber) {
+        self.attempts
+            .lock()
+            .insert((local, remote), ConnectAttempt { iss, error: None });
+    }
+
+    /// Record that the attempt from `local` to `remote` failed with the ICMP
+    /// error `err`, quoting a segment with the sequence number `seq`.
+    ///
+    /// Returns whether the error is about the attempt: its SYN is the only
+    /// segment in flight, so `seq` must be the one of the SYN (SND.UNA to
+    /// SND.NXT, RFC 5927, section 4.1).
+    pub fn incoming_icmp_error(
+        &self,
+        local: IpEndpoint,
+        remote: IpEndpoint,
+        seq: TcpSeqNumber,
+        err: NetError,
+    ) -> bool {
+        let mut attempts = self.attempts.lock();
+        match attempts.get_mut(&(local, remote)) {
+            Some(attempt) if attempt.iss == seq => {
+                attempt.error = Some(err);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Forget the attempt from `local` to `remote`, returning the error that
+    /// aborted it, if any.
+    pub fn remove(&self, local: IpEndpoint, remote: IpEndpoint) -> Option<NetError> {
+        self.attempts.lock().remove(&(local, remote))?.error
+    }
+}
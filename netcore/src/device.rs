@@ -1,13 +1,29 @@
-use crate::common::{NetError, STANDARD_MTU};
-use crate::{KernelNetFunc, NetBufOps, NetDriverOps, LISTENING_TABLE};
+use crate::common::{NetError, NetResult, IPPROTO_RAW, STANDARD_MTU};
+use crate::dns;
+use crate::event::WakeKey;
+use crate::interface::NetInterface;
+use crate::udp_table::UdpRecvMeta;
+use crate::{
+    ChecksumOffload, KernelNetFunc, NetBufOps, NetDriverOps, ProtocolOffload, TcpSegmentation,
+    CONNECT_TABLE, ICMP_TABLE, KERNEL_NET_FUNC, LISTENING_TABLE, MSS_TABLE, NET_INTERFACE,
+    PACKET_TABLE, RAW_TABLE, REASSEMBLY_TABLE, ROUTE_TABLE, SOCKET_EVENTS, UDP_TABLE,
+};
 use alloc::boxed::Box;
 use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
 use core::cell::RefCell;
+use core::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
 use log::{info, warn};
 use preprint::pprintln;
 use smoltcp::iface::SocketSet;
-use smoltcp::phy::{Device, DeviceCapabilities, Medium, RxToken, TxToken};
+use smoltcp::phy::{Checksum, Device, DeviceCapabilities, Medium, RxToken, TxToken};
+use smoltcp::socket::tcp::{self, State};
 use smoltcp::time::Instant;
+use smoltcp::wire::{
+    EthernetAddress, EthernetFrame, EthernetProtocol, Icmpv4Message, IpAddress, IpEndpoint,
+    IpProtocol, Ipv4Address, TcpSeqNumber,
+};
 
 pub struct NetDeviceWrapper {
     inner: RefCell<Box<dyn NetDriverOps>>,
@@ -21,6 +37,43 @@ impl NetDeviceWrapper {
             timer,
         }
     }
+
+    /// Sends a whole frame built by the caller, bypassing smoltcp.
+    pub fn transmit_frame(&mut self, frame: &[u8]) -> NetResult<()> {
+        let link = LinkInfo::of(&**self.inner.borrow());
+        let min_len = match link.medium {
+            Medium::Ethernet => EthernetFrame::<&[u8]>::header_len(),
+            _ => 1,
+        };
+        if frame.len() < min_len || frame.len() > link_header_len(link.medium) + link.mtu {
+            warn!("transmit_frame() failed: bad frame length {}", frame.len());
+            return Err(NetError::InvalidInput);
+        }
+        let mut dev = self.inner.borrow_mut();
+        dev.recycle_tx_buffers()?;
+        if !dev.can_transmit() {
+            return Err(NetError::WouldBlock);
+        }
+        let mut tx_buf = dev.alloc_tx_buffer(frame.len())?;
+        tx_buf.packet_mut()[..frame.len()].copy_from_slice(frame);
+        info!("SEND {} bytes (packet socket)", frame.len());
+        dev.transmit(tx_buf)
+    }
+
+    /// The MTU of the link, as the driver reports it.
+    pub fn mtu(&self) -> usize {
+        self.inner.borrow().mtu()
+    }
+
+    /// Asks the driver to change the MTU of the link to `mtu`.
+    pub fn set_mtu(&mut self, mtu: usize) -> NetResult<()> {
+        self.inner.borrow_mut().set_mtu(mtu)
+    }
+
+    /// Asks the NIC to receive the frames sent to the multicast `addrs`.
+    pub fn set_multicast_filter(&mut self, addrs: &[EthernetAddress]) -> NetResult<()> {
+        self.inner.borrow_mut().set_multicast_filter(addrs)
+    }
 }
 
 impl Device for NetDeviceWrapper {
@@ -33,16 +86,59 @@ impl Device for NetDeviceWrapper {
             warn!("recycle_tx_buffers failed: {:?}", e);
             return None;
         }
-        if !dev.can_receive() {
-            return None;
-        }
-        match dev.receive() {
-            Ok(buf) => Some((NetRxToken(&self.inner, buf), NetTxToken(&self.inner))),
-            Err(e) => {
-                if !matches!(e, NetError::Again) {
-                    warn!("receive failed: {:?}", e);
+        let link = LinkInfo::of(&**dev);
+        loop {
+            if !dev.can_receive() {
+                return None;
+            }
+            match dev.receive() {
+                Ok(buf) => {
+                    let is_ethernet = link.medium == Medium::Ethernet;
+                    let local = is_ethernet.then(|| dev.mac_address());
+                    let (buf, reassembled) = if is_fragment(buf.packet(), link.medium) {
+                        // packet sockets see the fragments, the rest of the
+                        // host the whole datagram.
+                        PACKET_TABLE.incoming_frame(buf.packet(), local);
+                        let datagram = reassemble(&*buf, link.medium, local);
+                        if let Err(e) = dev.recycle_rx_buffer(buf) {
+                            warn!("recycle_rx_buffer failed: {:?}", e);
+                            return None;
+                        }
+                        match datagram {
+                            Some(datagram) => (Box::new(datagram) as Box<dyn NetBufOps>, true),
+                            None => continue,
+                        }
+                    } else {
+                        (buf, false)
+                    };
+                    // smoltcp skips the checksums the NIC offloads, for every packet.
+                    let checked = buf.checksum_verified() || link.checksum.udp.rx;
+                    if !buf.checksum_verified() && !verify_offloaded(buf.packet(), &link) {
+                        warn!("dropped a packet with a bad checksum");
+                    } else if snoop_udp(buf.packet(), is_ethernet, checked).unwrap_or(true) {
+                        let rx_token = NetRxToken(&self.inner, buf, reassembled);
+                        return Some((rx_token, NetTxToken(&self.inner, false)));
+                    } else if reassembled {
+                        tap_ip(buf.packet(), local);
+                    } else {
+                        // smoltcp would queue the datagram on a socket connected
+                        // elsewhere, but packet and raw sockets still see it.
+                        tap_frame(buf.packet(), local);
+                    }
+                    if reassembled {
+                        continue;
+                    }
+                    if let Err(e) = dev.recycle_rx_buffer(buf) {
+                        warn!("recycle_rx_buffer failed: {:?}", e);
+                        return None;
+                    }
+                }
+                Err(e) => {
+                    if !matches!(e, NetError::Again) {
+                        warn!("receive failed: {:?}", e);
+                    }
+                    return None;
                 }
-                None
             }
         }
     }
@@ -55,20 +151,78 @@ impl Device for NetDeviceWrapper {
         if !dev.can_transmit() {
             return None;
         }
-        Some(NetTxToken(&self.inner))
+        Some(NetTxToken(&self.inner, false))
     }
 
     fn capabilities(&self) -> DeviceCapabilities {
+        let link = LinkInfo::of(&**self.inner.borrow());
         let mut caps = DeviceCapabilities::default();
-        caps.max_transmission_unit = 1514;
+        // smoltcp reads the capabilities once, when the interface is made, so
+        // it gets the largest MTU there is and builds packets as large as
+        // IPv4 allows. netcore fits them to the MTU of the link, which
+        // `set_mtu` may change, and of their path: it clamps the MSS of TCP
+        // handshakes and splits the packets that don't fit.
+        caps.max_transmission_unit = link_header_len(link.medium) + IPV4_MAX_LEN;
         caps.max_burst_size = None;
-        caps.medium = self.inner.borrow().medium();
+        caps.medium = link.medium;
+        caps.checksum.ipv4 = software_checksum(link.checksum.ipv4);
+        caps.checksum.tcp = software_checksum(link.checksum.tcp);
+        caps.checksum.udp = software_checksum(link.checksum.udp);
         caps
     }
 }
 
-pub struct NetRxToken<'a>(&'a RefCell<Box<dyn NetDriverOps>>, Box<dyn NetBufOps>);
-pub struct NetTxToken<'a>(&'a RefCell<Box<dyn NetDriverOps>>);
+/// The device, seen as one that never receives, to send the queued packets
+/// of some sockets without feeding them incoming ones.
+pub struct TransmitOnly<'a>(pub &'a mut NetDeviceWrapper);
+
+impl Device for TransmitOnly<'_> {
+    type RxToken<'a> = NetRxToken<'a> where Self: 'a;
+    type TxToken<'a> = NetTxToken<'a> where Self: 'a;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        None
+    }
+
+    fn transmit(&mut self, timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        let token = self.0.transmit(timestamp)?;
+        Some(NetTxToken(token.0, true))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        self.0.capabilities()
+    }
+}
+
+/// A received frame, and whether netcore reassembled it from fragments
+/// rather than the driver lending it.
+pub struct NetRxToken<'a>(&'a RefCell<Box<dyn NetDriverOps>>, Box<dyn NetBufOps>, bool);
+/// A frame to send, and whether it may be the [`IPPROTO_RAW`] wrapper of the
+/// packet of a raw socket, which only the send-only sockets send.
+pub struct NetTxToken<'a>(&'a RefCell<Box<dyn NetDriverOps>>, bool);
+
+/// What the link is like, and what its driver offloads.
+struct LinkInfo {
+    medium: Medium,
+    /// The MTU of the link, at most what an IPv4 packet holds.
+    mtu: usize,
+    checksum: ChecksumOffload,
+    /// TSO, which needs the TCP checksum offloaded too.
+    tso: bool,
+}
+
+impl LinkInfo {
+    fn of(dev: &dyn NetDriverOps) -> Self {
+        let checksum = dev.checksum_offload();
+        Self {
+            medium: dev.medium(),
+            // the MTU of loopback is 64 KiB, one more than an IPv4 packet holds.
+            mtu: dev.mtu().min(IPV4_MAX_LEN),
+            checksum,
+            tso: dev.tcp_segmentation_offload() && checksum.tcp.tx,
+        }
+    }
+}
 
 impl RxToken for NetRxToken<'_> {
     fn consume<R, F>(self, f: F) -> R
@@ -77,14 +231,34 @@ impl RxToken for NetRxToken<'_> {
     {
         let mut rx_buf = self.1;
         info!("RECV {} bytes", rx_buf.packet_len(),);
+        let link = LinkInfo::of(&**self.0.borrow());
+        let segmentation = rx_buf.segmentation();
+        // unverified packets of offloaded protocols were checked on receive.
+        let checked = rx_buf.checksum_verified() || link.checksum.tcp.rx;
+        if let Some(packet) = ipv4_packet_mut(rx_buf.packet_mut(), link.medium) {
+            if let Some(segmentation) = segmentation {
+                info!("RECV coalesced from segments of {}", segmentation.mss);
+                fill_coalesced_checksums(packet, &link);
+            }
+            adjust_tcp_mss(packet, false, checked, &link);
+        }
         let result = f(rx_buf.packet_mut());
-        self.0.borrow_mut().recycle_rx_buffer(rx_buf).unwrap();
+        if !self.2 {
+            self.0.borrow_mut().recycle_rx_buffer(rx_buf).unwrap();
+        }
         result
     }
     fn preprocess(&self, sockets: &mut SocketSet<'_>) {
         let dev = self.0.borrow_mut();
         let medium = dev.medium();
-        snoop_tcp_packet(self.1.packet(), sockets, medium == Medium::Ethernet).ok();
+        let local = (medium == Medium::Ethernet).then(|| dev.mac_address());
+        if self.2 {
+            // packet sockets saw the fragments.
+            tap_ip(self.1.packet(), local);
+        } else {
+            tap_frame(self.1.packet(), local);
+        }
+        snoop_packet(self.1.packet(), sockets, medium == Medium::Ethernet).ok();
     }
 }
 
@@ -94,27 +268,322 @@ impl TxToken for NetTxToken<'_> {
         F: FnOnce(&mut [u8]) -> R,
     {
         let mut dev = self.0.borrow_mut();
-        let mut tx_buf = dev.alloc_tx_buffer(len).unwrap();
-        let result = f(tx_buf.packet_mut());
-        info!("SEND {} bytes", tx_buf.packet_len());
-        dev.transmit(tx_buf).unwrap();
+        let link = LinkInfo::of(&**dev);
+        if !self.1 {
+            return transmit_built(&mut **dev, &link, len, f);
+        }
+        let mut frame = vec![0; len];
+        let result = f(&mut frame);
+        match unwrap_raw_packet(&frame, link.medium) {
+            Some(frame) => transmit_raw(&mut **dev, &frame, &link),
+            None => transmit_built(&mut **dev, &link, len, |buf| buf.copy_from_slice(&frame)),
+        }
         result
     }
 }
 
-fn snoop_tcp_packet(
+/// Transmit the frame of `len` bytes smoltcp builds with `f`, fitting it to
+/// the link and the path, and offloading what the driver can.
+fn transmit_built<R>(
+    dev: &mut dyn NetDriverOps,
+    link: &LinkInfo,
+    len: usize,
+    f: impl FnOnce(&mut [u8]) -> R,
+) -> R {
+    let link_len = link_header_len(link.medium);
+    if len > link_len + link.mtu && !link.tso {
+        // the driver only has buffers for frames that fit the link.
+        let mut frame = vec![0; len];
+        let result = f(&mut frame);
+        let mtu = ipv4_packet_mut(&mut frame, link.medium).and_then(|packet| {
+            record_tcp_syn(packet);
+            adjust_tcp_mss(packet, true, true, link);
+            exceeded_path_mtu(packet, link)
+        });
+        match mtu {
+            Some(mtu) => transmit_split(dev, &mut frame, link_len, mtu),
+            None => {
+                // only IPv4 packets can be split.
+                warn!("dropped a frame of {} bytes, larger than the MTU", len);
+                TX_DROPPED.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        return result;
+    }
+    let mut tx_buf = match dev.alloc_tx_buffer(len) {
+        Ok(tx_buf) => tx_buf,
+        Err(e) => {
+            // smoltcp still builds the packet, which is dropped.
+            warn!("failed to allocate a packet: {:?}", e);
+            TX_DROPPED.fetch_add(1, Ordering::Relaxed);
+            return f(&mut vec![0; len]);
+        }
+    };
+    let result = f(tx_buf.packet_mut());
+    let (segmentation, path_mtu) = match ipv4_packet_mut(tx_buf.packet_mut(), link.medium) {
+        Some(packet) => {
+            record_tcp_syn(packet);
+            adjust_tcp_mss(packet, true, true, link);
+            match tso_segmentation(packet, link) {
+                Some(segmentation) => (Some(segmentation), None),
+                None => (None, exceeded_path_mtu(packet, link)),
+            }
+        }
+        None => (None, None),
+    };
+    if let Some(mtu) = path_mtu {
+        transmit_split(dev, tx_buf.packet_mut(), link_len, mtu);
+        return result;
+    }
+    request_checksum_offload(&mut *tx_buf, link.medium, link.checksum);
+    if let Some(segmentation) = segmentation {
+        info!("SEND in segments of {}", segmentation.mss);
+        tx_buf.set_segmentation(segmentation);
+    }
+    info!("SEND {} bytes", tx_buf.packet_len());
+    if let Err(e) = dev.transmit(tx_buf) {
+        warn!("failed to transmit a packet: {:?}", e);
+        TX_DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+    result
+}
+
+/// The frame of the packet of a raw socket, out of the [`IPPROTO_RAW`]
+/// wrapper smoltcp routed for it, if `frame` is one.
+fn unwrap_raw_packet(frame: &[u8], medium: Medium) -> Option<Vec<u8>> {
+    use smoltcp::wire::Ipv4Packet;
+
+    let wrapper = Ipv4Packet::new_checked(ipv4_packet(frame, medium)?).ok()?;
+    if wrapper.next_header() != IpProtocol::from(IPPROTO_RAW) {
+        return None;
+    }
+    let link_len = link_header_len(medium);
+    let mut unwrapped = frame[..link_len].to_vec();
+    unwrapped.extend_from_slice(wrapper.payload());
+    Some(unwrapped)
+}
+
+/// Transmit the frame of the packet of a raw socket as it is, or in
+/// fragments if it doesn't fit the path and may be fragmented.
+///
+/// Its checksums are the user's, so no offload touches them.
+fn transmit_raw(dev: &mut dyn NetDriverOps, frame: &[u8], link: &LinkInfo) {
+    use smoltcp::wire::Ipv4Packet;
+
+    let link_len = link_header_len(link.medium);
+    let packet = Ipv4Packet::new_unchecked(&frame[link_len..]);
+    let mtu = path_mtu(packet.dst_addr().into(), link);
+    let len = frame.len() - link_len;
+    if len > mtu {
+        if packet.dont_frag() {
+            warn!("dropped a raw packet of {} bytes, larger than the MTU", len);
+            TX_DROPPED.fetch_add(1, Ordering::Relaxed);
+        } else {
+            transmit_fragments(dev, frame, link_len, mtu);
+        }
+        return;
+    }
+    let mut tx_buf = match dev.alloc_tx_buffer(frame.len()) {
+        Ok(tx_buf) => tx_buf,
+        Err(e) => {
+            warn!("failed to allocate a packet: {:?}", e);
+            TX_DROPPED.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+    };
+    tx_buf.packet_mut()[..frame.len()].copy_from_slice(frame);
+    info!("SEND {} bytes, raw", frame.len());
+    if let Err(e) = dev.transmit(tx_buf) {
+        warn!("failed to transmit a packet: {:?}", e);
+        TX_DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The largest IPv4 packet, as its total length is 16 bits.
+const IPV4_MAX_LEN: usize = 65535;
+const TCP_HEADER_LEN: usize = 20;
+const TCP_CHECKSUM_OFFSET: usize = 16;
+const UDP_CHECKSUM_OFFSET: usize = 6;
+/// The kind of the MSS option of TCP.
+const TCP_OPT_MSS: u8 = 2;
+/// The MTUs of common links, guessed when a router doesn't tell the next-hop
+/// MTU (RFC 1191, section 7).
+const MTU_PLATEAUS: [usize; 10] = [32000, 17914, 8166, 4352, 2002, 1492, 1006, 508, 296, 68];
+
+/// The identification of the fragments and segments sent by netcore, as
+/// opposed to smoltcp.
+pub(crate) static NEXT_FRAG_IDENT: AtomicU16 = AtomicU16::new(0x8000);
+
+/// The packets dropped on transmit, as the driver had no room for them or
+/// they didn't fit the link.
+pub(crate) static TX_DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+fn now() -> Instant {
+    KERNEL_NET_FUNC
+        .get()
+        .map_or(Instant::ZERO, |func| func.now().into())
+}
+
+/// Deliver a copy of a received frame to the packet sockets, and of its IPv4
+/// packet to the raw sockets, before smoltcp processes it.
+///
+/// `local` is the hardware address of the device, or `None` if it carries
+/// bare IP packets.
+fn tap_frame(frame: &[u8], local: Option<EthernetAddress>) {
+    PACKET_TABLE.incoming_frame(frame, local);
+    tap_ip(frame, local);
+}
+
+/// Deliver a copy of the IPv4 packet in a received frame to the raw sockets.
+fn tap_ip(frame: &[u8], local: Option<EthernetAddress>) {
+    match local {
+        Some(local) if sent_to_host(frame, local) => {
+            RAW_TABLE.incoming_packet(&frame[link_header_len(Medium::Ethernet)..]);
+        }
+        Some(_) => {}
+        None => RAW_TABLE.incoming_packet(frame),
+    }
+}
+
+/// Whether the Ethernet `frame` carries an IPv4 packet sent to the host with
+/// the hardware address `local`, as smoltcp only takes those.
+fn sent_to_host(frame: &[u8], local: EthernetAddress) -> bool {
+    let Ok(ether_frame) = EthernetFrame::new_checked(frame) else {
+        return false;
+    };
+    let dst = ether_frame.dst_addr();
+    ether_frame.ethertype() == EthernetProtocol::Ipv4
+        && (dst == local || dst.is_broadcast() || dst.is_multicast())
+}
+
+/// Whether the frame carries a fragment of an IPv4 datagram.
+fn is_fragment(frame: &[u8], medium: Medium) -> bool {
+    use smoltcp::wire::Ipv4Packet;
+
+    ipv4_packet(frame, medium)
+        .and_then(|packet| Ipv4Packet::new_checked(packet).ok())
+        .is_some_and(|packet| packet.more_frags() || packet.frag_offset() != 0)
+}
+
+/// Add the fragment received in `buf` to its datagram, returning the frame
+/// of the whole datagram once every fragment arrived.
+///
+/// The fragments sent to other hosts, or with a bad header checksum, are
+/// dropped, as smoltcp would.
+fn reassemble(
+    buf: &dyn NetBufOps,
+    medium: Medium,
+    local: Option<EthernetAddress>,
+) -> Option<ReassembledBuf> {
+    use smoltcp::wire::Ipv4Packet;
+
+    let frame = buf.packet();
+    if local.is_some_and(|local| !sent_to_host(frame, local)) {
+        return None;
+    }
+    let link_len = link_header_len(medium);
+    let packet = &frame[link_len..];
+    if !buf.checksum_verified() && !Ipv4Packet::new_unchecked(packet).verify_checksum() {
+        warn!("dropped a fragment with a bad header checksum");
+        return None;
+    }
+    let datagram = REASSEMBLY_TABLE.incoming_fragment(packet, now())?;
+    let mut frame = frame[..link_len].to_vec();
+    frame.extend_from_slice(&datagram);
+    Some(ReassembledBuf(frame))
+}
+
+/// The frame of a datagram netcore reassembled from its fragments.
+///
+/// It is never recycled to the driver, which didn't lend it.
+struct ReassembledBuf(Vec<u8>);
+
+impl NetBufOps for ReassembledBuf {
+    fn packet(&self) -> &[u8] {
+        &self.0
+    }
+
+    fn packet_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+
+    fn packet_len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+/// Parse the IPv4 packet in the frame `buf`.
+fn parse_ipv4(
     buf: &[u8],
-    sockets: &mut SocketSet<'_>,
     is_ethernet: bool,
-) -> Result<(), smoltcp::wire::Error> {
-    use smoltcp::wire::{EthernetFrame, IpProtocol, Ipv4Packet, TcpPacket};
+) -> Result<smoltcp::wire::Ipv4Packet<&[u8]>, smoltcp::wire::Error> {
+    use smoltcp::wire::Ipv4Packet;
 
-    let ipv4_packet = if is_ethernet {
+    if is_ethernet {
         let ether_frame = EthernetFrame::new_checked(buf)?;
-        Ipv4Packet::new_checked(ether_frame.payload())?
+        Ipv4Packet::new_checked(ether_frame.payload())
     } else {
-        Ipv4Packet::new_checked(buf)?
+        Ipv4Packet::new_checked(buf)
+    }
+}
+
+/// Look at a received UDP datagram before smoltcp does, and return whether
+/// smoltcp should get it.
+///
+/// It runs on receive rather than in `preprocess`, so that the datagrams
+/// a connected socket doesn't accept never reach its queue. Unless the
+/// checksum was `checked`, it's verified first: a corrupted datagram is left
+/// for smoltcp to drop, and no other socket gets a copy of it.
+fn snoop_udp(buf: &[u8], is_ethernet: bool, checked: bool) -> Result<bool, smoltcp::wire::Error> {
+    use smoltcp::wire::UdpPacket;
+
+    let ipv4_packet = parse_ipv4(buf, is_ethernet)?;
+    if ipv4_packet.next_header() != IpProtocol::Udp {
+        return Ok(true);
+    }
+    let dst_ip = ipv4_packet.dst_addr();
+    let udp_packet = UdpPacket::new_checked(ipv4_packet.payload())?;
+    if !checked && !udp_packet.verify_checksum(&ipv4_packet.src_addr().into(), &dst_ip.into()) {
+        return Ok(true);
+    }
+    let Some(iface) = NET_INTERFACE.get() else {
+        return Ok(true);
     };
+    // smoltcp drops the datagrams to the groups the host didn't join.
+    if dst_ip.is_multicast() && !iface.has_multicast_group(dst_ip.into()) {
+        return Ok(true);
+    }
+    let src_addr = (ipv4_packet.src_addr(), udp_packet.src_port()).into();
+    let dst_addr = (dst_ip, udp_packet.dst_port()).into();
+    let fan_out = dst_ip.is_multicast() || iface.is_broadcast(dst_ip.into());
+    // smoltcp drops the destination address and the TTL of datagrams.
+    let meta = UdpRecvMeta {
+        dst: dst_ip.into(),
+        ttl: ipv4_packet.hop_limit(),
+        timestamp: now(),
+    };
+    // with `fan_out`, every socket sharing the port gets its own copy.
+    let payload = udp_packet.payload();
+    let accepted = UDP_TABLE.incoming_udp_packet(src_addr, dst_addr, payload, meta, fan_out);
+    if accepted && !fan_out && udp_packet.src_port() == dns::DNS_PORT {
+        // smoltcp's DNS socket drops the TTLs of the answers.
+        dns::incoming_dns_response(src_addr.addr, udp_packet.payload());
+    }
+    Ok(accepted)
+}
+
+fn snoop_packet(
+    buf: &[u8],
+    sockets: &mut SocketSet<'_>,
+    is_ethernet: bool,
+) -> Result<(), smoltcp::wire::Error> {
+    use smoltcp::wire::{Icmpv4Packet, TcpPacket};
+
+    let ipv4_packet = parse_ipv4(buf, is_ethernet)?;
+    if ipv4_packet.frag_offset() != 0 {
+        // the later fragments of a datagram carry no transport header.
+        return Ok(());
+    }
     if ipv4_packet.next_header() == IpProtocol::Tcp {
         let tcp_packet = TcpPacket::new_checked(ipv4_packet.payload())?;
         let src_addr = (ipv4_packet.src_addr(), tcp_packet.src_port()).into();
@@ -125,10 +594,616 @@ fn snoop_tcp_packet(
             // create a socket for the first incoming TCP packet, as the later accept() returns.
             LISTENING_TABLE.incoming_tcp_packet(src_addr, dst_addr, sockets);
         }
+    } else if ipv4_packet.next_header() == IpProtocol::Icmp {
+        let icmp_packet = Icmpv4Packet::new_checked(ipv4_packet.payload())?;
+        if icmp_packet.msg_type() == Icmpv4Message::DstUnreachable && icmp_packet.msg_code() == 4 {
+            // smoltcp ignores "fragmentation needed", so a smaller MTU on the
+            // path would stall the connections.
+            if let Some((dst, mtu)) = frag_needed_mtu(ipv4_packet.payload()) {
+                ROUTE_TABLE.update_pmtu(dst, mtu, now());
+            }
+        }
+        if matches!(
+            icmp_packet.msg_type(),
+            Icmpv4Message::DstUnreachable | Icmpv4Message::TimeExceeded
+        ) {
+            let quoted = icmp_packet.data();
+            // smoltcp doesn't give ICMP sockets the errors about their echo requests.
+            if let Some(ident) = quoted_echo_ident(quoted) {
+                let src_addr = ipv4_packet.src_addr().into();
+                ICMP_TABLE.incoming_icmp_error(ident, src_addr, ipv4_packet.payload());
+            } else if let (Some(err), Some((protocol, local, remote))) = (
+                icmp_error(icmp_packet.msg_type(), icmp_packet.msg_code()),
+                quoted_endpoints(quoted),
+            ) {
+                // nor does it tell UDP and TCP sockets about ICMP errors.
+                match protocol {
+                    IpProtocol::Udp => UDP_TABLE.incoming_icmp_error(local, remote, err),
+                    IpProtocol::Tcp => abort_tcp_connect(local, remote, quoted, err, sockets),
+                    _ => {}
+                }
+            }
+        }
     }
     Ok(())
 }
 
+/// The identifier of the echo request quoted by an ICMP error, if it quotes one.
+fn quoted_echo_ident(quoted: &[u8]) -> Option<u16> {
+    use smoltcp::wire::Ipv4Packet;
+
+    // the quote is truncated after 8 bytes of payload, so `new_checked` would fail.
+    if quoted.len() < 20 {
+        return None;
+    }
+    let ipv4_packet = Ipv4Packet::new_unchecked(quoted);
+    let echo = quoted.get(ipv4_packet.header_len() as usize..)?.get(..8)?;
+    (ipv4_packet.next_header() == IpProtocol::Icmp
+        && echo[0] == u8::from(Icmpv4Message::EchoRequest))
+    .then(|| u16::from_be_bytes([echo[4], echo[5]]))
+}
+
+/// The protocol, source and destination of the UDP or TCP packet quoted by
+/// an ICMP error.
+fn quoted_endpoints(quoted: &[u8]) -> Option<(IpProtocol, IpEndpoint, IpEndpoint)> {
+    use smoltcp::wire::Ipv4Packet;
+
+    // the quote is truncated after 8 bytes of payload, so `new_checked` would fail.
+    if quoted.len() < 20 {
+        return None;
+    }
+    let ipv4_packet = Ipv4Packet::new_unchecked(quoted);
+    let ports = quoted.get(ipv4_packet.header_len() as usize..)?.get(..4)?;
+    let src_port = u16::from_be_bytes([ports[0], ports[1]]);
+    let dst_port = u16::from_be_bytes([ports[2], ports[3]]);
+    Some((
+        ipv4_packet.next_header(),
+        (ipv4_packet.src_addr(), src_port).into(),
+        (ipv4_packet.dst_addr(), dst_port).into(),
+    ))
+}
+
+/// The sequence number of the TCP segment quoted by an ICMP error.
+fn quoted_tcp_seq(quoted: &[u8]) -> Option<TcpSeqNumber> {
+    use smoltcp::wire::Ipv4Packet;
+
+    // the quote is truncated after 8 bytes of payload, so `new_checked` would fail.
+    if quoted.len() < 20 {
+        return None;
+    }
+    let ipv4_packet = Ipv4Packet::new_unchecked(quoted);
+    let seq = quoted.get(ipv4_packet.header_len() as usize..)?.get(4..8)?;
+    Some(TcpSeqNumber(i32::from_be_bytes(seq.try_into().ok()?)))
+}
+
+/// The destination whose path MTU a "fragmentation needed" message lowers,
+/// and the MTU, given the whole ICMP message.
+fn frag_needed_mtu(message: &[u8]) -> Option<(IpAddress, usize)> {
+    use smoltcp::wire::Ipv4Packet;
+
+    let mtu = u16::from_be_bytes([*message.get(6)?, *message.get(7)?]) as usize;
+    let quoted = message.get(8..)?;
+    // the quote is truncated after 8 bytes of payload, so `new_checked` would fail.
+    if quoted.len() < 20 {
+        return None;
+    }
+    let ipv4_packet = Ipv4Packet::new_unchecked(quoted);
+    let mtu = if mtu != 0 {
+        mtu
+    } else {
+        // routers older than RFC 1191 leave the MTU out.
+        let total_len = ipv4_packet.total_len() as usize;
+        MTU_PLATEAUS
+            .into_iter()
+            .find(|&plateau| plateau < total_len)?
+    };
+    Some((ipv4_packet.dst_addr().into(), mtu))
+}
+
+fn link_header_len(medium: Medium) -> usize {
+    match medium {
+        Medium::Ethernet => EthernetFrame::<&[u8]>::header_len(),
+        _ => 0,
+    }
+}
+
+/// The IPv4 packet in `frame`, if it carries one.
+fn ipv4_packet(frame: &[u8], medium: Medium) -> Option<&[u8]> {
+    if medium == Medium::Ethernet {
+        let ether_frame = EthernetFrame::new_checked(frame).ok()?;
+        if ether_frame.ethertype() != EthernetProtocol::Ipv4 {
+            return None;
+        }
+    }
+    frame.get(link_header_len(medium)..)
+}
+
+fn ipv4_packet_mut(frame: &mut [u8], medium: Medium) -> Option<&mut [u8]> {
+    let len = ipv4_packet(frame, medium)?.len();
+    let start = frame.len() - len;
+    frame.get_mut(start..)
+}
+
+/// What smoltcp must checksum itself for a protocol the NIC offloads as
+/// `offload`.
+fn software_checksum(offload: ProtocolOffload) -> Checksum {
+    match (offload.rx, offload.tx) {
+        (false, false) => Checksum::Both,
+        (true, false) => Checksum::Tx,
+        (false, true) => Checksum::Rx,
+        (true, true) => Checksum::None,
+    }
+}
+
+/// Whether the checksums the NIC offloads, but didn't verify, are right in
+/// the received `frame`.
+///
+/// The fragments of a datagram can't be verified one by one: netcore
+/// verifies the datagram once it reassembled it.
+fn verify_offloaded(frame: &[u8], link: &LinkInfo) -> bool {
+    use smoltcp::wire::{Ipv4Packet, TcpPacket, UdpPacket};
+
+    let offload = link.checksum;
+    let Some(packet) = ipv4_packet(frame, link.medium) else {
+        return true;
+    };
+    // smoltcp drops malformed packets itself.
+    let Ok(ipv4_packet) = Ipv4Packet::new_checked(packet) else {
+        return true;
+    };
+    if offload.ipv4.rx && !ipv4_packet.verify_checksum() {
+        return false;
+    }
+    if ipv4_packet.more_frags() || ipv4_packet.frag_offset() != 0 {
+        return true;
+    }
+    let src_addr = IpAddress::from(ipv4_packet.src_addr());
+    let dst_addr = IpAddress::from(ipv4_packet.dst_addr());
+    match ipv4_packet.next_header() {
+        IpProtocol::Tcp if offload.tcp.rx => TcpPacket::new_checked(ipv4_packet.payload())
+            .map_or(true, |packet| packet.verify_checksum(&src_addr, &dst_addr)),
+        IpProtocol::Udp if offload.udp.rx => UdpPacket::new_checked(ipv4_packet.payload())
+            .map_or(true, |packet| packet.verify_checksum(&src_addr, &dst_addr)),
+        _ => true,
+    }
+}
+
+/// Compute the TCP or UDP checksum of an IPv4 packet in software.
+fn fill_transport_checksum(packet: &mut [u8]) {
+    use smoltcp::wire::{Ipv4Packet, TcpPacket, UdpPacket};
+
+    let Ok(mut ipv4_packet) = Ipv4Packet::new_checked(packet) else {
+        return;
+    };
+    let src_addr = IpAddress::from(ipv4_packet.src_addr());
+    let dst_addr = IpAddress::from(ipv4_packet.dst_addr());
+    match ipv4_packet.next_header() {
+        IpProtocol::Tcp => {
+            if let Ok(mut tcp_packet) = TcpPacket::new_checked(ipv4_packet.payload_mut()) {
+                tcp_packet.fill_checksum(&src_addr, &dst_addr);
+            }
+        }
+        IpProtocol::Udp => {
+            if let Ok(mut udp_packet) = UdpPacket::new_checked(ipv4_packet.payload_mut()) {
+                udp_packet.fill_checksum(&src_addr, &dst_addr);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Leave the TCP or UDP checksum of the packet in `tx_buf` to the NIC, if it
+/// offloads it: store the sum of the pseudo header, and tell the NIC where
+/// the rest is.
+fn request_checksum_offload(tx_buf: &mut dyn NetBufOps, medium: Medium, offload: ChecksumOffload) {
+    use smoltcp::wire::Ipv4Packet;
+
+    let Some(packet) = ipv4_packet_mut(tx_buf.packet_mut(), medium) else {
+        return;
+    };
+    let Ok(mut ipv4_packet) = Ipv4Packet::new_checked(packet) else {
+        return;
+    };
+    // smoltcp's fragments of UDP datagrams go without a checksum (RFC 768).
+    if ipv4_packet.more_frags() || ipv4_packet.frag_offset() != 0 {
+        return;
+    }
+    let protocol = ipv4_packet.next_header();
+    let offset = match protocol {
+        IpProtocol::Tcp if offload.tcp.tx => TCP_CHECKSUM_OFFSET,
+        IpProtocol::Udp if offload.udp.tx => UDP_CHECKSUM_OFFSET,
+        _ => return,
+    };
+    let header_len = ipv4_packet.header_len() as usize;
+    let sum = pseudo_header_sum(
+        ipv4_packet.src_addr(),
+        ipv4_packet.dst_addr(),
+        protocol,
+        ipv4_packet.total_len() as usize - header_len,
+    );
+    let Some(field) = ipv4_packet.payload_mut().get_mut(offset..offset + 2) else {
+        return;
+    };
+    field.copy_from_slice(&sum.to_be_bytes());
+    tx_buf.set_checksum_partial(link_header_len(medium) + header_len, offset);
+}
+
+/// The one's complement sum of the pseudo header of a TCP or UDP packet,
+/// not complemented yet.
+fn pseudo_header_sum(src: Ipv4Address, dst: Ipv4Address, protocol: IpProtocol, len: usize) -> u16 {
+    let mut sum = src
+        .as_bytes()
+        .chunks(2)
+        .chain(dst.as_bytes().chunks(2))
+        .map(|word| u16::from_be_bytes([word[0], word[1]]) as u32)
+        .sum::<u32>();
+    sum += u8::from(protocol) as u32 + len as u32;
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// The MTU of the path to `dst`, over `link`, which no route or learned
+/// path MTU raises.
+fn path_mtu(dst: IpAddress, link: &LinkInfo) -> usize {
+    ROUTE_TABLE
+        .path_mtu(dst, now())
+        .map_or(link.mtu, |mtu| mtu.min(link.mtu))
+}
+
+/// Record the sequence number of an outgoing SYN that starts a TCP connection
+/// attempt in the [`CONNECT_TABLE`].
+fn record_tcp_syn(packet: &[u8]) {
+    use smoltcp::wire::{Ipv4Packet, TcpPacket};
+
+    let Ok(ipv4_packet) = Ipv4Packet::new_checked(packet) else {
+        return;
+    };
+    if ipv4_packet.next_header() != IpProtocol::Tcp || ipv4_packet.frag_offset() != 0 {
+        return;
+    }
+    let Ok(tcp_packet) = TcpPacket::new_checked(ipv4_packet.payload()) else {
+        return;
+    };
+    if tcp_packet.syn() && !tcp_packet.ack() {
+        let local = (ipv4_packet.src_addr(), tcp_packet.src_port()).into();
+        let remote = (ipv4_packet.dst_addr(), tcp_packet.dst_port()).into();
+        CONNECT_TABLE.outgoing_syn(local, remote, tcp_packet.seq_number());
+    }
+}
+
+/// Lower the MSS option of a TCP SYN to fit the path MTU to the peer, the
+/// destination of an `outgoing` packet or else the source.
+///
+/// smoltcp sizes the segments of a connection from the MSS of the SYN, and
+/// only knows the MTU of the device. With TSO, the MSS of the peer is
+/// recorded in the [`MSS_TABLE`] instead, and raised for smoltcp to send
+/// large packets; an RST forgets it.
+///
+/// Unless the checksum was `checked`, it's verified first.
+fn adjust_tcp_mss(packet: &mut [u8], outgoing: bool, checked: bool, link: &LinkInfo) {
+    use smoltcp::wire::{Ipv4Packet, TcpPacket};
+
+    let Ok(mut ipv4_packet) = Ipv4Packet::new_checked(packet) else {
+        return;
+    };
+    if ipv4_packet.next_header() != IpProtocol::Tcp
+        || ipv4_packet.more_frags()
+        || ipv4_packet.frag_offset() != 0
+    {
+        return;
+    }
+    let src_addr = IpAddress::from(ipv4_packet.src_addr());
+    let dst_addr = IpAddress::from(ipv4_packet.dst_addr());
+    let header_len = ipv4_packet.header_len() as usize + TCP_HEADER_LEN;
+    let Ok(mut tcp_packet) = TcpPacket::new_checked(ipv4_packet.payload_mut()) else {
+        return;
+    };
+    // don't make a corrupted segment look valid.
+    if !(tcp_packet.syn() || tcp_packet.rst())
+        || !(checked || tcp_packet.verify_checksum(&src_addr, &dst_addr))
+    {
+        return;
+    }
+    let src = IpEndpoint::new(src_addr, tcp_packet.src_port());
+    let dst = IpEndpoint::new(dst_addr, tcp_packet.dst_port());
+    if tcp_packet.rst() {
+        if outgoing {
+            MSS_TABLE.remove(src, dst);
+        } else {
+            MSS_TABLE.remove(dst, src);
+        }
+        return;
+    }
+    let options = tcp_packet.options_mut();
+    let mut i = 0;
+    let mut old_mss = None;
+    while let Some(&kind) = options.get(i) {
+        match kind {
+            0 => break,
+            1 => i += 1,
+            _ => {
+                let Some(&len) = options.get(i + 1) else {
+                    return;
+                };
+                let len = len as usize;
+                if len < 2 || i + len > options.len() {
+                    return;
+                }
+                if kind == TCP_OPT_MSS && len == 4 {
+                    old_mss = Some(u16::from_be_bytes([options[i + 2], options[i + 3]]));
+                    break;
+                }
+                i += len;
+            }
+        }
+    }
+    let Some(old_mss) = old_mss else {
+        return;
+    };
+    let peer = if outgoing { dst_addr } else { src_addr };
+    let max_mss = path_mtu(peer, link).saturating_sub(header_len) as u16;
+    let mss = old_mss.min(max_mss);
+    let new_mss = if !outgoing && link.tso && MSS_TABLE.insert(dst, src, mss) {
+        (IPV4_MAX_LEN - header_len) as u16
+    } else {
+        mss
+    };
+    if new_mss != old_mss {
+        options[i + 2..i + 4].copy_from_slice(&new_mss.to_be_bytes());
+        info!(
+            "TCP SYN {} -> {}: MSS {} changed to {}",
+            src, dst, old_mss, new_mss
+        );
+        tcp_packet.fill_checksum(&src_addr, &dst_addr);
+    }
+}
+
+/// How the NIC should split a large TCP packet with TSO, if it's larger than
+/// the MSS of the peer.
+fn tso_segmentation(packet: &[u8], link: &LinkInfo) -> Option<TcpSegmentation> {
+    use smoltcp::wire::{Ipv4Packet, TcpPacket};
+
+    if !link.tso {
+        return None;
+    }
+    let ipv4_packet = Ipv4Packet::new_checked(packet).ok()?;
+    if ipv4_packet.next_header() != IpProtocol::Tcp
+        || ipv4_packet.more_frags()
+        || ipv4_packet.frag_offset() != 0
+    {
+        return None;
+    }
+    let tcp_packet = TcpPacket::new_checked(ipv4_packet.payload()).ok()?;
+    let src_addr = IpAddress::from(ipv4_packet.src_addr());
+    let dst_addr = IpAddress::from(ipv4_packet.dst_addr());
+    let mss = MSS_TABLE.get(
+        IpEndpoint::new(src_addr, tcp_packet.src_port()),
+        IpEndpoint::new(dst_addr, tcp_packet.dst_port()),
+    )?;
+    let header_len = ipv4_packet.header_len() as usize + tcp_packet.header_len() as usize;
+    // the path MTU may have dropped since the handshake.
+    let mss = mss.min(path_mtu(dst_addr, link).saturating_sub(header_len) as u16);
+    (tcp_packet.payload().len() > mss as usize).then(|| TcpSegmentation {
+        header_len: link_header_len(link.medium) + header_len,
+        mss,
+    })
+}
+
+/// Make the checksums of a packet coalesced by the NIC right again for
+/// smoltcp, which verifies the ones the NIC doesn't offload.
+fn fill_coalesced_checksums(packet: &mut [u8], link: &LinkInfo) {
+    use smoltcp::wire::Ipv4Packet;
+
+    if !link.checksum.ipv4.rx {
+        if let Ok(mut ipv4_packet) = Ipv4Packet::new_checked(&mut *packet) {
+            ipv4_packet.fill_checksum();
+        }
+    }
+    if !link.checksum.tcp.rx {
+        fill_transport_checksum(packet);
+    }
+}
+
+/// The MTU an IPv4 packet exceeds, either of the link or of its path.
+fn exceeded_path_mtu(packet: &[u8], link: &LinkInfo) -> Option<usize> {
+    use smoltcp::wire::Ipv4Packet;
+
+    let ipv4_packet = Ipv4Packet::new_checked(packet).ok()?;
+    // smoltcp's own fragments are not split again.
+    if ipv4_packet.more_frags() || ipv4_packet.frag_offset() != 0 {
+        return None;
+    }
+    let mtu = path_mtu(ipv4_packet.dst_addr().into(), link);
+    (ipv4_packet.total_len() as usize > mtu).then_some(mtu)
+}
+
+/// Transmit the IPv4 packet in `frame`, after `link_len` bytes of link
+/// header, which exceeds `mtu`.
+///
+/// A TCP segment is split into smaller segments. That lowers the MSS of the
+/// connections agreed before the path MTU was lowered, which smoltcp can't
+/// do afterwards, so a lost packet costs one small segment. The other
+/// packets are split into fragments.
+fn transmit_split(dev: &mut dyn NetDriverOps, frame: &mut [u8], link_len: usize, mtu: usize) {
+    match split_tcp_mss(&frame[link_len..], mtu) {
+        Some(mss) => transmit_segments(dev, frame, link_len, mss),
+        None => {
+            // the NIC can't checksum a datagram split in fragments.
+            fill_transport_checksum(&mut frame[link_len..]);
+            transmit_fragments(dev, frame, link_len, mtu);
+        }
+    }
+}
+
+/// The payload of the segments an IPv4 TCP packet splits into to fit `mtu`,
+/// if it's a segment that can be split.
+fn split_tcp_mss(packet: &[u8], mtu: usize) -> Option<usize> {
+    use smoltcp::wire::{Ipv4Packet, TcpPacket};
+
+    let ipv4_packet = Ipv4Packet::new_checked(packet).ok()?;
+    if ipv4_packet.next_header() != IpProtocol::Tcp
+        || ipv4_packet.more_frags()
+        || ipv4_packet.frag_offset() != 0
+    {
+        return None;
+    }
+    let tcp_packet = TcpPacket::new_checked(ipv4_packet.payload()).ok()?;
+    if tcp_packet.syn() {
+        return None;
+    }
+    let header_len = ipv4_packet.header_len() as usize + tcp_packet.header_len() as usize;
+    mtu.checked_sub(header_len).filter(|&mss| mss > 0)
+}
+
+/// Transmit the TCP segment in `frame`, after `link_len` bytes of link
+/// header, as segments with `mss` bytes of payload, like TSO does.
+fn transmit_segments(dev: &mut dyn NetDriverOps, frame: &[u8], link_len: usize, mss: usize) {
+    use smoltcp::wire::{Ipv4Packet, TcpPacket};
+
+    let (link_header, packet) = frame.split_at(link_len);
+    let ipv4_packet = Ipv4Packet::new_unchecked(packet);
+    let ip_header_len = ipv4_packet.header_len() as usize;
+    let tcp_packet = TcpPacket::new_unchecked(&packet[ip_header_len..]);
+    let header_len = ip_header_len + tcp_packet.header_len() as usize;
+    let payload = &packet[header_len..ipv4_packet.total_len() as usize];
+    let (seq, fin, psh) = (tcp_packet.seq_number(), tcp_packet.fin(), tcp_packet.psh());
+    for (i, chunk) in payload.chunks(mss).enumerate() {
+        let offset = i * mss;
+        // only the last segment ends the data or the stream.
+        let last = offset + chunk.len() == payload.len();
+        let len = link_len + header_len + chunk.len();
+        let mut tx_buf = match dev.alloc_tx_buffer(len) {
+            Ok(tx_buf) => tx_buf,
+            Err(e) => {
+                warn!("failed to allocate a segment: {:?}", e);
+                TX_DROPPED.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        };
+        let buf = &mut tx_buf.packet_mut()[..len];
+        buf[..link_len].copy_from_slice(link_header);
+        buf[link_len..link_len + header_len].copy_from_slice(&packet[..header_len]);
+        buf[link_len + header_len..].copy_from_slice(chunk);
+        let mut segment = Ipv4Packet::new_unchecked(&mut buf[link_len..]);
+        segment.set_total_len((header_len + chunk.len()) as u16);
+        segment.set_ident(NEXT_FRAG_IDENT.fetch_add(1, Ordering::Relaxed));
+        segment.fill_checksum();
+        let mut tcp_segment = TcpPacket::new_unchecked(segment.payload_mut());
+        tcp_segment.set_seq_number(seq + offset);
+        tcp_segment.set_fin(fin && last);
+        tcp_segment.set_psh(psh && last);
+        fill_transport_checksum(&mut buf[link_len..]);
+        info!("SEND {} bytes, segment at {}", len, offset);
+        if let Err(e) = dev.transmit(tx_buf) {
+            warn!("failed to transmit a segment: {:?}", e);
+            TX_DROPPED.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+    }
+}
+
+/// Transmit the IPv4 packet in `frame`, after `link_len` bytes of link
+/// header, as fragments that fit `mtu`.
+fn transmit_fragments(dev: &mut dyn NetDriverOps, frame: &[u8], link_len: usize, mtu: usize) {
+    use smoltcp::wire::Ipv4Packet;
+
+    let (link_header, packet) = frame.split_at(link_len);
+    let ipv4_packet = Ipv4Packet::new_unchecked(packet);
+    let header_len = ipv4_packet.header_len() as usize;
+    let payload = &packet[header_len..ipv4_packet.total_len() as usize];
+    // fragment offsets count 8 bytes.
+    let frag_len = (mtu - header_len) & !7;
+    let ident = NEXT_FRAG_IDENT.fetch_add(1, Ordering::Relaxed);
+    for (i, chunk) in payload.chunks(frag_len).enumerate() {
+        let offset = i * frag_len;
+        let len = link_len + header_len + chunk.len();
+        let mut tx_buf = match dev.alloc_tx_buffer(len) {
+            Ok(tx_buf) => tx_buf,
+            Err(e) => {
+                warn!("failed to allocate a fragment: {:?}", e);
+                TX_DROPPED.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        };
+        let buf = &mut tx_buf.packet_mut()[..len];
+        buf[..link_len].copy_from_slice(link_header);
+        buf[link_len..link_len + header_len].copy_from_slice(&packet[..header_len]);
+        buf[link_len + header_len..].copy_from_slice(chunk);
+        let mut frag = Ipv4Packet::new_unchecked(&mut buf[link_len..]);
+        frag.set_total_len((header_len + chunk.len()) as u16);
+        frag.set_ident(ident);
+        frag.set_dont_frag(false);
+        frag.set_more_frags(offset + chunk.len() < payload.len());
+        frag.set_frag_offset(offset as u16);
+        frag.fill_checksum();
+        info!("SEND {} bytes, fragment at {}", len, offset);
+        if let Err(e) = dev.transmit(tx_buf) {
+            warn!("failed to transmit a fragment: {:?}", e);
+            TX_DROPPED.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+    }
+}
+
+/// The error reported to sockets for an ICMP error message, following the
+/// errnos of Linux.
+fn icmp_error(msg_type: Icmpv4Message, code: u8) -> Option<NetError> {
+    match (msg_type, code) {
+        // network unreachable, unknown, prohibited, or unreachable for the ToS.
+        (Icmpv4Message::DstUnreachable, 0 | 6 | 9 | 11) => Some(NetError::NetworkUnreachable),
+        // protocol or port unreachable.
+        (Icmpv4Message::DstUnreachable, 2 | 3) => Some(NetError::ConnectionRefused),
+        // fragmentation needed is about the path MTU, not an error.
+        (Icmpv4Message::DstUnreachable, 4) => None,
+        (Icmpv4Message::DstUnreachable, _) => Some(NetError::HostUnreachable),
+        (Icmpv4Message::TimeExceeded, _) => Some(NetError::HostUnreachable),
+        _ => None,
+    }
+}
+
+/// Abort the connection attempt from `local` to `remote`, which failed with
+/// the ICMP error `err` quoting the segment `quoted`.
+///
+/// Only an error quoting the SYN of the attempt aborts it, so that a blind,
+/// forged one can't.
+fn abort_tcp_connect(
+    local: IpEndpoint,
+    remote: IpEndpoint,
+    quoted: &[u8],
+    err: NetError,
+    sockets: &mut SocketSet<'_>,
+) {
+    let Some(seq) = quoted_tcp_seq(quoted) else {
+        return;
+    };
+    let Some((handle, socket)) = sockets
+        .iter_mut()
+        .filter_map(|(handle, socket)| tcp::Socket::downcast_mut(socket).map(|s| (handle, s)))
+        .find(|(_, socket)| {
+            socket.state() == State::SynSent
+                && socket.local_endpoint() == Some(local)
+                && socket.remote_endpoint() == Some(remote)
+        })
+    else {
+        return;
+    };
+    if !CONNECT_TABLE.incoming_icmp_error(local, remote, seq, err) {
+        warn!(
+            "TCP socket {}: ignored {:?} quoting sequence number {}",
+            handle, err, seq
+        );
+        return;
+    }
+    info!(
+        "TCP socket {}: connect to {} failed: {:?}",
+        handle, remote, err
+    );
+    socket.abort();
+    SOCKET_EVENTS.notify_writable(WakeKey::Socket(handle));
+}
+
 const GB: usize = 1000 * MB;
 const MB: usize = 1000 * KB;
 const KB: usize = 1000;
@@ -205,3 +1280,420 @@ impl NetDeviceWrapper {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+    use smoltcp::wire::{Ipv4Packet, TcpPacket, UdpPacket};
+
+    const UDP_HEADER_LEN: usize = 8;
+    const SRC_ADDR: Ipv4Address = Ipv4Address([10, 0, 0, 1]);
+    const DST_ADDR: Ipv4Address = Ipv4Address([10, 0, 0, 2]);
+
+    /// A buffer that checksums itself like a NIC, as
+    /// [`NetBufOps::set_checksum_partial`] asks.
+    struct TestBuf {
+        buf: Vec<u8>,
+        checksum_partial: Option<(usize, usize)>,
+    }
+
+    impl TestBuf {
+        fn new(buf: Vec<u8>) -> Self {
+            Self {
+                buf,
+                checksum_partial: None,
+            }
+        }
+
+        fn transmit(&mut self) {
+            if let Some((start, offset)) = self.checksum_partial.take() {
+                let checksum = !ones_complement_sum(&self.buf[start..]);
+                self.buf[start + offset..start + offset + 2]
+                    .copy_from_slice(&checksum.to_be_bytes());
+            }
+        }
+    }
+
+    impl NetBufOps for TestBuf {
+        fn packet(&self) -> &[u8] {
+            &self.buf
+        }
+
+        fn packet_mut(&mut self) -> &mut [u8] {
+            &mut self.buf
+        }
+
+        fn packet_len(&self) -> usize {
+            self.buf.len()
+        }
+
+        fn set_checksum_partial(&mut self, start: usize, offset: usize) {
+            self.checksum_partial = Some((start, offset));
+        }
+    }
+
+    fn ones_complement_sum(data: &[u8]) -> u16 {
+        let mut sum = data
+            .chunks(2)
+            .map(|word| u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]) as u32)
+            .sum::<u32>();
+        while sum > 0xffff {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        sum as u16
+    }
+
+    /// An IPv4 packet from `src_addr` to `dst_addr` carrying `payload`.
+    fn ipv4_packet_of(
+        src_addr: Ipv4Address,
+        dst_addr: Ipv4Address,
+        protocol: IpProtocol,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let mut buf = vec![0; 20 + payload.len()];
+        let mut packet = Ipv4Packet::new_unchecked(&mut buf);
+        packet.set_version(4);
+        packet.set_header_len(20);
+        packet.set_total_len((20 + payload.len()) as u16);
+        packet.set_dont_frag(true);
+        packet.set_hop_limit(64);
+        packet.set_next_header(protocol);
+        packet.set_src_addr(src_addr);
+        packet.set_dst_addr(dst_addr);
+        packet.fill_checksum();
+        packet.payload_mut().copy_from_slice(payload);
+        buf
+    }
+
+    /// A UDP datagram carrying `data`, without a checksum.
+    fn udp_packet(data: &[u8]) -> Vec<u8> {
+        let mut payload = vec![0; UDP_HEADER_LEN + data.len()];
+        let mut packet = UdpPacket::new_unchecked(&mut payload);
+        packet.set_src_port(1234);
+        packet.set_dst_port(5678);
+        packet.set_len((UDP_HEADER_LEN + data.len()) as u16);
+        packet.payload_mut().copy_from_slice(data);
+        ipv4_packet_of(SRC_ADDR, DST_ADDR, IpProtocol::Udp, &payload)
+    }
+
+    /// A TCP segment from `src` to `dst` with the sequence number 1000,
+    /// `options` and the flags `set_flags` sets, carrying `data`.
+    fn tcp_segment(
+        src: (Ipv4Address, u16),
+        dst: (Ipv4Address, u16),
+        options: &[u8],
+        set_flags: impl FnOnce(&mut TcpPacket<&mut Vec<u8>>),
+        data: &[u8],
+    ) -> Vec<u8> {
+        let header_len = TCP_HEADER_LEN + options.len();
+        let mut payload = vec![0; header_len + data.len()];
+        let mut packet = TcpPacket::new_unchecked(&mut payload);
+        packet.set_src_port(src.1);
+        packet.set_dst_port(dst.1);
+        packet.set_seq_number(TcpSeqNumber(1000));
+        packet.set_header_len(header_len as u8);
+        packet.set_window_len(1024);
+        set_flags(&mut packet);
+        packet.options_mut().copy_from_slice(options);
+        packet.payload_mut().copy_from_slice(data);
+        let mut buf = ipv4_packet_of(src.0, dst.0, IpProtocol::Tcp, &payload);
+        fill_transport_checksum(&mut buf);
+        buf
+    }
+
+    /// An ACK from [`SRC_ADDR`] to [`DST_ADDR`] carrying `data`.
+    fn tcp_packet(data: &[u8]) -> Vec<u8> {
+        let (src, dst) = ((SRC_ADDR, 1234), (DST_ADDR, 5678));
+        tcp_segment(src, dst, &[], |packet| packet.set_ack(true), data)
+    }
+
+    /// A SYN from `src` to `dst` asking for the MSS `mss`.
+    fn tcp_syn(src: (Ipv4Address, u16), dst: (Ipv4Address, u16), mss: u16) -> Vec<u8> {
+        let [hi, lo] = mss.to_be_bytes();
+        let options = [TCP_OPT_MSS, 4, hi, lo];
+        tcp_segment(src, dst, &options, |packet| packet.set_syn(true), &[])
+    }
+
+    /// The MSS option of the TCP segment in `packet`.
+    fn mss_option(packet: &[u8]) -> u16 {
+        let ipv4_packet = Ipv4Packet::new_checked(packet).unwrap();
+        let tcp_packet = TcpPacket::new_checked(ipv4_packet.payload()).unwrap();
+        let options = tcp_packet.options();
+        assert_eq!(options[..2], [TCP_OPT_MSS, 4]);
+        u16::from_be_bytes([options[2], options[3]])
+    }
+
+    fn verify_transport_checksum(packet: &[u8]) -> bool {
+        let ipv4_packet = Ipv4Packet::new_checked(packet).unwrap();
+        let src_addr = IpAddress::from(ipv4_packet.src_addr());
+        let dst_addr = IpAddress::from(ipv4_packet.dst_addr());
+        match ipv4_packet.next_header() {
+            IpProtocol::Tcp => TcpPacket::new_checked(ipv4_packet.payload())
+                .unwrap()
+                .verify_checksum(&src_addr, &dst_addr),
+            IpProtocol::Udp => UdpPacket::new_checked(ipv4_packet.payload())
+                .unwrap()
+                .verify_checksum(&src_addr, &dst_addr),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn pseudo_header_sum_folds_carries() {
+        // 0x0a00 + 0x0001 + 0x0a00 + 0x0002 + 17 + 12
+        assert_eq!(
+            pseudo_header_sum(SRC_ADDR, DST_ADDR, IpProtocol::Udp, 12),
+            0x1420
+        );
+        // 4 * 0xffff + 6 + 0xfffa folds twice.
+        let all_ones = Ipv4Address([0xff; 4]);
+        assert_eq!(
+            pseudo_header_sum(all_ones, all_ones, IpProtocol::Tcp, 0xfffa),
+            1
+        );
+    }
+
+    #[test]
+    fn checksum_offload_matches_software() {
+        // odd lengths make the NIC pad the last byte.
+        for data in [&b""[..], b"x", b"hello, world", &[0xff; 1001]] {
+            let packets = [
+                (udp_packet(data), UDP_CHECKSUM_OFFSET),
+                (tcp_packet(data), TCP_CHECKSUM_OFFSET),
+            ];
+            for (mut packet, offset) in packets {
+                let mut expected = packet.clone();
+                fill_transport_checksum(&mut expected);
+                assert!(verify_transport_checksum(&expected));
+
+                // a stale checksum must not leak into the NIC's sum.
+                packet[20 + offset..20 + offset + 2].copy_from_slice(&[0xde, 0xad]);
+                let mut tx_buf = TestBuf::new(packet);
+                request_checksum_offload(&mut tx_buf, Medium::Ip, ChecksumOffload::all());
+                assert_eq!(tx_buf.checksum_partial, Some((20, offset)));
+                tx_buf.transmit();
+                assert!(verify_transport_checksum(&tx_buf.buf));
+                assert_eq!(tx_buf.buf, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn checksum_offload_after_link_header() {
+        let mut frame = vec![0; 14];
+        frame.extend_from_slice(&tcp_packet(b"data"));
+        EthernetFrame::new_unchecked(&mut frame).set_ethertype(EthernetProtocol::Ipv4);
+        let mut tx_buf = TestBuf::new(frame);
+        request_checksum_offload(&mut tx_buf, Medium::Ethernet, ChecksumOffload::all());
+        assert_eq!(
+            tx_buf.checksum_partial,
+            Some((14 + 20, TCP_CHECKSUM_OFFSET))
+        );
+        tx_buf.transmit();
+        assert!(verify_transport_checksum(&tx_buf.buf[14..]));
+    }
+
+    #[test]
+    fn checksum_offload_only_when_offloaded() {
+        let udp_only = ChecksumOffload {
+            udp: ProtocolOffload {
+                rx: false,
+                tx: true,
+            },
+            ..Default::default()
+        };
+        let mut tx_buf = TestBuf::new(tcp_packet(b"data"));
+        request_checksum_offload(&mut tx_buf, Medium::Ip, udp_only);
+        assert_eq!(tx_buf.checksum_partial, None);
+        assert_eq!(tx_buf.buf, tcp_packet(b"data"));
+
+        // fragments go without a checksum.
+        let mut packet = udp_packet(b"data");
+        Ipv4Packet::new_unchecked(&mut packet).set_more_frags(true);
+        let mut tx_buf = TestBuf::new(packet.clone());
+        request_checksum_offload(&mut tx_buf, Medium::Ip, udp_only);
+        assert_eq!(tx_buf.checksum_partial, None);
+        assert_eq!(tx_buf.buf, packet);
+    }
+
+    /// A device that keeps the packets it transmits.
+    #[derive(Default)]
+    struct TestDev {
+        sent: Vec<Vec<u8>>,
+    }
+
+    impl NetDriverOps for TestDev {
+        fn medium(&self) -> Medium {
+            Medium::Ip
+        }
+
+        fn mac_address(&self) -> EthernetAddress {
+            EthernetAddress([0; 6])
+        }
+
+        fn can_transmit(&self) -> bool {
+            true
+        }
+
+        fn can_receive(&self) -> bool {
+            false
+        }
+
+        fn rx_queue_size(&self) -> usize {
+            0
+        }
+
+        fn tx_queue_size(&self) -> usize {
+            usize::MAX
+        }
+
+        fn recycle_rx_buffer(&mut self, _rx_buf: Box<dyn NetBufOps>) -> Result<(), NetError> {
+            Ok(())
+        }
+
+        fn recycle_tx_buffers(&mut self) -> Result<(), NetError> {
+            Ok(())
+        }
+
+        fn transmit(&mut self, tx_buf: Box<dyn NetBufOps>) -> Result<(), NetError> {
+            self.sent.push(tx_buf.packet().to_vec());
+            Ok(())
+        }
+
+        fn receive(&mut self) -> Result<Box<dyn NetBufOps>, NetError> {
+            Err(NetError::Again)
+        }
+
+        fn alloc_tx_buffer(&mut self, size: usize) -> Result<Box<dyn NetBufOps>, NetError> {
+            Ok(Box::new(TestBuf::new(vec![0; size])))
+        }
+    }
+
+    fn link(mtu: usize, tso: bool) -> LinkInfo {
+        LinkInfo {
+            medium: Medium::Ip,
+            mtu,
+            checksum: ChecksumOffload::all(),
+            tso,
+        }
+    }
+
+    #[test]
+    fn mss_of_outgoing_syn_fits_the_link() {
+        let mut packet = tcp_syn((SRC_ADDR, 40000), (DST_ADDR, 80), 1460);
+        adjust_tcp_mss(&mut packet, true, true, &link(576, false));
+        assert_eq!(mss_option(&packet), 576 - 40);
+        assert!(verify_transport_checksum(&packet));
+
+        // a smaller MSS is kept.
+        let mut packet = tcp_syn((SRC_ADDR, 40000), (DST_ADDR, 80), 500);
+        adjust_tcp_mss(&mut packet, true, true, &link(576, false));
+        assert_eq!(mss_option(&packet), 500);
+    }
+
+    #[test]
+    fn mss_of_other_segments_is_kept() {
+        let (src, dst) = ((SRC_ADDR, 40001), (DST_ADDR, 80));
+        let options = [TCP_OPT_MSS, 4, 0x05, 0xb4];
+        let ack = tcp_segment(src, dst, &options, |packet| packet.set_ack(true), &[]);
+        let mut packet = ack.clone();
+        adjust_tcp_mss(&mut packet, true, true, &link(576, false));
+        assert_eq!(packet, ack);
+
+        // a corrupted SYN is left for smoltcp to drop.
+        let mut syn = tcp_syn((DST_ADDR, 80), (SRC_ADDR, 40001), 1460);
+        let last = syn.len() - 1;
+        syn[last] ^= 1;
+        let mut packet = syn.clone();
+        adjust_tcp_mss(&mut packet, false, false, &link(576, true));
+        assert_eq!(packet, syn);
+        let local = IpEndpoint::new(SRC_ADDR.into(), 40001);
+        let remote = IpEndpoint::new(DST_ADDR.into(), 80);
+        assert_eq!(MSS_TABLE.get(local, remote), None);
+    }
+
+    #[test]
+    fn tso_raises_the_mss_of_incoming_syn() {
+        let link = link(1500, true);
+        let local = IpEndpoint::new(SRC_ADDR.into(), 40002);
+        let remote = IpEndpoint::new(DST_ADDR.into(), 80);
+        let mut packet = tcp_syn((DST_ADDR, 80), (SRC_ADDR, 40002), 1460);
+        adjust_tcp_mss(&mut packet, false, false, &link);
+        assert_eq!(mss_option(&packet) as usize, IPV4_MAX_LEN - 40);
+        assert!(verify_transport_checksum(&packet));
+        assert_eq!(MSS_TABLE.get(local, remote), Some(1460));
+
+        // the peer's RST forgets it.
+        let (src, dst) = ((DST_ADDR, 80), (SRC_ADDR, 40002));
+        let mut packet = tcp_segment(src, dst, &[], |packet| packet.set_rst(true), &[]);
+        adjust_tcp_mss(&mut packet, false, false, &link);
+        assert_eq!(MSS_TABLE.get(local, remote), None);
+    }
+
+    #[test]
+    fn tso_segments_at_the_mss_of_the_peer() {
+        let local = IpEndpoint::new(SRC_ADDR.into(), 1234);
+        let remote = IpEndpoint::new(DST_ADDR.into(), 5678);
+        assert!(MSS_TABLE.insert(local, remote, 1000));
+        let packet = tcp_packet(&[0; 3000]);
+        assert_eq!(
+            tso_segmentation(&packet, &link(1500, true)),
+            Some(TcpSegmentation {
+                header_len: 40,
+                mss: 1000
+            })
+        );
+        // nothing to split, or nothing to split with.
+        assert_eq!(
+            tso_segmentation(&tcp_packet(&[0; 1000]), &link(1500, true)),
+            None
+        );
+        assert_eq!(tso_segmentation(&packet, &link(1500, false)), None);
+        // the link is narrower than the MSS.
+        assert_eq!(
+            tso_segmentation(&packet, &link(576, true)).map(|segmentation| segmentation.mss),
+            Some(576 - 40)
+        );
+        MSS_TABLE.remove(local, remote);
+        assert_eq!(tso_segmentation(&packet, &link(1500, true)), None);
+    }
+
+    #[test]
+    fn segments_split_like_tso() {
+        let data: Vec<u8> = (0..2500).map(|i| i as u8).collect();
+        let (src, dst) = ((SRC_ADDR, 1234), (DST_ADDR, 5678));
+        let packet = tcp_segment(
+            src,
+            dst,
+            &[],
+            |packet| {
+                packet.set_ack(true);
+                packet.set_psh(true);
+                packet.set_fin(true);
+            },
+            &data,
+        );
+        let mut dev = TestDev::default();
+        transmit_segments(&mut dev, &packet, 0, 1000);
+
+        assert_eq!(dev.sent.len(), 3);
+        let mut received = Vec::new();
+        for (i, segment) in dev.sent.iter().enumerate() {
+            let last = i == dev.sent.len() - 1;
+            let ipv4_packet = Ipv4Packet::new_checked(&segment[..]).unwrap();
+            assert!(ipv4_packet.verify_checksum());
+            assert!(verify_transport_checksum(segment));
+            let tcp_packet = TcpPacket::new_checked(ipv4_packet.payload()).unwrap();
+            assert_eq!(
+                tcp_packet.seq_number(),
+                TcpSeqNumber(1000 + 1000 * i as i32)
+            );
+            assert!(tcp_packet.ack());
+            assert_eq!(tcp_packet.psh(), last);
+            assert_eq!(tcp_packet.fin(), last);
+            received.extend_from_slice(tcp_packet.payload());
+        }
+        assert_eq!(received, data);
+    }
+}
@@ -0,0 +1,405 @@
+//! Name resolution on top of smoltcp's DNS socket.
+//!
+//! Names are looked up in the static hosts table, then in the cache, and
+//! only then sent to the nameservers. The kernel sets the nameservers with
+//! [`set_nameservers`], e.g. from a DHCP lease.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::net::IpAddr;
+use core::time::Duration;
+
+use log::{info, warn};
+use smoltcp::config::DNS_MAX_SERVER_COUNT;
+use smoltcp::iface::SocketHandle;
+use smoltcp::socket::dns::{self, GetQueryResultError, QueryHandle, StartQueryError};
+use smoltcp::time::{Duration as NetDuration, Instant};
+use smoltcp::wire::{DnsQueryType, IpAddress};
+use spin::Lazy;
+
+use crate::addr::{from_core_ipaddr, into_core_ipaddr};
+use crate::common::{NetError, NetResult};
+use crate::interface::{NetInterface, SocketSetWrapper};
+use crate::{KERNEL_NET_FUNC, NET_INTERFACE, SOCKET_SET};
+use kernel_sync::TicketMutex as Mutex;
+
+/// The UDP port of DNS servers.
+pub(crate) const DNS_PORT: u16 = 53;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_ATTEMPTS: u32 = 2;
+/// The cache lifetime of answers whose TTL was not seen.
+const DEFAULT_TTL: u32 = 60;
+/// Answers are not cached for longer than a day, whatever their TTL.
+const MAX_TTL: u32 = 24 * 60 * 60;
+const CACHE_SIZE: usize = 256;
+
+struct CacheEntry {
+    addrs: Vec<IpAddr>,
+    expires: Instant,
+}
+
+struct PendingQuery {
+    handle: QueryHandle,
+    deadline: Instant,
+    attempt: u32,
+}
+
+struct Resolver {
+    socket: Option<SocketHandle>,
+    nameservers: Vec<IpAddr>,
+    hosts: BTreeMap<String, Vec<IpAddr>>,
+    cache: BTreeMap<String, CacheEntry>,
+    pending: BTreeMap<String, PendingQuery>,
+    timeout: Duration,
+    attempts: u32,
+}
+
+static RESOLVER: Lazy<Mutex<Resolver>> = Lazy::new(|| {
+    let mut hosts = BTreeMap::new();
+    hosts.insert(
+        String::from("localhost"),
+        vec![IpAddr::from([127, 0, 0, 1])],
+    );
+    Mutex::new(Resolver {
+        socket: None,
+        nameservers: Vec::new(),
+        hosts,
+        cache: BTreeMap::new(),
+        pending: BTreeMap::new(),
+        timeout: DEFAULT_TIMEOUT,
+        attempts: DEFAULT_ATTEMPTS,
+    })
+});
+
+/// The smallest TTL of the answers to each pending query, seen by
+/// [`incoming_dns_response`].
+///
+/// It has its own lock, as it's updated while the interface is polled.
+static ANSWER_TTLS: Mutex<BTreeMap<String, Option<u32>>> = Mutex::new(BTreeMap::new());
+
+/// The nameservers [`incoming_dns_response`] takes responses from, a copy of
+/// the resolver's with its own lock for the same reason.
+static ANSWER_SERVERS: Mutex<Vec<IpAddr>> = Mutex::new(Vec::new());
+
+/// Returns the nameservers queried by [`resolve`].
+pub fn nameservers() -> Vec<IpAddr> {
+    RESOLVER.lock().nameservers.clone()
+}
+
+/// Sets the nameservers queried by [`resolve`], tried in order.
+///
+/// Returns [`Err(InvalidInput)`](NetError::InvalidInput) if there are more
+/// than smoltcp supports, or if one is an IPv6 address, as netcore only
+/// speaks IPv4.
+pub fn set_nameservers(servers: &[IpAddr]) -> NetResult<()> {
+    if servers.len() > DNS_MAX_SERVER_COUNT {
+        warn!("too many nameservers: {}", servers.len());
+        return Err(NetError::InvalidInput);
+    }
+    if let Some(server) = servers.iter().find(|server| server.is_ipv6()) {
+        warn!("IPv6 nameserver not supported: {}", server);
+        return Err(NetError::InvalidInput);
+    }
+    let mut resolver = RESOLVER.lock();
+    resolver.nameservers = servers.to_vec();
+    *ANSWER_SERVERS.lock() = servers.to_vec();
+    if let Some(handle) = resolver.socket {
+        let servers: Vec<_> = servers.iter().map(|&ip| from_core_ipaddr(ip)).collect();
+        SOCKET_SET.with_socket_mut::<dns::Socket, _, _>(handle, |socket| {
+            socket.update_servers(&servers);
+        });
+    }
+    info!("DNS nameservers: {:?}", servers);
+    Ok(())
+}
+
+/// Adds `name` to the static hosts table, like a line of `/etc/hosts`.
+///
+/// Hosts take precedence over the nameservers.
+pub fn add_host(name: &str, addrs: &[IpAddr]) {
+    RESOLVER
+        .lock()
+        .hosts
+        .insert(normalize(name), addrs.to_vec());
+}
+
+/// Removes `name` from the static hosts table.
+pub fn remove_host(name: &str) {
+    RESOLVER.lock().hosts.remove(&normalize(name));
+}
+
+/// Sets how long to wait for an answer, and how many times to send a query
+/// before giving up.
+///
+/// Returns [`Err(InvalidInput)`](NetError::InvalidInput) if `attempts` is 0.
+pub fn set_timeout(timeout: Duration, attempts: u32) -> NetResult<()> {
+    if attempts == 0 {
+        warn!("DNS set_timeout() failed: no attempts");
+        return Err(NetError::InvalidInput);
+    }
+    let mut resolver = RESOLVER.lock();
+    resolver.timeout = timeout;
+    resolver.attempts = attempts;
+    Ok(())
+}
+
+/// Forgets every cached answer.
+pub fn flush_cache() {
+    RESOLVER.lock().cache.clear();
+}
+
+/// Resolves `name` to its IPv4 addresses, waiting for the nameservers if
+/// needed.
+///
+/// Returns [`Err(NotFound)`](NetError::NotFound) if the name doesn't exist,
+/// [`Err(TimedOut)`](NetError::TimedOut) if no nameserver answered, or
+/// [`Err(Interrupted)`](NetError::Interrupted) if the kernel reports a
+/// signal.
+pub fn resolve(name: &str) -> NetResult<Vec<IpAddr>> {
+    loop {
+        SOCKET_SET.poll_interfaces();
+        match resolve_nonblocking(name) {
+            Err(NetError::WouldBlock) => {
+                if KERNEL_NET_FUNC.get().unwrap().yield_now() {
+                    return Err(NetError::Interrupted);
+                }
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Like [`resolve`], but returns [`Err(WouldBlock)`](NetError::WouldBlock)
+/// instead of waiting.
+///
+/// The query goes on in the background, call it again with the same name
+/// (after polling the interface) to get the answer.
+pub fn resolve_nonblocking(name: &str) -> NetResult<Vec<IpAddr>> {
+    if let Ok(addr) = name.parse::<IpAddr>() {
+        return Ok(vec![addr]);
+    }
+    let name = normalize(name);
+    let now: Instant = KERNEL_NET_FUNC.get().unwrap().now().into();
+    let mut resolver = RESOLVER.lock();
+    if let Some(addrs) = resolver.hosts.get(&name) {
+        return Ok(addrs.clone());
+    }
+    if let Some(entry) = resolver.cache.get(&name) {
+        if entry.expires > now {
+            return Ok(entry.addrs.clone());
+        }
+        resolver.cache.remove(&name);
+    }
+    resolver.expire_pending(&name, now);
+    resolver.poll_query(name, now)
+}
+
+impl Resolver {
+    /// Returns the DNS socket, creating it on first use.
+    fn socket(&mut self) -> SocketHandle {
+        *self.socket.get_or_insert_with(|| {
+            let servers: Vec<_> = self
+                .nameservers
+                .iter()
+                .map(|&ip| from_core_ipaddr(ip))
+                .collect();
+            SOCKET_SET.add(SocketSetWrapper::new_dns_socket(&servers))
+        })
+    }
+
+    /// Checks the query for `name`, starting or retrying it if needed.
+    fn poll_query(&mut self, name: String, now: Instant) -> NetResult<Vec<IpAddr>> {
+        let socket = self.socket();
+        let Some(query) = self.pending.get(&name) else {
+            self.start_query(name, now, 1)?;
+            return Err(NetError::WouldBlock);
+        };
+        let result = SOCKET_SET.with_socket_mut::<dns::Socket, _, _>(socket, |socket| {
+            socket.get_query_result(query.handle)
+        });
+        let attempt = query.attempt;
+        let error = match result {
+            Ok(addrs) => {
+                self.pending.remove(&name);
+                let addrs: Vec<_> = addrs.into_iter().map(into_core_ipaddr).collect();
+                let ttl = ANSWER_TTLS.lock().remove(&name).flatten();
+                self.insert_cache(name, addrs.clone(), ttl, now);
+                return Ok(addrs);
+            }
+            Err(GetQueryResultError::Pending) if now < query.deadline => {
+                return Err(NetError::WouldBlock);
+            }
+            Err(GetQueryResultError::Pending) => {
+                SOCKET_SET.with_socket_mut::<dns::Socket, _, _>(socket, |socket| {
+                    socket.cancel_query(query.handle);
+                });
+                NetError::TimedOut
+            }
+            Err(GetQueryResultError::Failed) => NetError::NotFound,
+        };
+        self.pending.remove(&name);
+        if attempt < self.attempts {
+            info!("DNS query for {} failed ({:?}), retrying", name, error);
+            self.start_query(name, now, attempt + 1)?;
+            return Err(NetError::WouldBlock);
+        }
+        warn!("DNS query for {} failed: {:?}", name, error);
+        ANSWER_TTLS.lock().remove(&name);
+        Err(error)
+    }
+
+    fn start_query(&mut self, name: String, now: Instant, attempt: u32) -> NetResult<()> {
+        if self.nameservers.is_empty() {
+            warn!("DNS query for {} failed: no nameservers", name);
+            return Err(NetError::Unaddressable);
+        }
+        let socket = self.socket();
+        let iface = NET_INTERFACE.get().unwrap().raw_interface();
+        // lock the interface first, like `poll_interfaces` does.
+        let mut iface = iface.lock();
+        let handle = SOCKET_SET
+            .with_socket_mut::<dns::Socket, _, _>(socket, |socket| {
+                socket.start_query(iface.context(), &name, DnsQueryType::A)
+            })
+            .map_err(|e| {
+                warn!("DNS query for {} failed: {:?}", name, e);
+                match e {
+                    StartQueryError::NoFreeSlot => NetError::WouldBlock,
+                    StartQueryError::InvalidName | StartQueryError::NameTooLong => {
+                        NetError::InvalidInput
+                    }
+                }
+            })?;
+        drop(iface);
+        ANSWER_TTLS.lock().insert(name.clone(), None);
+        let query = PendingQuery {
+            handle,
+            deadline: now + NetDuration::from(self.timeout),
+            attempt,
+        };
+        self.pending.insert(name, query);
+        Ok(())
+    }
+
+    /// Gives up the queries that nobody checked before their deadline,
+    /// except the one for `name`, which is about to be checked.
+    fn expire_pending(&mut self, name: &str, now: Instant) {
+        let Some(socket) = self.socket else {
+            return;
+        };
+        let expired: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|(pending, query)| pending.as_str() != name && query.deadline <= now)
+            .map(|(pending, _)| pending.clone())
+            .collect();
+        for pending in expired {
+            let query = self.pending.remove(&pending).unwrap();
+            ANSWER_TTLS.lock().remove(&pending);
+            SOCKET_SET.with_socket_mut::<dns::Socket, _, _>(socket, |socket| {
+                socket.cancel_query(query.handle);
+            });
+        }
+    }
+
+    fn insert_cache(&mut self, name: String, addrs: Vec<IpAddr>, ttl: Option<u32>, now: Instant) {
+        let ttl = ttl.unwrap_or(DEFAULT_TTL).min(MAX_TTL);
+        if ttl == 0 {
+            return;
+        }
+        if self.cache.len() >= CACHE_SIZE {
+            self.cache.retain(|_, entry| entry.expires > now);
+        }
+        if self.cache.len() >= CACHE_SIZE {
+            self.cache.pop_first();
+        }
+        let expires = now + NetDuration::from_secs(ttl as u64);
+        self.cache.insert(name, CacheEntry { addrs, expires });
+    }
+}
+
+/// Record the TTL of the answers in a DNS response from `src`, if they are
+/// for a pending query.
+///
+/// smoltcp's DNS socket only reports the addresses, so the device snoops the
+/// responses for the TTLs. Responses from other hosts than the nameservers
+/// are ignored, as smoltcp ignores them too.
+pub(crate) fn incoming_dns_response(src: IpAddress, message: &[u8]) {
+    if !ANSWER_SERVERS.lock().contains(&into_core_ipaddr(src)) {
+        return;
+    }
+    let Some((name, ttl)) = parse_response_ttl(message) else {
+        return;
+    };
+    if let Some(pending) = ANSWER_TTLS.lock().get_mut(&name) {
+        *pending = Some(pending.map_or(ttl, |pending| pending.min(ttl)));
+    }
+}
+
+/// Parses a DNS response, returning the question name and the smallest TTL
+/// of the answers.
+fn parse_response_ttl(message: &[u8]) -> Option<(String, u32)> {
+    // a response (QR) to a single question.
+    let header = message.get(..12)?;
+    let question_count = u16::from_be_bytes([header[4], header[5]]);
+    let answer_count = u16::from_be_bytes([header[6], header[7]]);
+    if header[2] & 0x80 == 0 || question_count != 1 {
+        return None;
+    }
+    let (name, pos) = read_name(message, 12)?;
+    // skip QTYPE and QCLASS.
+    let mut pos = pos + 4;
+    let mut ttl: Option<u32> = None;
+    for _ in 0..answer_count {
+        let (_, name_end) = read_name(message, pos)?;
+        let record = message.get(name_end..name_end + 10)?;
+        let record_type = u16::from_be_bytes([record[0], record[1]]);
+        let record_ttl = u32::from_be_bytes([record[4], record[5], record[6], record[7]]);
+        let data_len = u16::from_be_bytes([record[8], record[9]]) as usize;
+        pos = name_end + 10 + data_len;
+        // A and CNAME records, the ones smoltcp follows.
+        if record_type == 1 || record_type == 5 {
+            ttl = Some(ttl.map_or(record_ttl, |ttl| ttl.min(record_ttl)));
+        }
+    }
+    Some((name, ttl?))
+}
+
+/// Reads a possibly compressed name at `pos`, returning it in the form of
+/// [`normalize`] and the position after it.
+fn read_name(message: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut name = String::new();
+    let mut end = None;
+    let mut jumps = 0;
+    loop {
+        let len = *message.get(pos)? as usize;
+        if len & 0xc0 == 0xc0 {
+            // bound the pointer jumps, a malicious message could loop.
+            jumps += 1;
+            if jumps > 16 {
+                return None;
+            }
+            let offset = u16::from_be_bytes([len as u8, *message.get(pos + 1)?]) & 0x3fff;
+            end.get_or_insert(pos + 2);
+            pos = offset as usize;
+            continue;
+        }
+        if len == 0 {
+            return Some((name, end.unwrap_or(pos + 1)));
+        }
+        let label = message.get(pos + 1..pos + 1 + len)?;
+        if !name.is_empty() {
+            name.push('.');
+        }
+        name.extend(label.iter().map(|&c| c.to_ascii_lowercase() as char));
+        pos += 1 + len;
+    }
+}
+
+/// Names are compared in lowercase and without the trailing dot.
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
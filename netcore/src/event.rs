@@ -0,0 +1,290 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use alloc::vec::Vec;
+use core::task::Waker;
+
+use smoltcp::iface::{SocketHandle, SocketSet};
+use smoltcp::socket::{icmp, tcp, udp};
+
+use crate::listen_table::ListenId;
+use crate::packet_table::PacketId;
+use crate::raw_table::RawId;
+use crate::{LISTENING_TABLE, SOCKET_EVENTS, SOCKET_SET};
+use kernel_sync::TicketMutex as Mutex;
+
+/// What a readiness change is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum WakeKey {
+    /// A socket in `SOCKET_SET`.
+    Socket(SocketHandle),
+    /// A TCP listener, woken when a connection in its SYN queue changes state.
+    Listener(ListenId),
+    /// A packet socket, woken when a frame is queued for it.
+    Packet(PacketId),
+    /// A raw socket, woken when a packet is queued for it or its sender has
+    /// room again.
+    Raw(RawId),
+}
+
+/// The kind of smoltcp socket behind a [`WakeKey::Socket`], needed to arm it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketKind {
+    Tcp,
+    Udp,
+    Icmp,
+}
+
+/// Callbacks for in-kernel socket users, like Linux's `sk_data_ready` and
+/// `sk_write_space`.
+///
+/// They are called from [`poll_interfaces`](crate::poll_interfaces) after
+/// the interface was polled, on the thread that polled it, with no netcore
+/// lock held. They may use the socket, but should not block.
+pub trait SocketUpcall: Send + Sync {
+    /// Data, a new connection, or the end of the stream arrived.
+    fn data_ready(&self);
+    /// Space was freed in the send buffer.
+    fn write_space(&self);
+}
+
+/// The waker netcore registers with smoltcp sockets.
+///
+/// smoltcp wakes it in the middle of `Interface::poll`, with `SOCKET_SET`
+/// locked, so it only records the key. The real wakeups happen in
+/// [`SocketEvents::dispatch`].
+struct KeyWaker {
+    key: WakeKey,
+    /// Registered as the send waker rather than the receive waker.
+    send: bool,
+}
+
+impl Wake for KeyWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if self.send {
+            SOCKET_EVENTS.notify_writable(self.key);
+        } else {
+            SOCKET_EVENTS.notify_readable(self.key);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Subscribers {
+    /// Cached [`KeyWaker`]s, so that re-registering them with smoltcp is cheap.
+    key_wakers: Option<(Waker, Waker)>,
+    kind: Option<SocketKind>,
+    wakers: Vec<Waker>,
+    upcall: Option<Arc<dyn SocketUpcall>>,
+}
+
+impl Subscribers {
+    fn key_wakers(&mut self, key: WakeKey) -> (Waker, Waker) {
+        self.key_wakers
+            .get_or_insert_with(|| {
+                let waker = |send| Waker::from(Arc::new(KeyWaker { key, send }));
+                (waker(false), waker(true))
+            })
+            .clone()
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+struct Woken {
+    readable: bool,
+    writable: bool,
+}
+
+/// Routes smoltcp socket wakeups to everyone waiting on a socket.
+///
+/// smoltcp keeps a single waker per socket and direction, so netcore
+/// registers its own [`KeyWaker`] there and fans the wakeup out to any number
+/// of subscribers: pollers, async tasks and in-kernel callbacks.
+pub struct SocketEvents {
+    subscribers: Mutex<BTreeMap<WakeKey, Subscribers>>,
+    woken: Mutex<BTreeMap<WakeKey, Woken>>,
+}
+
+impl Default for SocketEvents {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SocketEvents {
+    pub const fn new() -> Self {
+        Self {
+            subscribers: Mutex::new(BTreeMap::new()),
+            woken: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// The receive and send wakers to register with the smoltcp socket
+    /// behind `key`.
+    pub fn key_wakers(&self, key: WakeKey) -> (Waker, Waker) {
+        self.subscribers
+            .lock()
+            .entry(key)
+            .or_default()
+            .key_wakers(key)
+    }
+
+    /// Register the netcore wakers with the smoltcp socket `handle`, so that
+    /// its next change is notified.
+    pub fn arm(&self, handle: SocketHandle, kind: SocketKind) {
+        let key = WakeKey::Socket(handle);
+        let (recv, send) = {
+            let mut subscribers = self.subscribers.lock();
+            let entry = subscribers.entry(key).or_default();
+            entry.kind = Some(kind);
+            entry.key_wakers(key)
+        };
+        SOCKET_SET.with_sockets_mut(|sockets| {
+            register_wakers(sockets, handle, kind, &recv, &send);
+        });
+    }
+
+    /// Register the wakers of the listener `id` with the sockets in its SYN
+    /// queue, so that the next connection to complete the handshake is
+    /// notified.
+    pub fn arm_listener(&self, id: ListenId) {
+        let (recv, _) = self.key_wakers(WakeKey::Listener(id));
+        SOCKET_SET.with_sockets_mut(|sockets| {
+            LISTENING_TABLE.register_waker(id, &recv, sockets);
+        });
+    }
+
+    /// Arm the socket `handle` again after a notification, unless it was
+    /// removed in the meantime.
+    fn rearm(&self, handle: SocketHandle) {
+        let key = WakeKey::Socket(handle);
+        SOCKET_SET.with_sockets_mut(|sockets| {
+            // `SocketSetWrapper::remove` detaches the key with the socket set
+            // locked, so a key that is still here has its socket too. Its
+            // kind is read again, as the handle may have been reused.
+            let armed = self.subscribers.lock().get(&key).and_then(|entry| {
+                let (recv, send) = entry.key_wakers.clone()?;
+                Some((entry.kind?, recv, send))
+            });
+            if let Some((kind, recv, send)) = armed {
+                register_wakers(sockets, handle, kind, &recv, &send);
+            }
+        });
+    }
+
+    /// Wake `waker` once, the next time `key` is notified.
+    ///
+    /// A [`WakeKey::Socket`] must have been [armed](Self::arm) first.
+    pub fn subscribe(&self, key: WakeKey, waker: &Waker) {
+        let mut subscribers = self.subscribers.lock();
+        let wakers = &mut subscribers.entry(key).or_default().wakers;
+        if !wakers.iter().any(|w| w.will_wake(waker)) {
+            wakers.push(waker.clone());
+        }
+    }
+
+    /// Attach `upcall` to `key`, or detach the current one with `None`.
+    ///
+    /// Unlike wakers, an upcall stays attached and the socket is re-armed
+    /// after every notification.
+    pub fn set_upcall(&self, key: WakeKey, upcall: Option<Arc<dyn SocketUpcall>>) {
+        self.subscribers.lock().entry(key).or_default().upcall = upcall;
+    }
+
+    /// Record that `key` may have become readable.
+    ///
+    /// The subscribers are woken by the next [`dispatch`](Self::dispatch).
+    pub fn notify_readable(&self, key: WakeKey) {
+        self.woken.lock().entry(key).or_default().readable = true;
+    }
+
+    /// Record that `key` may have become writable.
+    pub fn notify_writable(&self, key: WakeKey) {
+        self.woken.lock().entry(key).or_default().writable = true;
+    }
+
+    /// Wake the subscribers and call the upcalls of every key notified since
+    /// the last call.
+    ///
+    /// It runs after `poll_interfaces` has released `SOCKET_SET`, so the
+    /// woken code may use sockets freely.
+    pub fn dispatch(&self) {
+        let woken = core::mem::take(&mut *self.woken.lock());
+        if woken.is_empty() {
+            return;
+        }
+        let mut wakers = Vec::new();
+        let mut upcalls = Vec::new();
+        {
+            let mut subscribers = self.subscribers.lock();
+            for (key, events) in woken {
+                if let Some(entry) = subscribers.get_mut(&key) {
+                    wakers.append(&mut entry.wakers);
+                    if let Some(upcall) = &entry.upcall {
+                        upcalls.push((key, entry.kind, upcall.clone(), events));
+                    }
+                }
+            }
+        }
+        wakers.into_iter().for_each(Waker::wake);
+        for (key, kind, upcall, events) in upcalls {
+            // re-arm first, so changes made during the upcall are not lost.
+            match (key, kind) {
+                (WakeKey::Socket(handle), Some(_)) => self.rearm(handle),
+                (WakeKey::Listener(id), _) => self.arm_listener(id),
+                _ => {}
+            }
+            if events.readable {
+                upcall.data_ready();
+            }
+            if events.writable {
+                upcall.write_space();
+            }
+        }
+    }
+
+    /// Forget `key` when its socket goes away, waking whoever still waits on it.
+    pub fn remove(&self, key: WakeKey) {
+        self.detach(key).into_iter().for_each(Waker::wake);
+    }
+
+    /// Forget `key` like [`remove`](Self::remove), but return the wakers to
+    /// wake instead, for callers that hold a lock.
+    pub fn detach(&self, key: WakeKey) -> Vec<Waker> {
+        self.subscribers
+            .lock()
+            .remove(&key)
+            .map(|entry| entry.wakers)
+            .unwrap_or_default()
+    }
+}
+
+fn register_wakers(
+    sockets: &mut SocketSet<'_>,
+    handle: SocketHandle,
+    kind: SocketKind,
+    recv: &Waker,
+    send: &Waker,
+) {
+    match kind {
+        SocketKind::Tcp => {
+            let socket = sockets.get_mut::<tcp::Socket>(handle);
+            socket.register_recv_waker(recv);
+            socket.register_send_waker(send);
+        }
+        SocketKind::Udp => {
+            let socket = sockets.get_mut::<udp::Socket>(handle);
+            socket.register_recv_waker(recv);
+            socket.register_send_waker(send);
+        }
+        SocketKind::Icmp => {
+            let socket = sockets.get_mut::<icmp::Socket>(handle);
+            socket.register_recv_waker(recv);
+            socket.register_send_waker(send);
+        }
+    }
+}
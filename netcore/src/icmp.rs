@@ -0,0 +1,312 @@
+use super::addr::{from_core_ipaddr, into_core_ipaddr};
+use super::{SocketSetWrapper, SOCKET_SET};
+use crate::common::{block_on, copy_truncated, NetError, NetPollState, NetResult};
+use crate::event::{SocketKind, WakeKey};
+use crate::poller::NetPollable;
+use crate::{ICMP_TABLE, SOCKET_EVENTS};
+use core::net::IpAddr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::Waker;
+use kernel_sync::TicketMutex as Mutex;
+use log::{info, warn};
+use smoltcp::iface::SocketHandle;
+use smoltcp::phy::ChecksumCapabilities;
+use smoltcp::socket::icmp::{self, BindError, SendError};
+use smoltcp::wire::{Icmpv4Message, Icmpv4Packet, Icmpv4Repr, IpAddress};
+
+/// Length of the ICMP echo header: type, code, checksum, identifier and
+/// sequence number.
+const ECHO_HEADER_LEN: usize = 8;
+
+/// A message received by [`IcmpSocket::recv_reply`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IcmpReply {
+    /// An echo reply, whose payload was copied to the buffer.
+    EchoReply { seq_no: u16, len: usize },
+    /// The echo request `seq_no` could not be delivered.
+    DstUnreachable { code: u8, seq_no: u16 },
+    /// The TTL of the echo request `seq_no` ran out on the way.
+    TimeExceeded { code: u8, seq_no: u16 },
+}
+
+/// An ICMP echo socket that provides POSIX-like APIs, like an unprivileged
+/// `SOCK_DGRAM` ICMP socket on Linux.
+///
+/// The socket is bound to an echo identifier, and only sees the replies and
+/// errors about the requests sent with it.
+pub struct IcmpSocket {
+    handle: SocketHandle,
+    ident: Mutex<Option<u16>>,
+    nonblock: AtomicBool,
+}
+
+impl IcmpSocket {
+    /// Creates a new ICMP socket.
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        let socket = SocketSetWrapper::new_icmp_socket();
+        let handle = SOCKET_SET.add(socket);
+        Self {
+            handle,
+            ident: Mutex::new(None),
+            nonblock: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns the echo identifier, or
+    /// [`Err(NotConnected)`](NetError::NotConnected) if not bound.
+    pub fn ident(&self) -> NetResult<u16> {
+        self.ident.lock().ok_or(NetError::NotConnected)
+    }
+
+    /// Returns whether this socket is in nonblocking mode.
+    #[inline]
+    pub fn is_nonblocking(&self) -> bool {
+        self.nonblock.load(Ordering::Acquire)
+    }
+
+    /// Moves this ICMP socket into or out of nonblocking mode.
+    ///
+    /// In nonblocking mode, the send and receive operations return
+    /// [`Err(WouldBlock)`](NetError::WouldBlock) instead of waiting.
+    #[inline]
+    pub fn set_nonblocking(&self, nonblocking: bool) {
+        self.nonblock.store(nonblocking, Ordering::Release);
+    }
+
+    /// Binds an unbound socket to the echo identifier `ident`.
+    ///
+    /// If `ident` is 0, it picks a free one. Returns
+    /// [`Err(AddrInUse)`](NetError::AddrInUse) if another socket owns it.
+    pub fn bind(&self, ident: u16) -> NetResult<()> {
+        let mut self_ident = self.ident.lock();
+        if self_ident.is_some() {
+            warn!("ICMP socket {}: already bound", self.handle);
+            return Err(NetError::InvalidInput);
+        }
+
+        let ident = ICMP_TABLE.bind(ident, self.handle)?;
+        SOCKET_SET
+            .with_socket_mut::<icmp::Socket, _, _>(self.handle, |socket| {
+                socket
+                    .bind(icmp::Endpoint::Ident(ident))
+                    .map_err(|e| match e {
+                        BindError::InvalidState => {
+                            warn!("ICMP socket {}: already bound", self.handle);
+                            NetError::AlreadyExists
+                        }
+                        BindError::Unaddressable => {
+                            warn!("ICMP socket {}: invalid identifier", self.handle);
+                            NetError::InvalidInput
+                        }
+                    })
+            })
+            .map_err(|e| {
+                ICMP_TABLE.unbind(ident);
+                e
+            })?;
+
+        *self_ident = Some(ident);
+        info!("ICMP socket {}: bound to identifier {}", self.handle, ident);
+        Ok(())
+    }
+
+    /// Sends an echo request with `seq_no` and `payload` to `dst`. On success,
+    /// returns the length of the payload.
+    ///
+    /// An unbound socket is bound to a free identifier first.
+    pub fn send_echo(&self, dst: IpAddr, seq_no: u16, payload: &[u8]) -> NetResult<usize> {
+        if dst.is_unspecified() {
+            warn!("socket send_echo() failed: invalid address");
+            return Err(NetError::InvalidInput);
+        }
+        let ident = match self.ident() {
+            Ok(ident) => ident,
+            Err(_) => {
+                self.bind(0)?;
+                self.ident()?
+            }
+        };
+        let repr = Icmpv4Repr::EchoRequest {
+            ident,
+            seq_no,
+            data: payload,
+        };
+        let dst = from_core_ipaddr(dst);
+        block_on(self.is_nonblocking(), || {
+            SOCKET_SET.with_socket_mut::<icmp::Socket, _, _>(self.handle, |socket| {
+                if !socket.can_send() {
+                    // tx buffer is full
+                    return Err(NetError::WouldBlock);
+                }
+                let buf = socket.send(repr.buffer_len(), dst).map_err(|e| match e {
+                    SendError::BufferFull => NetError::WouldBlock,
+                    SendError::Unaddressable => {
+                        warn!("ICMP socket {}: send() failed: unaddressable", self.handle);
+                        NetError::Unaddressable
+                    }
+                })?;
+                repr.emit(
+                    &mut Icmpv4Packet::new_unchecked(buf),
+                    &ChecksumCapabilities::default(),
+                );
+                Ok(payload.len())
+            })
+        })
+    }
+
+    /// Sends the ICMP echo request in `buf` to `dst`, like `sendto` on a
+    /// Linux ping socket.
+    ///
+    /// The identifier is replaced by the one of the socket and the checksum
+    /// is computed. On success, returns the length of `buf`.
+    pub fn send_to(&self, buf: &[u8], dst: IpAddr) -> NetResult<usize> {
+        if buf.len() < ECHO_HEADER_LEN
+            || buf[0] != u8::from(Icmpv4Message::EchoRequest)
+            || buf[1] != 0
+        {
+            warn!("socket send_to() failed: not an echo request");
+            return Err(NetError::InvalidInput);
+        }
+        let seq_no = u16::from_be_bytes([buf[6], buf[7]]);
+        self.send_echo(dst, seq_no, &buf[ECHO_HEADER_LEN..])?;
+        Ok(buf.len())
+    }
+
+    /// Receives a single ICMP message, an echo reply or an error, like
+    /// `recvfrom` on a Linux ping socket. On success, returns the number of
+    /// bytes read and the origin.
+    pub fn recv_from(&self, buf: &mut [u8]) -> NetResult<(usize, IpAddr)> {
+        self.recv_impl(|message, src| Ok((copy_truncated(buf, message), into_core_ipaddr(src))))
+    }
+
+    /// Receives a single echo reply or error, and copies the payload of a
+    /// reply to `buf`. On success, returns what was received and the origin.
+    pub fn recv_reply(&self, buf: &mut [u8]) -> NetResult<(IcmpReply, IpAddr)> {
+        self.recv_impl(|message, src| {
+            let reply = parse_reply(message, buf).ok_or_else(|| {
+                warn!(
+                    "ICMP socket {}: recv() failed: malformed message",
+                    self.handle
+                );
+                NetError::BadState
+            })?;
+            Ok((reply, into_core_ipaddr(src)))
+        })
+    }
+
+    /// Close the socket.
+    pub fn shutdown(&self) -> NetResult<()> {
+        SOCKET_SET.with_socket_mut::<icmp::Socket, _, _>(self.handle, |socket| {
+            info!("ICMP socket {}: shutting down", self.handle);
+            // smoltcp has no `close` for ICMP sockets, drop what is queued instead.
+            while socket.recv().is_ok() {}
+        });
+        if let Some(ident) = self.ident.lock().take() {
+            ICMP_TABLE.unbind(ident);
+        }
+        Ok(())
+    }
+
+    /// Whether the socket is readable or writable.
+    pub fn poll(&self) -> NetResult<NetPollState> {
+        let Some(ident) = *self.ident.lock() else {
+            return Ok(NetPollState {
+                writable: true,
+                ..Default::default()
+            });
+        };
+        SOCKET_SET.with_socket_mut::<icmp::Socket, _, _>(self.handle, |socket| {
+            Ok(NetPollState {
+                readable: socket.can_recv() || ICMP_TABLE.has_errors(ident),
+                writable: socket.can_send(),
+                ..Default::default()
+            })
+        })
+    }
+}
+
+/// Private methods
+impl IcmpSocket {
+    /// Receives one ICMP message and passes it with its source to `op`.
+    ///
+    /// Errors routed by the [`IcmpTable`] are taken before the replies
+    /// queued in the smoltcp socket.
+    ///
+    /// [`IcmpTable`]: crate::icmp_table::IcmpTable
+    fn recv_impl<F, T>(&self, mut op: F) -> NetResult<T>
+    where
+        F: FnMut(&[u8], IpAddress) -> NetResult<T>,
+    {
+        let Some(ident) = *self.ident.lock() else {
+            warn!("ICMP socket {}: recv() failed: not bound", self.handle);
+            return Err(NetError::NotConnected);
+        };
+
+        block_on(self.is_nonblocking(), || {
+            if let Some(res) = ICMP_TABLE.recv_error(ident, |error| op(&error.message, error.src)) {
+                return res;
+            }
+            SOCKET_SET.with_socket_mut::<icmp::Socket, _, _>(self.handle, |socket| {
+                while socket.can_recv() {
+                    let (message, src) = socket.recv().map_err(|_| {
+                        warn!("ICMP socket {}: recv() failed", self.handle);
+                        NetError::BadState
+                    })?;
+                    // smoltcp also hands over echo requests with our identifier.
+                    if message.first() == Some(&u8::from(Icmpv4Message::EchoReply)) {
+                        return op(message, src);
+                    }
+                }
+                // no more data
+                Err(NetError::WouldBlock)
+            })
+        })
+    }
+}
+
+impl NetPollable for IcmpSocket {
+    fn poll(&self) -> NetResult<NetPollState> {
+        IcmpSocket::poll(self)
+    }
+
+    fn register_waker(&self, waker: &Waker) {
+        SOCKET_EVENTS.arm(self.handle, SocketKind::Icmp);
+        SOCKET_EVENTS.subscribe(WakeKey::Socket(self.handle), waker);
+    }
+}
+
+impl Drop for IcmpSocket {
+    fn drop(&mut self) {
+        self.shutdown().ok();
+        SOCKET_SET.remove(self.handle);
+    }
+}
+
+/// Parses an echo reply, copying its payload to `buf`, or an error quoting
+/// one of our echo requests.
+fn parse_reply(message: &[u8], buf: &mut [u8]) -> Option<IcmpReply> {
+    let packet = Icmpv4Packet::new_checked(message).ok()?;
+    match packet.msg_type() {
+        Icmpv4Message::EchoReply => {
+            let len = copy_truncated(buf, packet.data());
+            Some(IcmpReply::EchoReply {
+                seq_no: packet.echo_seq_no(),
+                len,
+            })
+        }
+        msg_type => {
+            // the quoted IP header, then the first 8 bytes of our request.
+            let quoted = packet.data();
+            let header_len = (*quoted.first()? & 0x0f) as usize * 4;
+            let echo = quoted.get(header_len..header_len + ECHO_HEADER_LEN)?;
+            let seq_no = u16::from_be_bytes([echo[6], echo[7]]);
+            let code = packet.msg_code();
+            match msg_type {
+                Icmpv4Message::DstUnreachable => Some(IcmpReply::DstUnreachable { code, seq_no }),
+                Icmpv4Message::TimeExceeded => Some(IcmpReply::TimeExceeded { code, seq_no }),
+                _ => None,
+            }
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+
+use log::{info, warn};
+use smoltcp::iface::SocketHandle;
+use smoltcp::wire::IpAddress;
+
+use crate::common::{NetError, NetResult, ICMP_RX_BUF_LEN};
+use crate::event::WakeKey;
+use crate::{KERNEL_NET_FUNC, SOCKET_EVENTS};
+use kernel_sync::TicketMutex as Mutex;
+
+/// An ICMP error about an echo request, delivered by netcore rather than by
+/// smoltcp.
+pub struct IcmpErrorMessage {
+    /// The whole ICMP message, header included.
+    pub message: Vec<u8>,
+    pub src: IpAddress,
+}
+
+struct IcmpBinding {
+    handle: SocketHandle,
+    errors: VecDeque<IcmpErrorMessage>,
+    errors_bytes: usize,
+}
+
+/// The echo identifiers bound by ICMP sockets.
+///
+/// smoltcp only hands echo replies to a socket bound to an identifier, so the
+/// errors quoting its echo requests are routed here.
+pub struct IcmpTable {
+    idents: Mutex<BTreeMap<u16, IcmpBinding>>,
+}
+
+impl Default for IcmpTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IcmpTable {
+    pub const fn new() -> Self {
+        Self {
+            idents: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Bind the socket `handle` to the echo identifier `ident`.
+    ///
+    /// If `ident` is 0, a random free one is picked. Returns the bound
+    /// identifier, or [`Err(AddrInUse)`](NetError::AddrInUse) if another
+    /// socket owns it.
+    pub fn bind(&self, mut ident: u16, handle: SocketHandle) -> NetResult<u16> {
+        let mut idents = self.idents.lock();
+        if ident == 0 {
+            if idents.len() >= u16::MAX as usize {
+                warn!("ICMP socket {}: no free identifier", handle);
+                return Err(NetError::AddrInUse);
+            }
+            let kernel_func = KERNEL_NET_FUNC.get().unwrap();
+            while ident == 0 || idents.contains_key(&ident) {
+                ident = kernel_func.random() as u16;
+            }
+        } else if idents.contains_key(&ident) {
+            warn!("ICMP socket {}: identifier {} is in use", handle, ident);
+            return Err(NetError::AddrInUse);
+        }
+        idents.insert(
+            ident,
+            IcmpBinding {
+                handle,
+                errors: VecDeque::new(),
+                errors_bytes: 0,
+            },
+        );
+        Ok(ident)
+    }
+
+    /// Release `ident`, dropping the pending errors.
+    pub fn unbind(&self, ident: u16) {
+        self.idents.lock().remove(&ident);
+    }
+
+    /// Whether errors are waiting for the socket bound to `ident`.
+    pub fn has_errors(&self, ident: u16) -> bool {
+        self.idents
+            .lock()
+            .get(&ident)
+            .is_some_and(|binding| !binding.errors.is_empty())
+    }
+
+    /// Take the oldest error for the socket bound to `ident`.
+    pub fn recv_error<F, T>(&self, ident: u16, f: F) -> Option<T>
+    where
+        F: FnOnce(&IcmpErrorMessage) -> T,
+    {
+        let mut idents = self.idents.lock();
+        let binding = idents.get_mut(&ident)?;
+        let error = binding.errors.pop_front()?;
+        binding.errors_bytes -= error.message.len();
+        Some(f(&error))
+    }
+
+    /// Queue an ICMP error quoting an echo request sent with `ident`.
+    pub fn incoming_icmp_error(&self, ident: u16, src: IpAddress, message: &[u8]) {
+        let mut idents = self.idents.lock();
+        let Some(binding) = idents.get_mut(&ident) else {
+            return;
+        };
+        if binding.errors_bytes + message.len() > ICMP_RX_BUF_LEN {
+            warn!("ICMP socket {}: error queue full, dropped", binding.handle);
+            return;
+        }
+        info!("ICMP socket {}: error from {}", binding.handle, src);
+        binding.errors_bytes += message.len();
+        binding.errors.push_back(IcmpErrorMessage {
+            message: message.to_vec(),
+            src,
+        });
+        SOCKET_EVENTS.notify_readable(WakeKey::Socket(binding.handle));
+    }
+}
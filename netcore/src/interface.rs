@@ -1,30 +1,115 @@
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
 use alloc::sync::Arc;
 use alloc::vec;
+use alloc::vec::Vec;
 use core::ops::DerefMut;
+use core::task::Waker;
 
-use crate::common::{TCP_RX_BUF_LEN, TCP_TX_BUF_LEN, UDP_RX_BUF_LEN, UDP_TX_BUF_LEN};
-use crate::device::NetDeviceWrapper;
-use crate::{KernelNetFunc, NET_INTERFACE};
+use crate::common::{
+    NetError, NetResult, ICMP_RX_BUF_LEN, ICMP_TX_BUF_LEN, IPPROTO_RAW, IPV4_MIN_MTU,
+    RAW_TX_BUF_LEN, TCP_RX_BUF_LEN, TCP_TX_BUF_LEN, UDP_RX_BUF_LEN, UDP_TX_BUF_LEN,
+};
+use crate::device::{NetDeviceWrapper, TransmitOnly};
+use crate::event::WakeKey;
+use crate::{KernelNetFunc, NET_INTERFACE, ROUTE_TABLE, SOCKET_EVENTS};
 use kernel_sync::TicketMutex as Mutex;
-use log::{info};
-use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
+use log::{info, warn};
+use smoltcp::iface::{Config, Interface, MulticastError, Route, SocketHandle, SocketSet};
 use smoltcp::socket;
+use smoltcp::socket::tcp::State;
 use smoltcp::socket::AnySocket;
-use smoltcp::wire::{EthernetAddress, HardwareAddress, IpAddress, IpCidr};
+use smoltcp::wire::{
+    EthernetAddress, HardwareAddress, IpAddress, IpCidr, IpEndpoint, IpListenEndpoint, IpProtocol,
+    IpVersion, Ipv4Address,
+};
 
 pub trait NetInterface: Send + Sync {
     fn ethernet_address(&self) -> EthernetAddress;
     fn setup_ip_addr(&self, ip: IpAddress, prefix_len: u8);
     fn setup_gateway(&self, gateway: IpAddress);
     fn poll(&self, sockets: &Mutex<SocketSet>);
-    fn raw_interface(&self) -> &Mutex<Interface>;
+    fn raw_interface(&self) -> &Mutex<Box<Interface>>;
+    /// Sends a whole frame built by the caller, bypassing smoltcp.
+    fn transmit_frame(&self, frame: &[u8]) -> NetResult<()>;
+    /// Queues an IPv4 UDP packet of `len` bytes, built by `emit`, for the
+    /// next poll to route and send.
+    ///
+    /// It is for datagrams whose source address smoltcp would not pick.
+    /// Returns [`Err(WouldBlock)`](NetError::WouldBlock) if the queue is full.
+    fn send_udp_packet(&self, len: usize, emit: &mut dyn FnMut(&mut [u8])) -> NetResult<()>;
+    /// Adds a raw socket that only sends, for a raw socket of the user, and
+    /// returns its handle.
+    ///
+    /// It sends the packets of the user wrapped in [`IPPROTO_RAW`] ones, which
+    /// the device unwraps.
+    fn add_raw_sender(&self) -> SocketHandle;
+    /// Removes the raw sender `handle`, with the packets it still queues.
+    fn remove_raw_sender(&self, handle: SocketHandle);
+    /// Queues an IPv4 packet of `len` bytes, built by `emit`, on the raw
+    /// sender `handle` for the next poll to route and send.
+    ///
+    /// Returns [`Err(WouldBlock)`](NetError::WouldBlock) if its queue is full.
+    fn send_raw_packet(
+        &self,
+        handle: SocketHandle,
+        len: usize,
+        emit: &mut dyn FnMut(&mut [u8]),
+    ) -> NetResult<()>;
+    /// Whether the raw sender `handle` has room for a packet.
+    fn raw_sender_can_send(&self, handle: SocketHandle) -> bool;
+    /// Wake `waker` once, when the raw sender `handle` sent a packet.
+    fn register_raw_sender_waker(&self, handle: SocketHandle, waker: &Waker);
+    /// Joins the multicast group `addr` for one more socket, sending an IGMP
+    /// report when the interface was not a member yet.
+    fn join_multicast_group(&self, addr: IpAddress) -> NetResult<()>;
+    /// Leaves the multicast group `addr` for one socket, sending an IGMP
+    /// leave when it was the last one.
+    fn leave_multicast_group(&self, addr: IpAddress) -> NetResult<()>;
+    /// Whether a socket of this host has joined the multicast group `addr`.
+    fn has_multicast_group(&self, addr: IpAddress) -> bool;
+    /// Whether `addr` is the limited broadcast address, or the broadcast
+    /// address of the subnet of the interface.
+    fn is_broadcast(&self, addr: IpAddress) -> bool;
+    /// Adds a route to `cidr`, replacing any previous one.
+    ///
+    /// With `via`, the packets go through that router; without, `cidr` is on
+    /// the link and only `mtu` is set. `mtu` overrides the MTU of the device
+    /// for the packets to `cidr`, like `ip route add ... mtu`, but only to
+    /// lower it.
+    fn add_route(&self, cidr: IpCidr, via: Option<IpAddress>, mtu: Option<usize>) -> NetResult<()>;
+    /// Removes the route to `cidr` and its MTU override.
+    fn remove_route(&self, cidr: IpCidr) -> NetResult<()>;
+    /// The MTU of the device.
+    fn mtu(&self) -> usize;
+    /// Changes the MTU of the device, if its driver accepts `mtu`.
+    ///
+    /// The interface keeps all its state. The connections established before
+    /// keep the MSS they agreed on, and their segments that no longer fit
+    /// are split into smaller ones.
+    fn set_mtu(&self, mtu: usize) -> NetResult<()>;
 }
 
 pub struct NetInterfaceWrapper {
     dev: Mutex<NetDeviceWrapper>,
-    interface: Mutex<Interface>,
+    interface: Mutex<Box<Interface>>,
     timer: Arc<dyn KernelNetFunc>,
     ether_addr: EthernetAddress,
+    /// The joined multicast groups, with how many sockets joined each.
+    ///
+    /// The device checks them on receive, so they are locked after the
+    /// device and the interface.
+    multicast_groups: Mutex<BTreeMap<Ipv4Address, usize>>,
+    /// The broadcast addresses of the subnets of the interface, which the
+    /// device can't ask smoltcp for while the interface is polled.
+    subnet_broadcasts: Mutex<Vec<Ipv4Address>>,
+    /// Sockets that only send, polled without receiving so that they never
+    /// see incoming packets. A raw socket in `SOCKET_SET` would keep smoltcp
+    /// from answering the packets of its protocol, like the datagrams to
+    /// closed UDP ports.
+    send_only: Mutex<SocketSet<'static>>,
+    /// The raw socket in `send_only` for [`NetInterface::send_udp_packet`].
+    raw_udp: SocketHandle,
 }
 
 impl NetInterfaceWrapper {
@@ -33,24 +118,65 @@ impl NetInterfaceWrapper {
         timer: Arc<dyn KernelNetFunc>,
         ether_addr: EthernetAddress,
     ) -> Self {
-        let mut config = if ether_addr == EthernetAddress([0, 0, 0, 0, 0, 0]) {
-            Config::new(HardwareAddress::Ip)
-        } else {
-            Config::new(HardwareAddress::Ethernet(ether_addr))
-        };
-        config.random_seed = 0x9898998;
         let mut dev = dev;
-        let time = timer.now().into();
-        let interface = Interface::new(config, &mut dev, time);
+        let interface = new_interface(&mut dev, timer.as_ref(), ether_addr);
+        let mut send_only = SocketSet::new(vec![]);
+        let raw_udp = send_only.add(SocketSetWrapper::new_raw_socket(IpProtocol::Udp));
         Self {
             dev: Mutex::new(dev),
             interface: Mutex::new(interface),
             timer,
             ether_addr,
+            multicast_groups: Mutex::new(BTreeMap::new()),
+            subnet_broadcasts: Mutex::new(Vec::new()),
+            send_only: Mutex::new(send_only),
+            raw_udp,
+        }
+    }
+
+    /// Programs the multicast filter of the NIC with the joined groups.
+    fn update_multicast_filter(
+        &self,
+        dev: &mut NetDeviceWrapper,
+        groups: &BTreeMap<Ipv4Address, usize>,
+    ) {
+        if self.ether_addr == EthernetAddress([0, 0, 0, 0, 0, 0]) {
+            return;
+        }
+        // IGMP queries are sent to all-systems.
+        let addrs: Vec<EthernetAddress> = core::iter::once(&Ipv4Address::MULTICAST_ALL_SYSTEMS)
+            .chain(groups.keys())
+            .map(|&addr| multicast_mac(addr))
+            .collect();
+        if let Err(e) = dev.set_multicast_filter(&addrs) {
+            warn!("failed to set the multicast filter: {:?}", e);
         }
     }
 }
 
+/// Makes the smoltcp interface, boxed right away so that it is not moved
+/// around by value.
+fn new_interface(
+    dev: &mut NetDeviceWrapper,
+    timer: &dyn KernelNetFunc,
+    ether_addr: EthernetAddress,
+) -> Box<Interface> {
+    let mut config = if ether_addr == EthernetAddress([0, 0, 0, 0, 0, 0]) {
+        Config::new(HardwareAddress::Ip)
+    } else {
+        Config::new(HardwareAddress::Ethernet(ether_addr))
+    };
+    config.random_seed = timer.random();
+    let time = timer.now().into();
+    Box::new(Interface::new(config, dev, time))
+}
+
+/// The Ethernet address an IPv4 multicast group maps to (RFC 1112).
+fn multicast_mac(addr: Ipv4Address) -> EthernetAddress {
+    let bytes = addr.as_bytes();
+    EthernetAddress([0x01, 0x00, 0x5e, bytes[1] & 0x7f, bytes[2], bytes[3]])
+}
+
 impl NetInterface for NetInterfaceWrapper {
     fn ethernet_address(&self) -> EthernetAddress {
         self.ether_addr
@@ -58,9 +184,14 @@ impl NetInterface for NetInterfaceWrapper {
 
     fn setup_ip_addr(&self, ip: IpAddress, prefix_len: u8) {
         let mut interface = self.interface.lock();
+        let cidr = IpCidr::new(ip, prefix_len);
         interface.update_ip_addrs(|ips| {
-            ips.push(IpCidr::new(ip, prefix_len)).unwrap();
-        })
+            ips.push(cidr).unwrap();
+        });
+        let IpCidr::Ipv4(cidr) = cidr;
+        if let Some(broadcast) = cidr.broadcast() {
+            self.subnet_broadcasts.lock().push(broadcast);
+        }
     }
 
     fn setup_gateway(&self, gateway: IpAddress) {
@@ -76,11 +207,182 @@ impl NetInterface for NetInterfaceWrapper {
         let mut sockets = sockets.lock();
         let timestamp = self.timer.now();
         interface.poll(timestamp.into(), dev.deref_mut(), &mut sockets);
+        drop(sockets);
+        let mut send_only = self.send_only.lock();
+        let mut dev = TransmitOnly(dev.deref_mut());
+        interface.poll(timestamp.into(), &mut dev, &mut send_only);
     }
 
-    fn raw_interface(&self) -> &Mutex<Interface> {
+    fn raw_interface(&self) -> &Mutex<Box<Interface>> {
         &self.interface
     }
+
+    fn transmit_frame(&self, frame: &[u8]) -> NetResult<()> {
+        self.dev.lock().transmit_frame(frame)
+    }
+
+    fn send_udp_packet(&self, len: usize, emit: &mut dyn FnMut(&mut [u8])) -> NetResult<()> {
+        self.send_raw_packet(self.raw_udp, len, emit)
+    }
+
+    fn add_raw_sender(&self) -> SocketHandle {
+        let socket = SocketSetWrapper::new_raw_socket(IpProtocol::from(IPPROTO_RAW));
+        self.send_only.lock().add(socket)
+    }
+
+    fn remove_raw_sender(&self, handle: SocketHandle) {
+        self.send_only.lock().remove(handle);
+    }
+
+    fn send_raw_packet(
+        &self,
+        handle: SocketHandle,
+        len: usize,
+        emit: &mut dyn FnMut(&mut [u8]),
+    ) -> NetResult<()> {
+        let mut send_only = self.send_only.lock();
+        let socket = send_only.get_mut::<socket::raw::Socket>(handle);
+        let tx_buf = socket.send(len).map_err(|_| NetError::WouldBlock)?;
+        emit(tx_buf);
+        Ok(())
+    }
+
+    fn raw_sender_can_send(&self, handle: SocketHandle) -> bool {
+        let mut send_only = self.send_only.lock();
+        send_only.get_mut::<socket::raw::Socket>(handle).can_send()
+    }
+
+    fn register_raw_sender_waker(&self, handle: SocketHandle, waker: &Waker) {
+        let mut send_only = self.send_only.lock();
+        let socket = send_only.get_mut::<socket::raw::Socket>(handle);
+        socket.register_send_waker(waker);
+    }
+
+    fn join_multicast_group(&self, addr: IpAddress) -> NetResult<()> {
+        let IpAddress::Ipv4(addr) = addr;
+        let mut dev = self.dev.lock();
+        let mut interface = self.interface.lock();
+        let mut groups = self.multicast_groups.lock();
+        if let Some(count) = groups.get_mut(&addr) {
+            *count += 1;
+            return Ok(());
+        }
+        let timestamp = self.timer.now().into();
+        match interface.join_multicast_group(dev.deref_mut(), addr, timestamp) {
+            Ok(_) => {}
+            // the group is joined, the report goes out with the next query.
+            Err(MulticastError::Exhausted) => warn!("IGMP report for {} not sent", addr),
+            Err(e) => {
+                warn!("failed to join multicast group {}: {:?}", addr, e);
+                return Err(NetError::NoBufs);
+            }
+        }
+        groups.insert(addr, 1);
+        info!("joined multicast group {}", addr);
+        self.update_multicast_filter(&mut dev, &groups);
+        Ok(())
+    }
+
+    fn leave_multicast_group(&self, addr: IpAddress) -> NetResult<()> {
+        let IpAddress::Ipv4(addr) = addr;
+        let mut dev = self.dev.lock();
+        let mut interface = self.interface.lock();
+        let mut groups = self.multicast_groups.lock();
+        let Some(count) = groups.get_mut(&addr) else {
+            return Err(NetError::NotFound);
+        };
+        *count -= 1;
+        if *count > 0 {
+            return Ok(());
+        }
+        groups.remove(&addr);
+        let timestamp = self.timer.now().into();
+        if let Err(e) = interface.leave_multicast_group(dev.deref_mut(), addr, timestamp) {
+            warn!("IGMP leave for {} not sent: {:?}", addr, e);
+        }
+        info!("left multicast group {}", addr);
+        self.update_multicast_filter(&mut dev, &groups);
+        Ok(())
+    }
+
+    fn has_multicast_group(&self, addr: IpAddress) -> bool {
+        let IpAddress::Ipv4(addr) = addr;
+        self.multicast_groups.lock().contains_key(&addr)
+    }
+
+    fn is_broadcast(&self, addr: IpAddress) -> bool {
+        let IpAddress::Ipv4(addr) = addr;
+        // smoltcp sends both kinds to the broadcast MAC address.
+        addr.is_broadcast() || self.subnet_broadcasts.lock().contains(&addr)
+    }
+
+    fn add_route(&self, cidr: IpCidr, via: Option<IpAddress>, mtu: Option<usize>) -> NetResult<()> {
+        if mtu.is_some_and(|mtu| mtu < IPV4_MIN_MTU) {
+            warn!("route {}: invalid MTU {:?}", cidr, mtu);
+            return Err(NetError::InvalidInput);
+        }
+        // the device can't send larger packets, whatever the route says.
+        let mtu = mtu.map(|mtu| mtu.min(self.mtu()));
+        if let Some(via_router) = via {
+            let mut interface = self.interface.lock();
+            let mut full = false;
+            interface.routes_mut().update(|routes| {
+                routes.retain(|route| route.cidr != cidr);
+                full = routes
+                    .push(Route {
+                        cidr,
+                        via_router,
+                        preferred_until: None,
+                        expires_at: None,
+                    })
+                    .is_err();
+            });
+            if full {
+                warn!("route {}: route table full", cidr);
+                return Err(NetError::NoBufs);
+            }
+        }
+        ROUTE_TABLE.set_route_mtu(cidr, mtu);
+        info!("route {}: via {:?}, MTU {:?}", cidr, via, mtu);
+        Ok(())
+    }
+
+    fn remove_route(&self, cidr: IpCidr) -> NetResult<()> {
+        let mut removed = false;
+        self.interface.lock().routes_mut().update(|routes| {
+            let len = routes.len();
+            routes.retain(|route| route.cidr != cidr);
+            removed = routes.len() != len;
+        });
+        if !ROUTE_TABLE.set_route_mtu(cidr, None) && !removed {
+            warn!("route {}: not found", cidr);
+            return Err(NetError::NotFound);
+        }
+        info!("route {}: removed", cidr);
+        Ok(())
+    }
+
+    fn mtu(&self) -> usize {
+        self.dev.lock().mtu()
+    }
+
+    fn set_mtu(&self, mtu: usize) -> NetResult<()> {
+        if mtu < IPV4_MIN_MTU {
+            warn!("invalid MTU {}", mtu);
+            return Err(NetError::InvalidInput);
+        }
+        let mut dev = self.dev.lock();
+        let old_mtu = dev.mtu();
+        if mtu == old_mtu {
+            return Ok(());
+        }
+        if let Err(e) = dev.set_mtu(mtu) {
+            warn!("the driver rejected MTU {}: {:?}", mtu, e);
+            return Err(e);
+        }
+        info!("MTU changed from {} to {}", old_mtu, mtu);
+        Ok(())
+    }
 }
 
 pub struct SocketSetWrapper<'a>(Mutex<SocketSet<'a>>);
@@ -114,6 +416,33 @@ impl<'a> SocketSetWrapper<'a> {
         socket::udp::Socket::new(udp_rx_buffer, udp_tx_buffer)
     }
 
+    pub fn new_icmp_socket() -> socket::icmp::Socket<'a> {
+        let icmp_rx_buffer = socket::icmp::PacketBuffer::new(
+            vec![socket::icmp::PacketMetadata::EMPTY; 8],
+            vec![0; ICMP_RX_BUF_LEN],
+        );
+        let icmp_tx_buffer = socket::icmp::PacketBuffer::new(
+            vec![socket::icmp::PacketMetadata::EMPTY; 8],
+            vec![0; ICMP_TX_BUF_LEN],
+        );
+        socket::icmp::Socket::new(icmp_rx_buffer, icmp_tx_buffer)
+    }
+
+    pub fn new_dns_socket(servers: &[IpAddress]) -> socket::dns::Socket<'a> {
+        socket::dns::Socket::new(servers, vec![])
+    }
+
+    /// A raw socket that never receives: netcore queues the packets of raw
+    /// sockets itself, in the [`RawTable`](crate::raw_table::RawTable).
+    pub fn new_raw_socket(protocol: IpProtocol) -> socket::raw::Socket<'a> {
+        let raw_rx_buffer = socket::raw::PacketBuffer::new(vec![], vec![]);
+        let raw_tx_buffer = socket::raw::PacketBuffer::new(
+            vec![socket::raw::PacketMetadata::EMPTY; 8],
+            vec![0; RAW_TX_BUF_LEN],
+        );
+        socket::raw::Socket::new(IpVersion::Ipv4, protocol, raw_rx_buffer, raw_tx_buffer)
+    }
+
     pub fn add<T: AnySocket<'a>>(&self, socket: T) -> SocketHandle {
         let handle = self.0.lock().add(socket);
         info!("socket {}: created", handle);
@@ -138,13 +467,63 @@ impl<'a> SocketSetWrapper<'a> {
         f(socket)
     }
 
+    /// Run `f` with the socket set locked, for work on several sockets at once.
+    pub fn with_sockets_mut<R, F>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut SocketSet<'a>) -> R,
+    {
+        f(&mut *self.0.lock())
+    }
+
+    /// Whether a TCP connection other than a listener is using the local
+    /// `endpoint`.
+    ///
+    /// Connections in `TIME_WAIT` are only counted when `time_wait` is true,
+    /// which lets `SO_REUSEADDR` sockets rebind right after a restart.
+    pub fn tcp_endpoint_in_use(&self, endpoint: IpListenEndpoint, time_wait: bool) -> bool {
+        self.0.lock().iter().any(|(_, socket)| {
+            let Some(socket) = socket::tcp::Socket::downcast(socket) else {
+                return false;
+            };
+            match socket.state() {
+                State::Closed | State::Listen => return false,
+                State::TimeWait if !time_wait => return false,
+                _ => {}
+            }
+            socket.local_endpoint().is_some_and(|local| {
+                local.port == endpoint.port && endpoint.addr.map_or(true, |addr| addr == local.addr)
+            })
+        })
+    }
+
+    /// Collects the local ports of TCP connections to `remote`, or of all TCP
+    /// connections if `remote` is `None`.
+    pub fn tcp_ports_in_use(&self, remote: Option<IpEndpoint>) -> BTreeSet<u16> {
+        self.0
+            .lock()
+            .iter()
+            .filter_map(|(_, socket)| socket::tcp::Socket::downcast(socket))
+            .filter(|socket| remote.is_none() || socket.remote_endpoint() == remote)
+            .filter_map(|socket| socket.local_endpoint())
+            .map(|local| local.port)
+            .collect()
+    }
+
     /// The NET_INTERFACE should be initialized before calling this function.
     pub fn poll_interfaces(&self) {
         NET_INTERFACE.get().unwrap().poll(&self.0);
+        // wake the waiters now that the socket set is unlocked.
+        SOCKET_EVENTS.dispatch();
     }
 
     pub fn remove(&self, handle: SocketHandle) {
-        self.0.lock().remove(handle);
+        let wakers = {
+            let mut set = self.0.lock();
+            set.remove(handle);
+            // with the set locked, so that the socket is never re-armed.
+            SOCKET_EVENTS.detach(WakeKey::Socket(handle))
+        };
+        wakers.into_iter().for_each(Waker::wake);
         info!("socket {}: destroyed", handle);
     }
 }
@@ -0,0 +1,108 @@
+//! [`embedded_io`] and [`embedded_io_async`] support, so that no_std crates
+//! built on them (TLS, HTTP, MQTT clients...) can run over netcore sockets.
+
+use core::future::poll_fn;
+
+use embedded_io::ErrorKind;
+
+use crate::asynch::{poll_io, AsyncTcpStream};
+use crate::common::NetError;
+use crate::tcp::TcpSocket;
+
+impl embedded_io::Error for NetError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            NetError::AddrInUse => ErrorKind::AddrInUse,
+            NetError::InvalidInput => ErrorKind::InvalidInput,
+            NetError::NotConnected => ErrorKind::NotConnected,
+            NetError::Unaddressable => ErrorKind::AddrNotAvailable,
+            NetError::AlreadyExists => ErrorKind::AlreadyExists,
+            NetError::ConnectionRefused => ErrorKind::ConnectionRefused,
+            NetError::ConnectionReset => ErrorKind::ConnectionReset,
+            NetError::Interrupted => ErrorKind::Interrupted,
+            NetError::NotFound => ErrorKind::NotFound,
+            NetError::TimedOut => ErrorKind::TimedOut,
+            NetError::NoBufs => ErrorKind::OutOfMemory,
+            NetError::PermissionDenied => ErrorKind::PermissionDenied,
+            NetError::WouldBlock
+            | NetError::Again
+            | NetError::BadState
+            | NetError::DeviceError
+            | NetError::HostUnreachable
+            | NetError::NetworkUnreachable => ErrorKind::Other,
+        }
+    }
+}
+
+impl embedded_io::ErrorType for TcpSocket {
+    type Error = NetError;
+}
+
+impl embedded_io::Read for TcpSocket {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, NetError> {
+        self.recv(buf)
+    }
+}
+
+impl embedded_io::Write for TcpSocket {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, NetError> {
+        self.send(buf)
+    }
+
+    /// Data is handed to smoltcp by `write`, which sends it out on its own.
+    fn flush(&mut self) -> Result<(), NetError> {
+        Ok(())
+    }
+}
+
+impl embedded_io::ReadReady for TcpSocket {
+    fn read_ready(&mut self) -> Result<bool, NetError> {
+        Ok(self.poll()?.readable)
+    }
+}
+
+impl embedded_io::WriteReady for TcpSocket {
+    fn write_ready(&mut self) -> Result<bool, NetError> {
+        Ok(self.poll()?.writable)
+    }
+}
+
+/// Waits for the socket without blocking the task, also when the socket is
+/// in blocking mode.
+impl embedded_io_async::Read for TcpSocket {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, NetError> {
+        let socket = &*self;
+        poll_fn(|cx| poll_io(socket, cx, || socket.recv_nonblocking(buf))).await
+    }
+}
+
+impl embedded_io_async::Write for TcpSocket {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, NetError> {
+        let socket = &*self;
+        poll_fn(|cx| poll_io(socket, cx, || socket.send_nonblocking(buf))).await
+    }
+
+    async fn flush(&mut self) -> Result<(), NetError> {
+        Ok(())
+    }
+}
+
+impl embedded_io::ErrorType for AsyncTcpStream {
+    type Error = NetError;
+}
+
+impl embedded_io_async::Read for AsyncTcpStream {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, NetError> {
+        AsyncTcpStream::read(self, buf).await
+    }
+}
+
+impl embedded_io_async::Write for AsyncTcpStream {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, NetError> {
+        AsyncTcpStream::write(self, buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), NetError> {
+        Ok(())
+    }
+}
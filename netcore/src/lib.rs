@@ -1,35 +1,74 @@
-#![feature(new_uninit)]
 #![feature(ip_in_core)]
 #![no_std]
 
 extern crate alloc;
 
-use crate::common::NetError;
+use crate::common::{NetError, NetResult, STANDARD_MTU};
+use crate::connect_table::ConnectTable;
+use crate::event::SocketEvents;
+use crate::icmp_table::IcmpTable;
 use crate::interface::{NetInterface, NetInterfaceWrapper, SocketSetWrapper};
 use crate::listen_table::ListenTable;
+use crate::mss_table::MssTable;
+use crate::packet_table::PacketTable;
+use crate::raw_table::RawTable;
+use crate::reassembly_table::ReassemblyTable;
+use crate::route_table::RouteTable;
+use crate::udp_table::UdpTable;
 use alloc::boxed::Box;
 use alloc::sync::Arc;
 use core::any::Any;
+use core::sync::atomic::Ordering;
 use preprint::pprintln;
 use smoltcp::time::Instant;
-use smoltcp::wire::IpAddress;
+use smoltcp::wire::{IpAddress, IpCidr};
 use spin::{Lazy, Once};
 
 mod addr;
+pub mod asynch;
+pub mod bpf;
 pub mod common;
+mod connect_table;
+pub mod icmp;
+mod icmp_table;
 mod interface;
+#[cfg(feature = "embedded-io")]
+mod io;
 mod listen_table;
+mod mss_table;
+pub mod packet;
+mod packet_table;
 
 mod device;
+pub mod dns;
+mod event;
+pub mod poller;
+mod port;
+pub mod raw;
+mod raw_table;
+mod reassembly_table;
+mod route_table;
 pub mod tcp;
 pub mod udp;
+mod udp_table;
 use crate::device::NetDeviceWrapper;
+pub use event::SocketUpcall;
+pub use port::{ephemeral_port_range, set_ephemeral_port_range};
 pub use smoltcp::phy::Medium;
 pub use smoltcp::wire::EthernetAddress;
 
 pub static NET_INTERFACE: Once<NetInterfaceWrapper> = Once::new();
 pub static SOCKET_SET: Lazy<SocketSetWrapper> = Lazy::new(SocketSetWrapper::new);
-pub static LISTENING_TABLE: Lazy<ListenTable> = Lazy::new(ListenTable::new);
+pub static LISTENING_TABLE: ListenTable = ListenTable::new();
+pub static CONNECT_TABLE: ConnectTable = ConnectTable::new();
+pub static UDP_TABLE: UdpTable = UdpTable::new();
+pub static ICMP_TABLE: IcmpTable = IcmpTable::new();
+pub static PACKET_TABLE: PacketTable = PacketTable::new();
+pub static RAW_TABLE: RawTable = RawTable::new();
+pub static ROUTE_TABLE: RouteTable = RouteTable::new();
+pub static MSS_TABLE: MssTable = MssTable::new();
+pub static REASSEMBLY_TABLE: ReassemblyTable = ReassemblyTable::new();
+pub static SOCKET_EVENTS: SocketEvents = SocketEvents::new();
 pub static KERNEL_NET_FUNC: Once<Arc<dyn KernelNetFunc>> = Once::new();
 
 pub struct NetInstant {
@@ -45,12 +84,88 @@ impl From<NetInstant> for Instant {
 pub trait KernelNetFunc: Send + Sync {
     fn now(&self) -> NetInstant;
     fn yield_now(&self) -> bool; // equal to suspend in kernel
+    /// A random number from the kernel, used for ephemeral ports and TCP
+    /// initial sequence numbers.
+    fn random(&self) -> u64;
+}
+
+/// Which checksums of one protocol a NIC handles itself.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolOffload {
+    /// The NIC verifies the checksums of received packets, and marks the
+    /// good ones with [`NetBufOps::checksum_verified`].
+    pub rx: bool,
+    /// The NIC fills in the checksums of transmitted packets.
+    pub tx: bool,
+}
+
+/// The checksum offloads of a NIC, per protocol.
+///
+/// ICMP is always checksummed in software.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ChecksumOffload {
+    /// The IPv4 header checksum. With `tx`, the NIC computes it on its own.
+    pub ipv4: ProtocolOffload,
+    /// With `tx`, the NIC computes it as [`NetBufOps::set_checksum_partial`]
+    /// asks.
+    pub tcp: ProtocolOffload,
+    /// Like `tcp`.
+    pub udp: ProtocolOffload,
+}
+
+impl ChecksumOffload {
+    /// Every checksum offloaded, for devices that never corrupt packets.
+    pub const fn all() -> Self {
+        let both = ProtocolOffload { rx: true, tx: true };
+        Self {
+            ipv4: both,
+            tcp: both,
+            udp: both,
+        }
+    }
+}
+
+/// How a large TCP packet splits into segments, like `gso_size` and
+/// `hdr_len` of virtio-net.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpSegmentation {
+    /// The length of the headers every segment repeats: link, IPv4 and TCP.
+    pub header_len: usize,
+    /// The payload of each segment, but the last.
+    pub mss: u16,
 }
 
 pub trait NetBufOps: Any {
     fn packet(&self) -> &[u8];
     fn packet_mut(&mut self) -> &mut [u8];
     fn packet_len(&self) -> usize;
+
+    /// Whether the NIC verified the checksums of this received packet, for
+    /// the protocols it offloads. netcore verifies the others in software.
+    fn checksum_verified(&self) -> bool {
+        false
+    }
+
+    /// Asks the NIC to checksum this packet when transmitting it: to sum the
+    /// bytes from `start` to the end, and to store the result at
+    /// `start + offset`, like `csum_start` and `csum_offset` of virtio-net.
+    ///
+    /// The checksum field already holds the sum of the pseudo header.
+    fn set_checksum_partial(&mut self, _start: usize, _offset: usize) {}
+
+    /// How this received packet was coalesced from segments (LRO), if it was.
+    ///
+    /// Coalesced packets must be [`checksum_verified`](Self::checksum_verified),
+    /// as their checksums are not the ones on the wire.
+    fn segmentation(&self) -> Option<TcpSegmentation> {
+        None
+    }
+
+    /// Asks the NIC to split this TCP packet to transmit into segments (TSO).
+    ///
+    /// netcore has already asked for its checksum with
+    /// [`set_checksum_partial`](Self::set_checksum_partial).
+    fn set_segmentation(&mut self, _segmentation: TcpSegmentation) {}
 }
 
 /// Operations that require a network device (NIC) driver to implement.
@@ -98,8 +213,54 @@ pub trait NetDriverOps: Send + Sync {
     /// Allocate a memory buffer of a specified size for network transmission,
     /// returns [`DevResult`]
     fn alloc_tx_buffer(&mut self, size: usize) -> Result<Box<dyn NetBufOps>, NetError>;
+
+    /// Sets the multicast addresses the NIC should receive, besides its own
+    /// address and broadcast.
+    ///
+    /// netcore calls it whenever the joined IPv4 multicast groups change. The
+    /// default does nothing, which suits NICs that receive every multicast
+    /// frame.
+    fn set_multicast_filter(&mut self, _addrs: &[EthernetAddress]) -> Result<(), NetError> {
+        Ok(())
+    }
+    /// The MTU of the link: the largest IP packet a frame carries, without
+    /// the link header.
+    ///
+    /// netcore sizes its packets, and the MSS of TCP, from it. The default is
+    /// the [`STANDARD_MTU`] of Ethernet.
+    fn mtu(&self) -> usize {
+        STANDARD_MTU
+    }
+
+    /// Changes the MTU of the link to `mtu`.
+    ///
+    /// The driver rejects an MTU it can't send or receive frames of with
+    /// [`NetError::InvalidInput`]. The default only accepts the current one.
+    fn set_mtu(&mut self, mtu: usize) -> Result<(), NetError> {
+        if mtu == self.mtu() {
+            Ok(())
+        } else {
+            Err(NetError::InvalidInput)
+        }
+    }
+    /// The checksums the NIC verifies and fills in, which netcore then
+    /// leaves to it. The default offloads nothing.
+    fn checksum_offload(&self) -> ChecksumOffload {
+        ChecksumOffload::default()
+    }
+
+    /// Whether the NIC splits large TCP packets into segments itself (TSO).
+    ///
+    /// netcore then hands it packets of up to 64 KiB, marked with
+    /// [`NetBufOps::set_segmentation`]. It needs the TCP checksum offloaded on
+    /// transmit too. The default is no.
+    fn tcp_segmentation_offload(&self) -> bool {
+        false
+    }
 }
 
+/// Sets up the interface on `device` with the address `ip` and the default
+/// gateway `gate_way`.
 pub fn init_net(
     device: Box<dyn NetDriverOps>,
     kernel_func: Arc<dyn KernelNetFunc>,
@@ -130,3 +291,37 @@ pub fn init_net(
 pub fn poll_interfaces() {
     SOCKET_SET.poll_interfaces();
 }
+
+/// Adds a route to `cidr`, replacing any previous one, like `ip route add`.
+///
+/// With `via`, the packets go through that router; without, `cidr` is on the
+/// link and only `mtu` is set. `mtu` overrides the MTU of the device for the
+/// packets to `cidr`, but only to lower it.
+pub fn add_route(cidr: IpCidr, via: Option<IpAddress>, mtu: Option<usize>) -> NetResult<()> {
+    NET_INTERFACE.get().unwrap().add_route(cidr, via, mtu)
+}
+
+/// Removes the route to `cidr` and its MTU override, like `ip route del`.
+pub fn remove_route(cidr: IpCidr) -> NetResult<()> {
+    NET_INTERFACE.get().unwrap().remove_route(cidr)
+}
+
+/// The packets dropped on transmit, as the driver had no room for them or
+/// they didn't fit the link.
+pub fn tx_dropped() -> usize {
+    device::TX_DROPPED.load(Ordering::Relaxed)
+}
+
+/// The MTU of the device.
+pub fn mtu() -> usize {
+    NET_INTERFACE.get().unwrap().mtu()
+}
+
+/// Changes the MTU of the device, if its driver accepts `mtu`, like
+/// `ip link set mtu`.
+///
+/// The connections established before keep the MSS they agreed on, and
+/// their segments that no longer fit are split into smaller ones.
+pub fn set_mtu(mtu: usize) -> NetResult<()> {
+    NET_INTERFACE.get().unwrap().set_mtu(mtu)
+}
@@ -1,5 +1,6 @@
-use alloc::{boxed::Box, collections::VecDeque};
-use core::ops::{Deref, DerefMut};
+use alloc::{collections::BTreeMap, collections::VecDeque, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::Waker;
 
 use log::{info, warn};
 use smoltcp::iface::{SocketHandle, SocketSet};
@@ -7,20 +8,31 @@ use smoltcp::socket::tcp::{self, State};
 use smoltcp::wire::{IpAddress, IpEndpoint, IpListenEndpoint};
 
 use crate::common::{NetError, NetResult, LISTEN_QUEUE_SIZE};
+use crate::event::WakeKey;
+use crate::{MSS_TABLE, SOCKET_EVENTS};
 
 use super::{SocketSetWrapper, SOCKET_SET};
 use kernel_sync::TicketMutex as Mutex;
-const PORT_NUM: usize = 65536;
+
+/// Identifies one listening socket inside the [`ListenTable`].
+///
+/// Several listeners may share a port (different addresses, or the same
+/// address with `SO_REUSEPORT`), so the port alone is not enough.
+pub type ListenId = usize;
 
 struct ListenTableEntry {
+    id: ListenId,
     listen_endpoint: IpListenEndpoint,
+    reuse_port: bool,
     syn_queue: VecDeque<SocketHandle>,
 }
 
 impl ListenTableEntry {
-    pub fn new(listen_endpoint: IpListenEndpoint) -> Self {
+    pub fn new(id: ListenId, listen_endpoint: IpListenEndpoint, reuse_port: bool) -> Self {
         Self {
+            id,
             listen_endpoint,
+            reuse_port,
             syn_queue: VecDeque::with_capacity(LISTEN_QUEUE_SIZE),
         }
     }
@@ -32,18 +44,60 @@ impl ListenTableEntry {
             None => true,
         }
     }
+
+    /// Whether a new listener on `endpoint` may coexist with this one.
+    ///
+    /// Follows Linux: the addresses must not overlap (a wildcard overlaps with
+    /// everything), unless both sockets set `SO_REUSEPORT` and are bound to
+    /// exactly the same address. `SO_REUSEADDR` never allows two listeners.
+    fn conflicts_with(&self, endpoint: IpListenEndpoint, reuse_port: bool) -> bool {
+        if !addr_overlaps(self.listen_endpoint.addr, endpoint.addr) {
+            return false;
+        }
+        !(self.reuse_port && reuse_port && self.listen_endpoint.addr == endpoint.addr)
+    }
+
+    /// Drop the sockets of the SYN queue whose connection was reset or
+    /// closed before it was accepted.
+    fn prune_syn_queue(&mut self, sockets: &mut SocketSet<'_>) {
+        self.syn_queue.retain(|&handle| {
+            let socket = sockets.get::<tcp::Socket>(handle);
+            if !matches!(socket.state(), State::Closed | State::TimeWait) {
+                return true;
+            }
+            if let (Some(local), Some(remote)) = (socket.local_endpoint(), socket.remote_endpoint())
+            {
+                MSS_TABLE.remove(local, remote);
+            }
+            info!("TCP socket {}: closed before accept", handle);
+            sockets.remove(handle);
+            false
+        });
+    }
 }
 
 impl Drop for ListenTableEntry {
     fn drop(&mut self) {
         for &handle in &self.syn_queue {
+            // connections that were never accepted leave their MSS behind.
+            let endpoints = SOCKET_SET.with_socket::<tcp::Socket, _, _>(handle, |socket| {
+                (socket.local_endpoint(), socket.remote_endpoint())
+            });
+            if let (Some(local), Some(remote)) = endpoints {
+                MSS_TABLE.remove(local, remote);
+            }
             SOCKET_SET.remove(handle);
         }
     }
 }
 
+/// The TCP listeners, grouped by port.
+///
+/// Only ports that have a listener occupy memory, so the table grows with the
+/// number of listening sockets rather than with the port space.
 pub struct ListenTable {
-    tcp: Box<[Mutex<Option<Box<ListenTableEntry>>>]>,
+    tcp: Mutex<BTreeMap<u16, Vec<ListenTableEntry>>>,
+    next_id: AtomicUsize,
 }
 
 impl Default for ListenTable {
@@ -53,55 +107,95 @@ impl Default for ListenTable {
 }
 
 impl ListenTable {
-    pub fn new() -> Self {
-        let tcp = unsafe {
-            let mut buf = Box::new_uninit_slice(PORT_NUM);
-            for i in 0..PORT_NUM {
-                buf[i].write(Mutex::new(None));
-            }
-            buf.assume_init()
-        };
-        Self { tcp }
+    pub const fn new() -> Self {
+        Self {
+            tcp: Mutex::new(BTreeMap::new()),
+            next_id: AtomicUsize::new(0),
+        }
     }
 
     /// Check if the port is available for listening.
     pub fn can_listen(&self, port: u16) -> bool {
-        self.tcp[port as usize].lock().is_none()
+        !self.tcp.lock().contains_key(&port)
+    }
+
+    /// Check whether a socket may bind to `endpoint` without colliding with
+    /// an existing listener.
+    pub fn can_bind(&self, endpoint: IpListenEndpoint, reuse_port: bool) -> bool {
+        !self.tcp.lock().get(&endpoint.port).is_some_and(|entries| {
+            entries
+                .iter()
+                .any(|entry| entry.conflicts_with(endpoint, reuse_port))
+        })
     }
 
-    /// Listen on a port.
+    /// Listen on an endpoint.
     ///
-    /// Create a new `ListenTableEntry` and store it in the table.
-    pub fn listen(&self, listen_endpoint: IpListenEndpoint) -> NetResult<()> {
+    /// Create a new `ListenTableEntry` and store it in the table. The returned
+    /// id must be passed to the other methods to refer to this listener.
+    pub fn listen(
+        &self,
+        listen_endpoint: IpListenEndpoint,
+        reuse_port: bool,
+    ) -> NetResult<ListenId> {
         let port = listen_endpoint.port;
         assert_ne!(port, 0);
-        let mut entry = self.tcp[port as usize].lock();
-        if entry.is_none() {
-            *entry = Some(Box::new(ListenTableEntry::new(listen_endpoint)));
-            Ok(())
-        } else {
-            warn!("socket listen() failed: port {} is in use", port);
-            Err(NetError::AddrInUse)
+        let mut table = self.tcp.lock();
+        let entries = table.entry(port).or_default();
+        if entries
+            .iter()
+            .any(|entry| entry.conflicts_with(listen_endpoint, reuse_port))
+        {
+            warn!("socket listen() failed: {} is in use", listen_endpoint);
+            return Err(NetError::AddrInUse);
         }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        entries.push(ListenTableEntry::new(id, listen_endpoint, reuse_port));
+        Ok(id)
     }
 
     /// Unlisten on a port.
-    pub fn unlisten(&self, port: u16) {
+    pub fn unlisten(&self, port: u16, id: ListenId) {
         info!("TCP socket unlisten on {}", port);
-        *self.tcp[port as usize].lock() = None;
+        let entry = {
+            let mut table = self.tcp.lock();
+            let Some(entries) = table.get_mut(&port) else {
+                return;
+            };
+            let entry = entries
+                .iter()
+                .position(|entry| entry.id == id)
+                .map(|idx| entries.swap_remove(idx));
+            if entries.is_empty() {
+                table.remove(&port);
+            }
+            entry
+        };
+        // Dropping the entry releases its pending sockets, which needs `SOCKET_SET`,
+        // so do it after the table lock is gone.
+        drop(entry);
+        SOCKET_EVENTS.remove(WakeKey::Listener(id));
     }
 
-    /// Check whether the port can accept a connection.
+    /// Check whether the listener can accept a connection.
     ///
-    /// Return `true` if the port is listening and there is at least one connection in the SYN queue.
-    pub fn can_accept(&self, port: u16) -> NetResult<bool> {
-        if let Some(entry) = self.tcp[port as usize].lock().deref() {
-            Ok(entry.syn_queue.iter().any(|&handle| is_connected(handle)))
-        } else {
-            // ax_err!(InvalidInput, "socket accept() failed: not listen")
-            warn!("socket accept() failed: not listen");
-            Err(NetError::InvalidInput)
-        }
+    /// Return `true` if the listener exists and there is at least one connection in the SYN queue.
+    pub fn can_accept(&self, port: u16, id: ListenId) -> NetResult<bool> {
+        // `SOCKET_SET` first, as when packets are received.
+        SOCKET_SET.with_sockets_mut(|sockets| {
+            let mut table = self.tcp.lock();
+            if let Some(entry) = find_entry_mut(table.get_mut(&port), id) {
+                entry.prune_syn_queue(sockets);
+                Ok(entry
+                    .syn_queue
+                    .iter()
+                    .any(|&handle| is_connected(sockets, handle)))
+            } else {
+                // ax_err!(InvalidInput, "socket accept() failed: not listen")
+                warn!("socket accept() failed: not listen");
+                Err(NetError::InvalidInput)
+            }
+        })
     }
 
     // The accept() system call is used with connection-based socket
@@ -113,28 +207,50 @@ impl ListenTable {
     // socket sockfd is unaffected by this call.
 
     /// Accept a connection.
-    pub fn accept(&self, port: u16) -> NetResult<(SocketHandle, (IpEndpoint, IpEndpoint))> {
-        if let Some(entry) = self.tcp[port as usize].lock().deref_mut() {
-            let syn_queue = &mut entry.syn_queue;
-            let (idx, addr_tuple) = syn_queue
-                .iter()
-                .enumerate()
-                .find_map(|(idx, &handle)| {
-                    is_connected(handle).then(|| (idx, get_addr_tuple(handle)))
-                })
-                .ok_or(NetError::WouldBlock)?; // wait for connection
-            if idx > 0 {
-                warn!(
-                    "slow SYN queue enumeration: index = {}, len = {}!",
-                    idx,
-                    syn_queue.len()
-                );
+    pub fn accept(
+        &self,
+        port: u16,
+        id: ListenId,
+    ) -> NetResult<(SocketHandle, (IpEndpoint, IpEndpoint))> {
+        SOCKET_SET.with_sockets_mut(|sockets| {
+            let mut table = self.tcp.lock();
+            if let Some(entry) = find_entry_mut(table.get_mut(&port), id) {
+                entry.prune_syn_queue(sockets);
+                let syn_queue = &mut entry.syn_queue;
+                let (idx, addr_tuple) = syn_queue
+                    .iter()
+                    .enumerate()
+                    .filter(|&(_, &handle)| is_connected(sockets, handle))
+                    .find_map(|(idx, &handle)| Some((idx, get_addr_tuple(sockets, handle)?)))
+                    .ok_or(NetError::WouldBlock)?; // wait for connection
+                if idx > 0 {
+                    warn!(
+                        "slow SYN queue enumeration: index = {}, len = {}!",
+                        idx,
+                        syn_queue.len()
+                    );
+                }
+                let handle = syn_queue.swap_remove_front(idx).unwrap();
+                Ok((handle, addr_tuple))
+            } else {
+                warn!("socket accept() failed: not listen");
+                Err(NetError::InvalidInput)
             }
-            let handle = syn_queue.swap_remove_front(idx).unwrap();
-            Ok((handle, addr_tuple))
-        } else {
-            warn!("socket accept() failed: not listen");
-            Err(NetError::InvalidInput)
+        })
+    }
+
+    /// Register `waker` with every socket in the SYN queue of the listener
+    /// `id`, as the recv waker.
+    ///
+    /// smoltcp uses a waker up when it wakes it, so this must be done again
+    /// after every notification.
+    pub fn register_waker(&self, id: ListenId, waker: &Waker, sockets: &mut SocketSet<'_>) {
+        let table = self.tcp.lock();
+        let entry = table.values().flatten().find(|entry| entry.id == id);
+        for &handle in entry.into_iter().flat_map(|entry| &entry.syn_queue) {
+            sockets
+                .get_mut::<tcp::Socket>(handle)
+                .register_recv_waker(waker);
         }
     }
 
@@ -144,41 +260,99 @@ impl ListenTable {
         dst: IpEndpoint,
         sockets: &mut SocketSet<'_>,
     ) {
-        if let Some(entry) = self.tcp[dst.port as usize].lock().deref_mut() {
-            if !entry.can_accept(dst.addr) {
-                // not listening on this address
-                return;
-            }
-            if entry.syn_queue.len() >= LISTEN_QUEUE_SIZE {
-                // SYN queue is full, drop the packet
-                warn!("SYN queue overflow!");
-                return;
-            }
-            let mut socket = SocketSetWrapper::new_tcp_socket();
-            if socket.listen(entry.listen_endpoint).is_ok() {
-                let handle = sockets.add(socket);
-                info!(
-                    "TCP socket {}: prepare for connection {} -> {}",
-                    handle, src, entry.listen_endpoint
-                );
-                entry.syn_queue.push_back(handle);
-            }
+        let mut table = self.tcp.lock();
+        let Some(entries) = table.get_mut(&dst.port) else {
+            return;
+        };
+        let Some(entry) = select_listener(entries, src, dst.addr) else {
+            // not listening on this address
+            return;
+        };
+        if entry.syn_queue.len() >= LISTEN_QUEUE_SIZE {
+            // SYN queue is full, drop the packet
+            warn!("SYN queue overflow!");
+            return;
         }
+        let mut socket = SocketSetWrapper::new_tcp_socket();
+        if socket.listen(entry.listen_endpoint).is_ok() {
+            // wake the listener's pollers once the handshake completes. Not
+            // before `listen`, which would use the waker up.
+            let (waker, _) = SOCKET_EVENTS.key_wakers(WakeKey::Listener(entry.id));
+            socket.register_recv_waker(&waker);
+            let handle = sockets.add(socket);
+            info!(
+                "TCP socket {}: prepare for connection {} -> {}",
+                handle, src, entry.listen_endpoint
+            );
+            entry.syn_queue.push_back(handle);
+        }
+    }
+}
+
+fn find_entry_mut(
+    entries: Option<&mut Vec<ListenTableEntry>>,
+    id: ListenId,
+) -> Option<&mut ListenTableEntry> {
+    entries?.iter_mut().find(|entry| entry.id == id)
+}
+
+/// Pick the listener that should receive a connection from `src` to `dst`.
+///
+/// A listener bound to `dst` exactly wins over a wildcard one. If several
+/// `SO_REUSEPORT` listeners tie, the connection is spread among them by a
+/// hash of the source endpoint, so one peer always lands on the same socket.
+fn select_listener<'a>(
+    entries: &'a mut [ListenTableEntry],
+    src: IpEndpoint,
+    dst: IpAddress,
+) -> Option<&'a mut ListenTableEntry> {
+    let exact = entries
+        .iter()
+        .any(|entry| entry.listen_endpoint.addr == Some(dst));
+    let candidate = |entry: &&mut ListenTableEntry| {
+        entry.can_accept(dst) && (!exact || entry.listen_endpoint.addr.is_some())
+    };
+    let count = entries.iter_mut().filter(candidate).count();
+    if count == 0 {
+        return None;
+    }
+    let pick = reuseport_hash(src) % count;
+    entries.iter_mut().filter(candidate).nth(pick)
+}
+
+fn reuseport_hash(src: IpEndpoint) -> usize {
+    // FNV-1a over the source address and port.
+    let mut hash: u32 = 0x811c_9dc5;
+    for &byte in src.addr.as_bytes().iter().chain(&src.port.to_be_bytes()) {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    hash as usize
+}
+
+#[inline]
+fn addr_overlaps(a: Option<IpAddress>, b: Option<IpAddress>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a == b,
+        _ => true,
     }
 }
 
-fn is_connected(handle: SocketHandle) -> bool {
-    SOCKET_SET.with_socket::<tcp::Socket, _, _>(handle, |socket| {
-        info!("[is_connected] socket state: {:?}", socket.state());
-        !(socket.state() == State::Listen || socket.state() == State::SynReceived)
-    })
+/// Whether the connection of a socket in the SYN queue completed its
+/// handshake, and was neither reset nor closed since.
+fn is_connected(sockets: &SocketSet<'_>, handle: SocketHandle) -> bool {
+    let socket = sockets.get::<tcp::Socket>(handle);
+    info!("[is_connected] socket state: {:?}", socket.state());
+    !matches!(
+        socket.state(),
+        State::Listen | State::SynReceived | State::Closed | State::TimeWait
+    )
 }
 
-fn get_addr_tuple(handle: SocketHandle) -> (IpEndpoint, IpEndpoint) {
-    SOCKET_SET.with_socket::<tcp::Socket, _, _>(handle, |socket| {
-        (
-            socket.local_endpoint().unwrap(),
-            socket.remote_endpoint().unwrap(),
-        )
-    })
+fn get_addr_tuple(
+    sockets: &SocketSet<'_>,
+    handle: SocketHandle,
+) -> Option<(IpEndpoint, IpEndpoint)> {
+    let socket = sockets.get::<tcp::Socket>(handle);
+    Some((socket.local_endpoint()?, socket.remote_endpoint()?))
 }
@@ -0,0 +1,57 @@
+use alloc::collections::BTreeMap;
+
+use log::{info, warn};
+use smoltcp::wire::IpEndpoint;
+
+use crate::common::MAX_MSS_ENTRIES;
+use kernel_sync::TicketMutex as Mutex;
+
+/// The MSS the peers of TCP connections asked for, when the NIC segments TCP
+/// packets itself (TSO).
+///
+/// smoltcp sizes the segments of a connection from the MSS of the peer's
+/// SYN, so netcore raises it there to get large packets, and keeps the real
+/// one here for the NIC to split them at.
+pub struct MssTable {
+    mss: Mutex<BTreeMap<(IpEndpoint, IpEndpoint), u16>>,
+}
+
+impl Default for MssTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MssTable {
+    pub const fn new() -> Self {
+        Self {
+            mss: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Record the MSS the peer `remote` of the connection from `local` asked
+    /// for, returning whether there was room for it.
+    ///
+    /// Without room, the connection must keep the MSS of the peer: forgetting
+    /// the one of another connection would leave it with the raised MSS.
+    pub fn insert(&self, local: IpEndpoint, remote: IpEndpoint, mss: u16) -> bool {
+        let mut table = self.mss.lock();
+        if table.len() >= MAX_MSS_ENTRIES && !table.contains_key(&(local, remote)) {
+            warn!("TCP {} -> {}: MSS table full, no TSO", local, remote);
+            return false;
+        }
+        table.insert((local, remote), mss);
+        info!("TCP {} -> {}: MSS {}", local, remote, mss);
+        true
+    }
+
+    /// The MSS recorded for the connection from `local` to `remote`.
+    pub fn get(&self, local: IpEndpoint, remote: IpEndpoint) -> Option<u16> {
+        self.mss.lock().get(&(local, remote)).copied()
+    }
+
+    /// Forget the connection from `local` to `remote`.
+    pub fn remove(&self, local: IpEndpoint, remote: IpEndpoint) {
+        self.mss.lock().remove(&(local, remote));
+    }
+}
@@ -0,0 +1,176 @@
+use crate::bpf::{BpfProgram, SockFilter};
+use crate::common::{block_on, copy_truncated, NetError, NetPollState, NetResult};
+use crate::event::WakeKey;
+use crate::interface::NetInterface;
+use crate::packet_table::PacketId;
+use crate::poller::NetPollable;
+use crate::{NET_INTERFACE, PACKET_TABLE, SOCKET_EVENTS};
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::Waker;
+use kernel_sync::TicketMutex as Mutex;
+use log::{info, warn};
+
+pub use crate::packet_table::{PacketInfo, PacketType, ETH_P_ALL, ETH_P_IP};
+
+/// A link-layer socket that provides POSIX-like APIs, like `AF_PACKET`
+/// with `SOCK_RAW`.
+///
+/// It receives a copy of every frame of its protocol before smoltcp
+/// processes it, link-layer header included, and sends frames built by the
+/// caller straight to the device. On devices with an IP medium, the frames
+/// are bare IPv4 packets of protocol [`ETH_P_IP`].
+pub struct PacketSocket {
+    id: PacketId,
+    protocol: Mutex<Option<u16>>,
+    filter: Mutex<Option<Arc<BpfProgram>>>,
+    nonblock: AtomicBool,
+}
+
+impl PacketSocket {
+    /// Creates a new packet socket.
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Self {
+            id: PACKET_TABLE.alloc_id(),
+            protocol: Mutex::new(None),
+            filter: Mutex::new(None),
+            nonblock: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns the bound ethertype, or
+    /// [`Err(NotConnected)`](NetError::NotConnected) if not bound.
+    pub fn protocol(&self) -> NetResult<u16> {
+        self.protocol.lock().ok_or(NetError::NotConnected)
+    }
+
+    /// Returns whether this socket is in nonblocking mode.
+    #[inline]
+    pub fn is_nonblocking(&self) -> bool {
+        self.nonblock.load(Ordering::Acquire)
+    }
+
+    /// Moves this packet socket into or out of nonblocking mode.
+    ///
+    /// In nonblocking mode, [`send`](Self::send) and
+    /// [`recv_from`](Self::recv_from) return
+    /// [`Err(WouldBlock)`](NetError::WouldBlock) instead of waiting.
+    #[inline]
+    pub fn set_nonblocking(&self, nonblocking: bool) {
+        self.nonblock.store(nonblocking, Ordering::Release);
+    }
+
+    /// Binds the socket to the interface and the ethertype `protocol`, or to
+    /// every frame with [`ETH_P_ALL`].
+    ///
+    /// netcore has a single interface, so there is no interface to choose.
+    /// Binding again changes the protocol.
+    pub fn bind(&self, protocol: u16) -> NetResult<()> {
+        let mut self_protocol = self.protocol.lock();
+        PACKET_TABLE.bind(self.id, protocol, self.filter.lock().clone());
+        *self_protocol = Some(protocol);
+        info!(
+            "packet socket {}: bound to protocol {:#06x}",
+            self.id, protocol
+        );
+        Ok(())
+    }
+
+    /// Attaches the classic BPF program `insns`, which every received frame
+    /// must pass before it is queued (`SO_ATTACH_FILTER`).
+    ///
+    /// The value returned by the program is how many bytes of the frame to
+    /// keep, 0 drops it. Returns [`Err(InvalidInput)`](NetError::InvalidInput)
+    /// if the program is rejected by the verifier.
+    pub fn attach_filter(&self, insns: &[SockFilter]) -> NetResult<()> {
+        let filter = Arc::new(BpfProgram::new(insns)?);
+        let mut self_filter = self.filter.lock();
+        PACKET_TABLE.set_filter(self.id, Some(filter.clone()));
+        *self_filter = Some(filter);
+        Ok(())
+    }
+
+    /// Removes the attached filter (`SO_DETACH_FILTER`).
+    ///
+    /// Returns [`Err(NotFound)`](NetError::NotFound) if there is none.
+    pub fn detach_filter(&self) -> NetResult<()> {
+        let mut self_filter = self.filter.lock();
+        if self_filter.take().is_none() {
+            return Err(NetError::NotFound);
+        }
+        PACKET_TABLE.set_filter(self.id, None);
+        Ok(())
+    }
+
+    /// Sends a whole frame, link-layer header included. On success, returns
+    /// the number of bytes written.
+    pub fn send(&self, frame: &[u8]) -> NetResult<usize> {
+        let iface = NET_INTERFACE.get().unwrap();
+        block_on(self.is_nonblocking(), || iface.transmit_frame(frame))?;
+        Ok(frame.len())
+    }
+
+    /// Receives a single frame. On success, returns the number of bytes read
+    /// and where the frame came from.
+    pub fn recv_from(&self, buf: &mut [u8]) -> NetResult<(usize, PacketInfo)> {
+        self.recv_impl(false, buf)
+    }
+
+    /// Receives a single frame, without removing it from the queue.
+    pub fn peek_from(&self, buf: &mut [u8]) -> NetResult<(usize, PacketInfo)> {
+        self.recv_impl(true, buf)
+    }
+
+    /// Close the socket.
+    pub fn shutdown(&self) -> NetResult<()> {
+        if self.protocol.lock().take().is_some() {
+            info!("packet socket {}: shutting down", self.id);
+            PACKET_TABLE.unbind(self.id);
+        }
+        Ok(())
+    }
+
+    /// Whether the socket is readable or writable.
+    pub fn poll(&self) -> NetResult<NetPollState> {
+        Ok(NetPollState {
+            readable: PACKET_TABLE.has_frames(self.id),
+            writable: true,
+            ..Default::default()
+        })
+    }
+}
+
+/// Private methods
+impl PacketSocket {
+    fn recv_impl(&self, peek: bool, buf: &mut [u8]) -> NetResult<(usize, PacketInfo)> {
+        if self.protocol.lock().is_none() {
+            warn!("packet socket {}: recv() failed: not bound", self.id);
+            return Err(NetError::NotConnected);
+        }
+        block_on(self.is_nonblocking(), || {
+            PACKET_TABLE
+                .recv(self.id, peek, |frame| {
+                    (copy_truncated(buf, &frame.frame), frame.info)
+                })
+                .ok_or(NetError::WouldBlock)
+        })
+    }
+}
+
+impl NetPollable for PacketSocket {
+    fn poll(&self) -> NetResult<NetPollState> {
+        PacketSocket::poll(self)
+    }
+
+    fn register_waker(&self, waker: &Waker) {
+        SOCKET_EVENTS.subscribe(WakeKey::Packet(self.id), waker);
+    }
+}
+
+impl Drop for PacketSocket {
+    fn drop(&mut self) {
+        self.shutdown().ok();
+        SOCKET_EVENTS.remove(WakeKey::Packet(self.id));
+    }
+}
@@ -0,0 +1,196 @@
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use log::warn;
+use smoltcp::wire::{EthernetAddress, EthernetFrame};
+
+use crate::bpf::BpfProgram;
+use crate::common::PACKET_RX_BUF_LEN;
+use crate::event::WakeKey;
+use crate::SOCKET_EVENTS;
+use kernel_sync::TicketMutex as Mutex;
+
+/// Identifies one packet socket inside the [`PacketTable`].
+pub type PacketId = usize;
+
+/// Receive frames of every protocol, like `ETH_P_ALL`.
+pub const ETH_P_ALL: u16 = 0x0003;
+/// The ethertype of IPv4, the protocol of the packets of IP-medium devices.
+pub const ETH_P_IP: u16 = 0x0800;
+
+/// Who a received frame was addressed to (`sll_pkttype`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    /// This host (`PACKET_HOST`).
+    Host,
+    /// Everyone on the link (`PACKET_BROADCAST`).
+    Broadcast,
+    /// A multicast group (`PACKET_MULTICAST`).
+    Multicast,
+    /// Another host, seen in promiscuous mode (`PACKET_OTHERHOST`).
+    OtherHost,
+}
+
+/// Where a frame received by a packet socket came from, like `sockaddr_ll`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketInfo {
+    /// The ethertype of the frame.
+    pub protocol: u16,
+    /// The source hardware address, or `None` on IP-medium devices.
+    pub src: Option<EthernetAddress>,
+    pub pkt_type: PacketType,
+}
+
+pub struct PacketFrame {
+    pub frame: Vec<u8>,
+    pub info: PacketInfo,
+}
+
+struct PacketBinding {
+    protocol: u16,
+    filter: Option<Arc<BpfProgram>>,
+    queue: VecDeque<PacketFrame>,
+    queue_bytes: usize,
+}
+
+/// The packet sockets, which get a copy of the frames received by the
+/// device before smoltcp processes them.
+pub struct PacketTable {
+    sockets: Mutex<BTreeMap<PacketId, PacketBinding>>,
+    next_id: AtomicUsize,
+}
+
+impl Default for PacketTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PacketTable {
+    pub const fn new() -> Self {
+        Self {
+            sockets: Mutex::new(BTreeMap::new()),
+            next_id: AtomicUsize::new(0),
+        }
+    }
+
+    /// Allocate an id for a new packet socket.
+    pub fn alloc_id(&self) -> PacketId {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Start delivering the frames of `protocol` that pass `filter` to the
+    /// socket `id`, or change its protocol.
+    pub fn bind(&self, id: PacketId, protocol: u16, filter: Option<Arc<BpfProgram>>) {
+        let mut sockets = self.sockets.lock();
+        let binding = sockets.entry(id).or_insert_with(|| PacketBinding {
+            protocol,
+            filter: None,
+            queue: VecDeque::new(),
+            queue_bytes: 0,
+        });
+        binding.protocol = protocol;
+        binding.filter = filter;
+    }
+
+    /// Replace the filter of the socket `id`, if it is bound.
+    pub fn set_filter(&self, id: PacketId, filter: Option<Arc<BpfProgram>>) {
+        if let Some(binding) = self.sockets.lock().get_mut(&id) {
+            binding.filter = filter;
+        }
+    }
+
+    /// Stop delivering frames to the socket `id`, dropping the queued ones.
+    pub fn unbind(&self, id: PacketId) {
+        self.sockets.lock().remove(&id);
+    }
+
+    /// Whether frames are waiting for the socket `id`.
+    pub fn has_frames(&self, id: PacketId) -> bool {
+        self.sockets
+            .lock()
+            .get(&id)
+            .is_some_and(|binding| !binding.queue.is_empty())
+    }
+
+    /// Take (or with `peek`, look at) the oldest frame of the socket `id`.
+    pub fn recv<F, T>(&self, id: PacketId, peek: bool, f: F) -> Option<T>
+    where
+        F: FnOnce(&PacketFrame) -> T,
+    {
+        let mut sockets = self.sockets.lock();
+        let binding = sockets.get_mut(&id)?;
+        if peek {
+            return binding.queue.front().map(f);
+        }
+        let frame = binding.queue.pop_front()?;
+        binding.queue_bytes -= frame.frame.len();
+        Some(f(&frame))
+    }
+
+    /// Deliver a copy of a received frame to the packet sockets bound to its
+    /// protocol.
+    ///
+    /// `local` is the hardware address of the device, or `None` if it
+    /// carries bare IP packets.
+    pub fn incoming_frame(&self, frame: &[u8], local: Option<EthernetAddress>) {
+        let mut sockets = self.sockets.lock();
+        if sockets.is_empty() {
+            return;
+        }
+        let Some(info) = frame_info(frame, local) else {
+            return;
+        };
+        for (&id, binding) in sockets.iter_mut() {
+            if binding.protocol != ETH_P_ALL && binding.protocol != info.protocol {
+                continue;
+            }
+            // the filter may also cut the frame short.
+            let len = match &binding.filter {
+                Some(filter) => (filter.run(frame) as usize).min(frame.len()),
+                None => frame.len(),
+            };
+            if len == 0 {
+                continue;
+            }
+            if binding.queue_bytes + len > PACKET_RX_BUF_LEN {
+                warn!("packet socket {}: queue full, dropped", id);
+                continue;
+            }
+            binding.queue_bytes += len;
+            binding.queue.push_back(PacketFrame {
+                frame: frame[..len].to_vec(),
+                info,
+            });
+            SOCKET_EVENTS.notify_readable(WakeKey::Packet(id));
+        }
+    }
+}
+
+fn frame_info(frame: &[u8], local: Option<EthernetAddress>) -> Option<PacketInfo> {
+    let Some(local) = local else {
+        return Some(PacketInfo {
+            protocol: ETH_P_IP,
+            src: None,
+            pkt_type: PacketType::Host,
+        });
+    };
+    let ether_frame = EthernetFrame::new_checked(frame).ok()?;
+    let dst = ether_frame.dst_addr();
+    let pkt_type = if dst.is_broadcast() {
+        PacketType::Broadcast
+    } else if dst.is_multicast() {
+        PacketType::Multicast
+    } else if dst == local {
+        PacketType::Host
+    } else {
+        PacketType::OtherHost
+    };
+    Some(PacketInfo {
+        protocol: ether_frame.ethertype().into(),
+        src: Some(ether_frame.src_addr()),
+        pkt_type,
+    })
+}
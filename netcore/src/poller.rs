@@ -0,0 +1,239 @@
+//! Waiting for readiness on many sockets at once, the backend of `epoll` and
+//! `select`.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use alloc::vec::Vec;
+use core::task::Waker;
+use core::time::Duration;
+
+use log::warn;
+use smoltcp::time::{Duration as NetDuration, Instant};
+
+use crate::common::{NetError, NetPollState, NetResult};
+use crate::{KERNEL_NET_FUNC, SOCKET_SET};
+use kernel_sync::TicketMutex as Mutex;
+
+/// A socket that can be registered with a [`Poller`].
+pub trait NetPollable: Send + Sync {
+    /// The current readiness of the socket.
+    fn poll(&self) -> NetResult<NetPollState>;
+
+    /// Wake `waker` once, the next time the readiness of the socket may
+    /// have changed.
+    fn register_waker(&self, waker: &Waker);
+}
+
+/// The events a [`Poller`] entry is interested in.
+///
+/// Errors and hangups are always reported, like `EPOLLERR` and `EPOLLHUP`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PollInterest {
+    /// Report the socket when it is readable (`EPOLLIN`).
+    pub readable: bool,
+    /// Report the socket when it is writable (`EPOLLOUT`).
+    pub writable: bool,
+    /// Report the peer shutting down its writing half (`EPOLLRDHUP`).
+    pub read_hangup: bool,
+    /// Report the socket once per readiness change instead of for as long as
+    /// it stays ready (`EPOLLET`).
+    pub edge_triggered: bool,
+}
+
+impl PollInterest {
+    fn filter(&self, state: NetPollState) -> Option<NetPollState> {
+        let state = NetPollState {
+            readable: self.readable && state.readable,
+            writable: self.writable && state.writable,
+            error: state.error,
+            hangup: state.hangup,
+            read_hangup: self.read_hangup && state.read_hangup,
+        };
+        (state.readable || state.writable || state.error || state.hangup || state.read_hangup)
+            .then_some(state)
+    }
+}
+
+/// A ready socket returned by [`Poller::wait`].
+#[derive(Debug, Clone, Copy)]
+pub struct PollEvent {
+    /// The token the socket was registered with.
+    pub token: u64,
+    /// The readiness, restricted to the events of interest.
+    pub state: NetPollState,
+}
+
+struct PollEntry {
+    source: Arc<dyn NetPollable>,
+    interest: PollInterest,
+    waker: Waker,
+}
+
+/// The tokens whose sockets may have changed readiness since the last check.
+#[derive(Default)]
+struct ReadyList(Mutex<BTreeSet<u64>>);
+
+struct EntryWaker {
+    ready: Arc<ReadyList>,
+    token: u64,
+}
+
+impl Wake for EntryWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.ready.0.lock().insert(self.token);
+    }
+}
+
+/// A set of sockets with the events of interest for each, like an epoll
+/// instance.
+///
+/// Sockets are only checked after netcore was told that they changed, so a
+/// wait costs the same no matter how many idle sockets are registered.
+pub struct Poller {
+    entries: Mutex<BTreeMap<u64, PollEntry>>,
+    ready: Arc<ReadyList>,
+}
+
+impl Default for Poller {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Poller {
+    /// Creates an empty poller.
+    pub fn new() -> Self {
+        Self {
+            entries: Mutex::new(BTreeMap::new()),
+            ready: Arc::new(ReadyList::default()),
+        }
+    }
+
+    /// Registers `source` under `token` (`EPOLL_CTL_ADD`).
+    ///
+    /// Returns [`Err(AlreadyExists)`](NetError::AlreadyExists) if the token
+    /// is taken.
+    pub fn add(
+        &self,
+        token: u64,
+        source: Arc<dyn NetPollable>,
+        interest: PollInterest,
+    ) -> NetResult<()> {
+        let mut entries = self.entries.lock();
+        if entries.contains_key(&token) {
+            warn!("poller add() failed: token {} exists", token);
+            return Err(NetError::AlreadyExists);
+        }
+        let waker = Waker::from(Arc::new(EntryWaker {
+            ready: self.ready.clone(),
+            token,
+        }));
+        entries.insert(
+            token,
+            PollEntry {
+                source,
+                interest,
+                waker,
+            },
+        );
+        // check it at the next wait, it may be ready already.
+        self.ready.0.lock().insert(token);
+        Ok(())
+    }
+
+    /// Changes the events of interest of `token` (`EPOLL_CTL_MOD`).
+    pub fn modify(&self, token: u64, interest: PollInterest) -> NetResult<()> {
+        let mut entries = self.entries.lock();
+        let entry = entries.get_mut(&token).ok_or_else(|| {
+            warn!("poller modify() failed: no token {}", token);
+            NetError::InvalidInput
+        })?;
+        entry.interest = interest;
+        self.ready.0.lock().insert(token);
+        Ok(())
+    }
+
+    /// Unregisters `token` (`EPOLL_CTL_DEL`).
+    pub fn delete(&self, token: u64) -> NetResult<()> {
+        if self.entries.lock().remove(&token).is_none() {
+            warn!("poller delete() failed: no token {}", token);
+            return Err(NetError::InvalidInput);
+        }
+        self.ready.0.lock().remove(&token);
+        Ok(())
+    }
+
+    /// Waits until at least one registered socket is ready, and appends at
+    /// most `max_events` of them to `events`.
+    ///
+    /// A `timeout` of `None` waits forever, and a zero timeout only checks
+    /// once. Returns the number of events, 0 on timeout, or
+    /// [`Err(Interrupted)`](NetError::Interrupted) if the kernel reports a
+    /// signal.
+    pub fn wait(
+        &self,
+        events: &mut Vec<PollEvent>,
+        max_events: usize,
+        timeout: Option<Duration>,
+    ) -> NetResult<usize> {
+        let kernel_func = KERNEL_NET_FUNC.get().unwrap();
+        let deadline = timeout.map(|timeout| {
+            let now: Instant = kernel_func.now().into();
+            now + NetDuration::from(timeout)
+        });
+        loop {
+            SOCKET_SET.poll_interfaces();
+            let count = self.collect(events, max_events);
+            if count > 0 {
+                return Ok(count);
+            }
+            if let Some(deadline) = deadline {
+                let now: Instant = kernel_func.now().into();
+                if now >= deadline {
+                    return Ok(0);
+                }
+            }
+            if kernel_func.yield_now() {
+                return Err(NetError::Interrupted);
+            }
+        }
+    }
+
+    /// Checks the sockets that were woken and reports the ready ones.
+    fn collect(&self, events: &mut Vec<PollEvent>, max_events: usize) -> usize {
+        let woken = core::mem::take(&mut *self.ready.0.lock());
+        let entries = self.entries.lock();
+        let mut count = 0;
+        let mut still_ready = BTreeSet::new();
+        for token in woken {
+            let Some(entry) = entries.get(&token) else {
+                continue;
+            };
+            if count == max_events {
+                // keep it for the next wait.
+                still_ready.insert(token);
+                continue;
+            }
+            // Arm the waker before checking, so no change can slip in between.
+            entry.source.register_waker(&entry.waker);
+            let state = entry.source.poll().unwrap_or(NetPollState {
+                error: true,
+                ..Default::default()
+            });
+            if let Some(state) = entry.interest.filter(state) {
+                events.push(PollEvent { token, state });
+                count += 1;
+                if !entry.interest.edge_triggered {
+                    still_ready.insert(token);
+                }
+            }
+        }
+        self.ready.0.lock().append(&mut still_ready);
+        count
+    }
+}
@@ -0,0 +1,58 @@
+use core::ops::RangeInclusive;
+
+use log::warn;
+
+use crate::common::{NetError, NetResult};
+use crate::KERNEL_NET_FUNC;
+use kernel_sync::TicketMutex as Mutex;
+
+/// The default ephemeral port range, the IANA dynamic ports.
+const DEFAULT_PORT_START: u16 = 0xc000;
+const DEFAULT_PORT_END: u16 = 0xffff;
+
+static EPHEMERAL_RANGE: Mutex<(u16, u16)> = Mutex::new((DEFAULT_PORT_START, DEFAULT_PORT_END));
+
+/// Returns the range that local ports are picked from when a socket is not
+/// bound explicitly.
+pub fn ephemeral_port_range() -> RangeInclusive<u16> {
+    let (start, end) = *EPHEMERAL_RANGE.lock();
+    start..=end
+}
+
+/// Sets the ephemeral port range, like `net.ipv4.ip_local_port_range`.
+///
+/// Returns [`Err(InvalidInput)`](NetError::InvalidInput) if the range is empty
+/// or includes port 0.
+pub fn set_ephemeral_port_range(range: RangeInclusive<u16>) -> NetResult<()> {
+    let (start, end) = range.into_inner();
+    if start == 0 || start > end {
+        warn!("invalid ephemeral port range {}..={}", start, end);
+        return Err(NetError::InvalidInput);
+    }
+    *EPHEMERAL_RANGE.lock() = (start, end);
+    Ok(())
+}
+
+/// Picks a free ephemeral port.
+///
+/// This is the "simple port randomization" algorithm of RFC 6056: the search
+/// starts at a random offset inside the range and walks forward until it
+/// finds a port for which `in_use` returns `false`.
+pub(crate) fn alloc_ephemeral_port<F>(mut in_use: F) -> NetResult<u16>
+where
+    F: FnMut(u16) -> bool,
+{
+    let (start, end) = *EPHEMERAL_RANGE.lock();
+    let num = (end - start) as u32 + 1;
+    let random = KERNEL_NET_FUNC.get().unwrap().random();
+    let mut offset = (random % num as u64) as u32;
+    for _ in 0..num {
+        let port = start + offset as u16;
+        if !in_use(port) {
+            return Ok(port);
+        }
+        offset = (offset + 1) % num;
+    }
+    warn!("no avaliable ports!");
+    Err(NetError::AddrInUse)
+}
@@ -0,0 +1,316 @@
+use super::addr::{from_core_ipaddr, into_core_ipaddr, is_unspecified};
+use crate::bpf::{BpfProgram, SockFilter};
+use crate::common::{
+    block_on, copy_truncated, NetError, NetPollState, NetResult, IPPROTO_RAW, RAW_TX_BUF_LEN,
+};
+use crate::device::NEXT_FRAG_IDENT;
+use crate::event::WakeKey;
+use crate::interface::NetInterface;
+use crate::poller::NetPollable;
+use crate::raw_table::RawId;
+use crate::{NET_INTERFACE, RAW_TABLE, SOCKET_EVENTS};
+use alloc::sync::Arc;
+use alloc::vec;
+use core::net::IpAddr;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use core::task::Waker;
+use kernel_sync::TicketMutex as Mutex;
+use log::{info, warn};
+use smoltcp::iface::SocketHandle;
+use smoltcp::phy::ChecksumCapabilities;
+use smoltcp::wire::{IpAddress, IpProtocol, Ipv4Address, Ipv4Packet, Ipv4Repr};
+
+/// The default TTL of the packets whose header is built by netcore.
+const DEFAULT_TTL: u8 = 64;
+/// The length of an IPv4 header without options.
+const IPV4_HEADER_LEN: usize = 20;
+/// The largest IPv4 packet, as its total length is 16 bits.
+const IPV4_MAX_LEN: usize = 65535;
+
+/// A raw IPv4 socket for one IP protocol, providing POSIX-like APIs
+/// (`SOCK_RAW`).
+///
+/// It receives a copy of every IP packet carrying its protocol, header
+/// included, fragmented datagrams once reassembled. Packets for TCP, UDP and
+/// ICMP are still processed by the stack as usual.
+///
+/// It is kept out of `SOCKET_SET`, so that smoltcp still answers the packets
+/// of its protocol, and sends through a raw socket that never receives.
+pub struct RawSocket {
+    id: RawId,
+    sender: SocketHandle,
+    protocol: IpProtocol,
+    local_addr: Mutex<Option<IpAddress>>,
+    nonblock: AtomicBool,
+    header_included: AtomicBool,
+    ttl: AtomicU8,
+}
+
+impl RawSocket {
+    /// Creates a new raw socket for the IP protocol number `protocol`.
+    pub fn new(protocol: u8) -> Self {
+        let protocol = IpProtocol::from(protocol);
+        let id = RAW_TABLE.alloc_id();
+        RAW_TABLE.bind(id, protocol);
+        Self {
+            id,
+            sender: NET_INTERFACE.get().unwrap().add_raw_sender(),
+            protocol,
+            local_addr: Mutex::new(None),
+            nonblock: AtomicBool::new(false),
+            // like Linux, `IPPROTO_RAW` implies `IP_HDRINCL`.
+            header_included: AtomicBool::new(protocol == IpProtocol::from(IPPROTO_RAW)),
+            ttl: AtomicU8::new(DEFAULT_TTL),
+        }
+    }
+
+    /// Returns the IP protocol number of the socket.
+    pub fn protocol(&self) -> u8 {
+        self.protocol.into()
+    }
+
+    /// Returns the local address, or
+    /// [`Err(NotConnected)`](NetError::NotConnected) if not bound.
+    pub fn local_addr(&self) -> NetResult<IpAddr> {
+        match *self.local_addr.lock() {
+            Some(addr) => Ok(into_core_ipaddr(addr)),
+            None => Err(NetError::NotConnected),
+        }
+    }
+
+    /// Returns whether this socket is in nonblocking mode.
+    #[inline]
+    pub fn is_nonblocking(&self) -> bool {
+        self.nonblock.load(Ordering::Acquire)
+    }
+
+    /// Moves this raw socket into or out of nonblocking mode.
+    ///
+    /// In nonblocking mode, [`send_to`](Self::send_to) and
+    /// [`recv_from`](Self::recv_from) return
+    /// [`Err(WouldBlock)`](NetError::WouldBlock) instead of waiting.
+    #[inline]
+    pub fn set_nonblocking(&self, nonblocking: bool) {
+        self.nonblock.store(nonblocking, Ordering::Release);
+    }
+
+    /// Returns whether `IP_HDRINCL` is set on this socket.
+    #[inline]
+    pub fn header_included(&self) -> bool {
+        self.header_included.load(Ordering::Acquire)
+    }
+
+    /// Sets `IP_HDRINCL`.
+    ///
+    /// With it, the buffers passed to [`send_to`](Self::send_to) start with
+    /// the IP header, which is sent as given, options included. Like Linux,
+    /// netcore only fills in the total length and checksum, an identification
+    /// of 0, and an unspecified source with the local address. It is set on
+    /// `IPPROTO_RAW` sockets, which send packets of any protocol.
+    #[inline]
+    pub fn set_header_included(&self, included: bool) {
+        self.header_included.store(included, Ordering::Release);
+    }
+
+    /// Returns the TTL of the packets sent without `IP_HDRINCL` (`IP_TTL`).
+    #[inline]
+    pub fn ttl(&self) -> u8 {
+        self.ttl.load(Ordering::Acquire)
+    }
+
+    /// Sets the TTL of the packets sent without `IP_HDRINCL` (`IP_TTL`).
+    #[inline]
+    pub fn set_ttl(&self, ttl: u8) {
+        self.ttl.store(ttl, Ordering::Release);
+    }
+
+    /// Binds the socket to a local address.
+    ///
+    /// Afterwards, it only receives packets sent to `local_addr`, and uses it
+    /// as the source of the packets it sends.
+    pub fn bind(&self, local_addr: IpAddr) -> NetResult<()> {
+        let local_addr = from_core_ipaddr(local_addr);
+        let mut self_local_addr = self.local_addr.lock();
+        *self_local_addr = (!is_unspecified(local_addr)).then_some(local_addr);
+        RAW_TABLE.bind_addr(self.id, *self_local_addr);
+        info!("raw socket {}: bound on {}", self.id, local_addr);
+        Ok(())
+    }
+
+    /// Attaches the classic BPF program `insns`, which every received packet
+    /// must pass before it is queued (`SO_ATTACH_FILTER`).
+    ///
+    /// The program sees the IP packet, header included, and returns how many
+    /// bytes of it to keep, 0 drops it. Returns
+    /// [`Err(InvalidInput)`](NetError::InvalidInput) if the program is
+    /// rejected by the verifier.
+    pub fn attach_filter(&self, insns: &[SockFilter]) -> NetResult<()> {
+        let filter = Arc::new(BpfProgram::new(insns)?);
+        RAW_TABLE.set_filter(self.id, Some(filter));
+        Ok(())
+    }
+
+    /// Removes the attached filter (`SO_DETACH_FILTER`).
+    ///
+    /// Returns [`Err(NotFound)`](NetError::NotFound) if there is none.
+    pub fn detach_filter(&self) -> NetResult<()> {
+        match RAW_TABLE.set_filter(self.id, None) {
+            Some(_) => Ok(()),
+            None => Err(NetError::NotFound),
+        }
+    }
+
+    /// Sends a packet to `dst`. On success, returns the number of bytes
+    /// written.
+    ///
+    /// `buf` is the payload, or with [`IP_HDRINCL`](Self::set_header_included)
+    /// the whole IP packet, whose destination takes precedence over `dst`.
+    pub fn send_to(&self, buf: &[u8], dst: IpAddr) -> NetResult<usize> {
+        let dst = from_core_ipaddr(dst);
+        let header_included = self.header_included();
+        if !header_included && is_unspecified(dst) {
+            warn!("socket send_to() failed: invalid address");
+            return Err(NetError::InvalidInput);
+        }
+        let IpAddress::Ipv4(dst) = dst;
+        let mut packet = if header_included {
+            self.check_header(buf)?;
+            buf.to_vec()
+        } else {
+            let repr = Ipv4Repr {
+                src_addr: Ipv4Address::UNSPECIFIED,
+                dst_addr: dst,
+                next_header: self.protocol,
+                payload_len: buf.len(),
+                hop_limit: self.ttl(),
+            };
+            let mut packet = vec![0; repr.buffer_len() + buf.len()];
+            let mut ipv4_packet = Ipv4Packet::new_unchecked(&mut packet);
+            repr.emit(&mut ipv4_packet, &ChecksumCapabilities::ignored());
+            // like Linux, a packet too large for the path is fragmented.
+            ipv4_packet.set_dont_frag(false);
+            ipv4_packet.payload_mut().copy_from_slice(buf);
+            packet
+        };
+        // the wrapper smoltcp routes adds its own header.
+        let wrapped_len = IPV4_HEADER_LEN + packet.len();
+        if wrapped_len > RAW_TX_BUF_LEN.min(IPV4_MAX_LEN) {
+            warn!("raw socket {}: send() failed: packet too large", self.id);
+            return Err(NetError::InvalidInput);
+        }
+        let packet_len = packet.len();
+        let mut ipv4_packet = Ipv4Packet::new_unchecked(&mut packet);
+        let src_addr = self.source_addr(ipv4_packet.src_addr())?;
+        ipv4_packet.set_src_addr(src_addr);
+        ipv4_packet.set_total_len(packet_len as u16);
+        if ipv4_packet.ident() == 0 {
+            ipv4_packet.set_ident(NEXT_FRAG_IDENT.fetch_add(1, Ordering::Relaxed));
+        }
+        ipv4_packet.fill_checksum();
+        let wrapper = Ipv4Repr {
+            src_addr,
+            dst_addr: ipv4_packet.dst_addr(),
+            next_header: IpProtocol::from(IPPROTO_RAW),
+            payload_len: packet_len,
+            hop_limit: DEFAULT_TTL,
+        };
+
+        let iface = NET_INTERFACE.get().unwrap();
+        block_on(self.is_nonblocking(), || {
+            iface.send_raw_packet(self.sender, wrapped_len, &mut |tx_buf| {
+                let mut wrapper_packet = Ipv4Packet::new_unchecked(tx_buf);
+                wrapper.emit(&mut wrapper_packet, &ChecksumCapabilities::default());
+                wrapper_packet.payload_mut().copy_from_slice(&packet);
+            })?;
+            Ok(buf.len())
+        })
+    }
+
+    /// Receives a single IP packet, header included. On success, returns
+    /// the number of bytes read and the origin.
+    pub fn recv_from(&self, buf: &mut [u8]) -> NetResult<(usize, IpAddr)> {
+        block_on(self.is_nonblocking(), || {
+            RAW_TABLE
+                .recv(self.id, |data| {
+                    let packet = Ipv4Packet::new_unchecked(data);
+                    let src = into_core_ipaddr(packet.src_addr().into());
+                    (copy_truncated(buf, data), src)
+                })
+                .ok_or(NetError::WouldBlock)
+        })
+    }
+
+    /// Whether the socket is readable or writable.
+    pub fn poll(&self) -> NetResult<NetPollState> {
+        let iface = NET_INTERFACE.get().unwrap();
+        Ok(NetPollState {
+            readable: RAW_TABLE.has_packets(self.id),
+            writable: iface.raw_sender_can_send(self.sender),
+            ..Default::default()
+        })
+    }
+}
+
+/// Private methods
+impl RawSocket {
+    /// Checks that `buf` starts with an IPv4 header the socket may send.
+    ///
+    /// Only `IPPROTO_RAW` sockets send any protocol, the others send their own.
+    fn check_header(&self, buf: &[u8]) -> NetResult<()> {
+        let packet = Ipv4Packet::new_unchecked(buf);
+        if buf.len() < IPV4_HEADER_LEN
+            || packet.version() != 4
+            || !(IPV4_HEADER_LEN..=buf.len()).contains(&(packet.header_len() as usize))
+        {
+            warn!("raw socket {}: send() failed: bad IP header", self.id);
+            return Err(NetError::InvalidInput);
+        }
+        if self.protocol != IpProtocol::from(IPPROTO_RAW) && packet.next_header() != self.protocol {
+            warn!("raw socket {}: send() failed: wrong protocol", self.id);
+            return Err(NetError::InvalidInput);
+        }
+        Ok(())
+    }
+
+    /// The source address of a packet: the given one if specified, else the
+    /// bound address, else the address of the interface.
+    fn source_addr(&self, src_addr: Ipv4Address) -> NetResult<Ipv4Address> {
+        if !src_addr.is_unspecified() {
+            return Ok(src_addr);
+        }
+        if let Some(IpAddress::Ipv4(addr)) = *self.local_addr.lock() {
+            return Ok(addr);
+        }
+        let iface = NET_INTERFACE.get().unwrap().raw_interface();
+        let addr = iface.lock().ipv4_addr();
+        addr.ok_or_else(|| {
+            warn!("raw socket {}: send() failed: no source address", self.id);
+            NetError::Unaddressable
+        })
+    }
+}
+
+impl NetPollable for RawSocket {
+    fn poll(&self) -> NetResult<NetPollState> {
+        RawSocket::poll(self)
+    }
+
+    fn register_waker(&self, waker: &Waker) {
+        let key = WakeKey::Raw(self.id);
+        // smoltcp uses a waker up when it wakes it, so register it every time.
+        let (_, send) = SOCKET_EVENTS.key_wakers(key);
+        NET_INTERFACE
+            .get()
+            .unwrap()
+            .register_raw_sender_waker(self.sender, &send);
+        SOCKET_EVENTS.subscribe(key, waker);
+    }
+}
+
+impl Drop for RawSocket {
+    fn drop(&mut self) {
+        RAW_TABLE.unbind(self.id);
+        NET_INTERFACE.get().unwrap().remove_raw_sender(self.sender);
+        SOCKET_EVENTS.remove(WakeKey::Raw(self.id));
+    }
+}
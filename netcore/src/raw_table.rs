@@ -0,0 +1,158 @@
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use log::warn;
+use smoltcp::wire::{IpAddress, IpProtocol, Ipv4Packet};
+
+use crate::bpf::BpfProgram;
+use crate::common::RAW_RX_BUF_LEN;
+use crate::event::WakeKey;
+use crate::SOCKET_EVENTS;
+use kernel_sync::TicketMutex as Mutex;
+
+/// Identifies a raw socket inside the [`RawTable`].
+///
+/// Raw sockets are not in `SOCKET_SET`, so they have no socket handle.
+pub type RawId = usize;
+
+struct RawBinding {
+    protocol: IpProtocol,
+    /// The address the socket is bound to, the only destination it accepts.
+    local_addr: Option<IpAddress>,
+    filter: Option<Arc<BpfProgram>>,
+    queue: VecDeque<Vec<u8>>,
+    queue_bytes: usize,
+}
+
+/// The raw sockets, which get a copy of the IP packets of their protocol
+/// before smoltcp processes them.
+///
+/// smoltcp would queue every packet on every raw socket of the protocol, so
+/// netcore queues them itself, after the filter of each socket.
+pub struct RawTable {
+    sockets: Mutex<BTreeMap<RawId, RawBinding>>,
+    next_id: AtomicUsize,
+}
+
+impl Default for RawTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RawTable {
+    pub const fn new() -> Self {
+        Self {
+            sockets: Mutex::new(BTreeMap::new()),
+            next_id: AtomicUsize::new(0),
+        }
+    }
+
+    /// Allocate an id for a new raw socket.
+    pub fn alloc_id(&self) -> RawId {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Start delivering the packets of `protocol` to the socket `id`.
+    pub fn bind(&self, id: RawId, protocol: IpProtocol) {
+        self.sockets.lock().insert(
+            id,
+            RawBinding {
+                protocol,
+                local_addr: None,
+                filter: None,
+                queue: VecDeque::new(),
+                queue_bytes: 0,
+            },
+        );
+    }
+
+    /// Only deliver the packets sent to `local_addr` to the socket `id`, or
+    /// every packet with `None`.
+    pub fn bind_addr(&self, id: RawId, local_addr: Option<IpAddress>) {
+        if let Some(binding) = self.sockets.lock().get_mut(&id) {
+            binding.local_addr = local_addr;
+        }
+    }
+
+    /// Replace the filter of the socket `id`, returning the previous one.
+    pub fn set_filter(
+        &self,
+        id: RawId,
+        filter: Option<Arc<BpfProgram>>,
+    ) -> Option<Arc<BpfProgram>> {
+        let mut sockets = self.sockets.lock();
+        let binding = sockets.get_mut(&id)?;
+        core::mem::replace(&mut binding.filter, filter)
+    }
+
+    /// Stop delivering packets to the socket `id`, dropping the queued ones.
+    pub fn unbind(&self, id: RawId) {
+        self.sockets.lock().remove(&id);
+    }
+
+    /// Whether packets are waiting for the socket `id`.
+    pub fn has_packets(&self, id: RawId) -> bool {
+        self.sockets
+            .lock()
+            .get(&id)
+            .is_some_and(|binding| !binding.queue.is_empty())
+    }
+
+    /// Take the oldest packet of the socket `id`.
+    pub fn recv<F, T>(&self, id: RawId, f: F) -> Option<T>
+    where
+        F: FnOnce(&[u8]) -> T,
+    {
+        let mut sockets = self.sockets.lock();
+        let binding = sockets.get_mut(&id)?;
+        let packet = binding.queue.pop_front()?;
+        binding.queue_bytes -= packet.len();
+        Some(f(&packet))
+    }
+
+    /// Deliver a copy of a received IPv4 packet to the raw sockets bound to
+    /// its protocol.
+    ///
+    /// Fragments are not delivered: the sockets get the datagram once
+    /// netcore reassembled it.
+    pub fn incoming_packet(&self, packet: &[u8]) {
+        let mut sockets = self.sockets.lock();
+        if sockets.is_empty() {
+            return;
+        }
+        let Ok(ipv4_packet) = Ipv4Packet::new_checked(packet) else {
+            return;
+        };
+        if ipv4_packet.more_frags() || ipv4_packet.frag_offset() != 0 {
+            return;
+        }
+        // the frame may be padded past the end of the packet.
+        let packet = &packet[..usize::from(ipv4_packet.total_len())];
+        let dst_addr = IpAddress::Ipv4(ipv4_packet.dst_addr());
+        for (&id, binding) in sockets.iter_mut() {
+            if binding.protocol != ipv4_packet.next_header()
+                || binding.local_addr.is_some_and(|addr| addr != dst_addr)
+            {
+                continue;
+            }
+            // the filter may also cut the packet short.
+            let len = match &binding.filter {
+                Some(filter) => (filter.run(packet) as usize).min(packet.len()),
+                None => packet.len(),
+            };
+            if len == 0 {
+                continue;
+            }
+            if binding.queue_bytes + len > RAW_RX_BUF_LEN {
+                warn!("raw socket {}: queue full, dropped", id);
+                continue;
+            }
+            binding.queue_bytes += len;
+            binding.queue.push_back(packet[..len].to_vec());
+            SOCKET_EVENTS.notify_readable(WakeKey::Raw(id));
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use log::{info, warn};
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{Ipv4Address, Ipv4Packet};
+
+use crate::common::{IPV4_REASSEMBLY_TIMEOUT_SECS, MAX_REASSEMBLY_BYTES, MAX_REASSEMBLY_DATAGRAMS};
+use kernel_sync::TicketMutex as Mutex;
+
+/// The largest IPv4 packet, as its total length is 16 bits.
+const IPV4_MAX_LEN: usize = 65535;
+
+/// The gaps a datagram may have between its fragments, like smoltcp's
+/// assembler segments.
+const MAX_FRAGMENT_RANGES: usize = 8;
+
+/// The fields that tell the fragments of a datagram from the others': the
+/// source, destination, protocol and identification (RFC 791).
+type DatagramKey = (Ipv4Address, Ipv4Address, u8, u16);
+
+/// A datagram whose fragments are arriving.
+struct PartialDatagram {
+    /// The IP header of the first fragment, once it arrived.
+    header: Option<Vec<u8>>,
+    payload: Vec<u8>,
+    /// The ranges of the payload received so far, sorted and merged.
+    ranges: Vec<(usize, usize)>,
+    /// The length of the payload, once the last fragment arrived.
+    len: Option<usize>,
+    expires_at: Instant,
+}
+
+impl PartialDatagram {
+    fn add_range(&mut self, start: usize, end: usize) {
+        self.ranges.push((start, end));
+        self.ranges.sort_unstable();
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(self.ranges.len());
+        for &(start, end) in &self.ranges {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        self.ranges = merged;
+    }
+
+    fn is_complete(&self) -> bool {
+        self.header.is_some() && self.len.is_some_and(|len| self.ranges == [(0, len)])
+    }
+}
+
+struct Datagrams {
+    map: BTreeMap<DatagramKey, PartialDatagram>,
+    /// The bytes of payload held by `map`.
+    bytes: usize,
+}
+
+impl Datagrams {
+    fn remove(&mut self, key: &DatagramKey) -> Option<PartialDatagram> {
+        let datagram = self.map.remove(key)?;
+        self.bytes -= datagram.payload.len();
+        Some(datagram)
+    }
+
+    /// Forget the oldest datagram but `keep`, returning whether there was one.
+    fn evict_oldest(&mut self, keep: &DatagramKey) -> bool {
+        let oldest = self
+            .map
+            .iter()
+            .filter(|(key, _)| *key != keep)
+            .min_by_key(|(_, datagram)| datagram.expires_at)
+            .map(|(&key, _)| key);
+        match oldest {
+            Some(oldest) => {
+                warn!("IPv4 reassembly full, dropped {:?}", oldest);
+                self.remove(&oldest);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// The IPv4 datagrams being reassembled from their fragments.
+///
+/// netcore reassembles them itself, so that smoltcp only sees whole
+/// datagrams and needs no reassembly buffers of its own. The buffers are on
+/// the heap, as large as the fragments received so far, and
+/// [`MAX_REASSEMBLY_BYTES`] and [`MAX_REASSEMBLY_DATAGRAMS`] bound them all:
+/// when full, the oldest datagram goes first, so that a peer that never sends
+/// its last fragments can't keep the others from being reassembled.
+pub struct ReassemblyTable {
+    datagrams: Mutex<Datagrams>,
+}
+
+impl Default for ReassemblyTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReassemblyTable {
+    pub const fn new() -> Self {
+        Self {
+            datagrams: Mutex::new(Datagrams {
+                map: BTreeMap::new(),
+                bytes: 0,
+            }),
+        }
+    }
+
+    /// Add a received fragment, returning the whole IPv4 packet once every
+    /// fragment of its datagram arrived.
+    ///
+    /// A malformed fragment, or one that contradicts the others, drops the
+    /// datagram.
+    pub fn incoming_fragment(&self, packet: &[u8], now: Instant) -> Option<Vec<u8>> {
+        let ipv4_packet = Ipv4Packet::new_checked(packet).ok()?;
+        let header_len = ipv4_packet.header_len() as usize;
+        let payload = &packet[header_len..usize::from(ipv4_packet.total_len())];
+        let more_frags = ipv4_packet.more_frags();
+        let start = usize::from(ipv4_packet.frag_offset());
+        let end = start + payload.len();
+        let key = (
+            ipv4_packet.src_addr(),
+            ipv4_packet.dst_addr(),
+            u8::from(ipv4_packet.next_header()),
+            ipv4_packet.ident(),
+        );
+
+        let mut datagrams = self.datagrams.lock();
+        let expired: Vec<_> = datagrams
+            .map
+            .iter()
+            .filter(|(_, datagram)| datagram.expires_at <= now)
+            .map(|(&key, _)| key)
+            .collect();
+        for key in expired {
+            info!("IPv4 reassembly of {:?} timed out", key);
+            datagrams.remove(&key);
+        }
+        // all but the last fragment carry multiples of 8 bytes.
+        let misaligned = more_frags && (payload.is_empty() || payload.len() & 7 != 0);
+        if end > IPV4_MAX_LEN - header_len || misaligned {
+            warn!("dropped a malformed IPv4 fragment of {:?}", key);
+            datagrams.remove(&key);
+            return None;
+        }
+        if !datagrams.map.contains_key(&key) && datagrams.map.len() >= MAX_REASSEMBLY_DATAGRAMS {
+            datagrams.evict_oldest(&key);
+        }
+        let datagram = datagrams.map.entry(key).or_insert_with(|| PartialDatagram {
+            header: None,
+            payload: Vec::new(),
+            ranges: Vec::new(),
+            len: None,
+            expires_at: now + Duration::from_secs(IPV4_REASSEMBLY_TIMEOUT_SECS),
+        });
+        let beyond_end = datagram.len.is_some_and(|len| end > len);
+        let wrong_end = !more_frags && datagram.len.is_some_and(|len| len != end);
+        let before_end = !more_frags && datagram.ranges.last().is_some_and(|last| last.1 > end);
+        if beyond_end || wrong_end || before_end {
+            warn!("dropped IPv4 datagram {:?} with overlapping fragments", key);
+            datagrams.remove(&key);
+            return None;
+        }
+        let grow = end.saturating_sub(datagram.payload.len());
+        while datagrams.bytes + grow > MAX_REASSEMBLY_BYTES {
+            if !datagrams.evict_oldest(&key) {
+                warn!("IPv4 datagram {:?} too large to reassemble", key);
+                datagrams.remove(&key);
+                return None;
+            }
+        }
+        datagrams.bytes += grow;
+        let datagram = datagrams.map.get_mut(&key).unwrap();
+        if datagram.payload.len() < end {
+            datagram.payload.resize(end, 0);
+        }
+        datagram.payload[start..end].copy_from_slice(payload);
+        datagram.add_range(start, end);
+        if start == 0 {
+            datagram.header = Some(packet[..header_len].to_vec());
+        }
+        if !more_frags {
+            datagram.len = Some(end);
+        }
+        if datagram.ranges.len() > MAX_FRAGMENT_RANGES {
+            warn!("dropped IPv4 datagram {:?} with too many gaps", key);
+            datagrams.remove(&key);
+            return None;
+        }
+        if !datagram.is_complete() {
+            return None;
+        }
+
+        let datagram = datagrams.remove(&key).unwrap();
+        drop(datagrams);
+        let mut packet = datagram.header.unwrap();
+        let total_len = packet.len() + datagram.payload.len();
+        if total_len > IPV4_MAX_LEN {
+            warn!("dropped IPv4 datagram {:?} longer than 64 KiB", key);
+            return None;
+        }
+        packet.extend_from_slice(&datagram.payload);
+        let mut ipv4_packet = Ipv4Packet::new_unchecked(&mut packet);
+        ipv4_packet.set_total_len(total_len as u16);
+        ipv4_packet.set_more_frags(false);
+        ipv4_packet.set_frag_offset(0);
+        ipv4_packet.fill_checksum();
+        info!("IPv4 datagram {:?} reassembled, {} bytes", key, total_len);
+        Some(packet)
+    }
+}
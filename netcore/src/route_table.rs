@@ -0,0 +1,122 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use log::info;
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{IpAddress, IpCidr};
+
+use crate::common::{MAX_PMTU_ENTRIES, MIN_PMTU, PMTU_EXPIRY_SECS};
+use kernel_sync::TicketMutex as Mutex;
+
+/// A path MTU learned from an ICMP "fragmentation needed" message.
+#[derive(Debug, Clone, Copy)]
+struct PmtuEntry {
+    mtu: usize,
+    expires_at: Instant,
+}
+
+/// The MTU overrides of routes, and the path MTUs learned from ICMP.
+///
+/// smoltcp knows neither, so netcore clamps the MSS of TCP handshakes and
+/// splits the packets that exceed the MTU of their path itself: TCP segments
+/// into smaller segments, the rest into fragments.
+pub struct RouteTable {
+    /// The MTU overrides of routes, as `(cidr, mtu)`.
+    mtus: Mutex<Vec<(IpCidr, usize)>>,
+    /// The path MTU cache, by destination.
+    pmtus: Mutex<BTreeMap<IpAddress, PmtuEntry>>,
+}
+
+impl Default for RouteTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RouteTable {
+    pub const fn new() -> Self {
+        Self {
+            mtus: Mutex::new(Vec::new()),
+            pmtus: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Set the MTU of the route to `cidr`, or remove its override if `mtu`
+    /// is `None`.
+    ///
+    /// Returns whether the route had an override before.
+    pub fn set_route_mtu(&self, cidr: IpCidr, mtu: Option<usize>) -> bool {
+        let mut mtus = self.mtus.lock();
+        let old = mtus.iter().position(|&(route, _)| route == cidr);
+        match (old, mtu) {
+            (Some(i), Some(mtu)) => mtus[i].1 = mtu,
+            (Some(i), None) => {
+                mtus.remove(i);
+            }
+            (None, Some(mtu)) => mtus.push((cidr, mtu)),
+            (None, None) => {}
+        }
+        old.is_some()
+    }
+
+    /// The MTU of the path to `dst`, if a route overrides it or ICMP lowered
+    /// it. Otherwise, it's the MTU of the device.
+    ///
+    /// The most specific route wins, and a learned path MTU only lowers it.
+    pub fn path_mtu(&self, dst: IpAddress, now: Instant) -> Option<usize> {
+        let route_mtu = self
+            .mtus
+            .lock()
+            .iter()
+            .filter(|(cidr, _)| cidr.contains_addr(&dst))
+            .max_by_key(|(cidr, _)| cidr.prefix_len())
+            .map(|&(_, mtu)| mtu);
+        let mut pmtus = self.pmtus.lock();
+        let pmtu = match pmtus.get(&dst) {
+            Some(entry) if entry.expires_at <= now => {
+                info!("path MTU to {} expired", dst);
+                pmtus.remove(&dst);
+                None
+            }
+            Some(entry) => Some(entry.mtu),
+            None => None,
+        };
+        match (route_mtu, pmtu) {
+            (Some(route_mtu), Some(pmtu)) => Some(route_mtu.min(pmtu)),
+            (route_mtu, pmtu) => route_mtu.or(pmtu),
+        }
+    }
+
+    /// Lower the path MTU to `dst` to `mtu`, as an ICMP "fragmentation
+    /// needed" message asks. It is forgotten after [`PMTU_EXPIRY_SECS`].
+    ///
+    /// `mtu` is raised to [`MIN_PMTU`], so that forged messages can't make
+    /// the packets arbitrarily small.
+    pub fn update_pmtu(&self, dst: IpAddress, mtu: usize, now: Instant) {
+        let mtu = mtu.max(MIN_PMTU);
+        let expires_at = now + Duration::from_secs(PMTU_EXPIRY_SECS);
+        let mut pmtus = self.pmtus.lock();
+        if let Some(entry) = pmtus.get_mut(&dst) {
+            if entry.expires_at > now && entry.mtu <= mtu {
+                return;
+            }
+            *entry = PmtuEntry { mtu, expires_at };
+        } else {
+            if pmtus.len() >= MAX_PMTU_ENTRIES {
+                pmtus.retain(|_, entry| entry.expires_at > now);
+            }
+            if pmtus.len() >= MAX_PMTU_ENTRIES {
+                // forget the one learned first.
+                let oldest = pmtus
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.expires_at)
+                    .map(|(&addr, _)| addr);
+                if let Some(oldest) = oldest {
+                    pmtus.remove(&oldest);
+                }
+            }
+            pmtus.insert(dst, PmtuEntry { mtu, expires_at });
+        }
+        info!("path MTU to {} lowered to {}", dst, mtu);
+    }
+}
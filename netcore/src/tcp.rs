@@ -1,14 +1,21 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::cell::UnsafeCell;
 use core::net::SocketAddr;
 use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use core::task::Waker;
 
 use log::{info, warn};
 use smoltcp::iface::SocketHandle;
 use smoltcp::socket::tcp::{self, ConnectError, State};
 use smoltcp::wire::{IpEndpoint, IpListenEndpoint};
 
-use crate::common::{NetError, NetPollState, NetResult};
-use crate::{KERNEL_NET_FUNC, LISTENING_TABLE, NET_INTERFACE};
+use crate::common::{block_on, NetError, NetPollState, NetResult};
+use crate::event::{SocketKind, SocketUpcall, WakeKey};
+use crate::listen_table::ListenId;
+use crate::poller::NetPollable;
+use crate::port::alloc_ephemeral_port;
+use crate::{CONNECT_TABLE, LISTENING_TABLE, MSS_TABLE, NET_INTERFACE, SOCKET_EVENTS};
 
 use super::addr::{from_core_sockaddr, into_core_sockaddr, is_unspecified, UNSPECIFIED_ENDPOINT};
 use super::{SocketSetWrapper, SOCKET_SET};
@@ -42,7 +49,13 @@ pub struct TcpSocket {
     handle: UnsafeCell<Option<SocketHandle>>,
     local_addr: UnsafeCell<IpEndpoint>,
     peer_addr: UnsafeCell<IpEndpoint>,
+    listen_id: UnsafeCell<ListenId>,
     nonblock: AtomicBool,
+    reuse_addr: AtomicBool,
+    reuse_port: AtomicBool,
+    pending_error: Mutex<Option<NetError>>,
+    /// Wakers registered before `connect` or `listen`, woken by them.
+    idle_wakers: Mutex<Vec<Waker>>,
 }
 
 unsafe impl Sync for TcpSocket {}
@@ -55,7 +68,12 @@ impl TcpSocket {
             handle: UnsafeCell::new(None),
             local_addr: UnsafeCell::new(UNSPECIFIED_ENDPOINT),
             peer_addr: UnsafeCell::new(UNSPECIFIED_ENDPOINT),
+            listen_id: UnsafeCell::new(0),
             nonblock: AtomicBool::new(false),
+            reuse_addr: AtomicBool::new(false),
+            reuse_port: AtomicBool::new(false),
+            pending_error: Mutex::new(None),
+            idle_wakers: Mutex::new(Vec::new()),
         }
     }
 
@@ -70,7 +88,12 @@ impl TcpSocket {
             handle: UnsafeCell::new(Some(handle)),
             local_addr: UnsafeCell::new(local_addr),
             peer_addr: UnsafeCell::new(peer_addr),
+            listen_id: UnsafeCell::new(0),
             nonblock: AtomicBool::new(false),
+            reuse_addr: AtomicBool::new(false),
+            reuse_port: AtomicBool::new(false),
+            pending_error: Mutex::new(None),
+            idle_wakers: Mutex::new(Vec::new()),
         }
     }
 
@@ -117,6 +140,69 @@ impl TcpSocket {
         self.nonblock.store(nonblocking, Ordering::Release);
     }
 
+    /// Returns whether `SO_REUSEADDR` is set on this socket.
+    #[inline]
+    pub fn reuse_addr(&self) -> bool {
+        self.reuse_addr.load(Ordering::Acquire)
+    }
+
+    /// Sets `SO_REUSEADDR`.
+    ///
+    /// It allows [`bind`](Self::bind) to succeed while old connections on the
+    /// same address and port are still in `TIME_WAIT`. It must be set before
+    /// binding.
+    #[inline]
+    pub fn set_reuse_addr(&self, reuse: bool) {
+        self.reuse_addr.store(reuse, Ordering::Release);
+    }
+
+    /// Returns whether `SO_REUSEPORT` is set on this socket.
+    #[inline]
+    pub fn reuse_port(&self) -> bool {
+        self.reuse_port.load(Ordering::Acquire)
+    }
+
+    /// Sets `SO_REUSEPORT`.
+    ///
+    /// Several listening sockets with this option bound to the same address
+    /// and port share the incoming connections between them. It must be set
+    /// before binding.
+    #[inline]
+    pub fn set_reuse_port(&self, reuse: bool) {
+        self.reuse_port.store(reuse, Ordering::Release);
+    }
+
+    /// Takes the pending error of the socket, like `SO_ERROR`.
+    ///
+    /// A failed nonblocking connect or a connection reset by the peer leaves
+    /// an error here, which is reported once.
+    pub fn take_error(&self) -> Option<NetError> {
+        self.pending_error.lock().take()
+    }
+
+    /// Attaches callbacks run when the socket becomes readable or writable,
+    /// or detaches them with `None`.
+    ///
+    /// For a listening socket, [`data_ready`](SocketUpcall::data_ready) means
+    /// a connection can be accepted. Returns
+    /// [`Err(NotConnected)`](NetError::NotConnected) before
+    /// [`connect`](Self::connect) or [`listen`](Self::listen).
+    pub fn set_upcall(&self, upcall: Option<Arc<dyn SocketUpcall>>) -> NetResult<()> {
+        if self.is_listening() {
+            // SAFETY: `self.listen_id` is initialized in a listening socket.
+            let listen_id = unsafe { self.listen_id.get().read() };
+            SOCKET_EVENTS.set_upcall(WakeKey::Listener(listen_id), upcall);
+            SOCKET_EVENTS.arm_listener(listen_id);
+        } else if let Some(handle) = unsafe { self.handle.get().read() } {
+            SOCKET_EVENTS.set_upcall(WakeKey::Socket(handle), upcall);
+            SOCKET_EVENTS.arm(handle, SocketKind::Tcp);
+        } else {
+            warn!("socket set_upcall() failed: not connected");
+            return Err(NetError::NotConnected);
+        }
+        Ok(())
+    }
+
     /// Connects to the given address and port.
     ///
     /// The local port is generated automatically.
@@ -130,7 +216,7 @@ impl TcpSocket {
 
             // TODO: check remote addr unreachable
             let remote_endpoint = from_core_sockaddr(remote_addr);
-            let bound_endpoint = self.bound_endpoint()?;
+            let bound_endpoint = self.bound_endpoint(Some(remote_endpoint))?;
             let iface = NET_INTERFACE.get().unwrap().raw_interface();
             let (local_endpoint, remote_endpoint) = SOCKET_SET
                 .with_socket_mut::<tcp::Socket, _, _>(handle, |socket| {
@@ -164,12 +250,13 @@ impl TcpSocket {
             warn!("socket connect() failed: already connected,state :{}", e);
             Err(NetError::AlreadyExists)
         })?; // EISCONN
+        self.wake_idle_wakers();
 
         // Here our state must be `CONNECTING`, and only one thread can run here.
         if self.is_nonblocking() {
             Err(NetError::WouldBlock)
         } else {
-            self.block_on(|| {
+            block_on(self.is_nonblocking(), || {
                 let NetPollState { writable, .. } = self.poll_connect()?;
                 if !writable {
                     Err(NetError::WouldBlock)
@@ -177,7 +264,7 @@ impl TcpSocket {
                     Ok(())
                 } else {
                     warn!("socket connect() failed: connection refused");
-                    Err(NetError::ConnectionRefused)
+                    Err(self.take_error().unwrap_or(NetError::ConnectionRefused))
                 }
             })
         }
@@ -191,9 +278,10 @@ impl TcpSocket {
     /// [`accept`](Self::accept).
     pub fn bind(&self, mut local_addr: SocketAddr) -> NetResult<()> {
         self.update_state(STATE_CLOSED, STATE_CLOSED, || {
-            // TODO: check addr is available
             if local_addr.port() == 0 {
-                local_addr.set_port(get_ephemeral_port()?);
+                local_addr.set_port(get_ephemeral_port(None)?);
+            } else {
+                self.check_bind(from_core_sockaddr(local_addr))?;
             }
             // SAFETY: no other threads can read or write `self.local_addr` as we
             // have changed the state to `BUSY`.
@@ -219,15 +307,18 @@ impl TcpSocket {
     /// [`accept`](Self::accept).
     pub fn listen(&self) -> NetResult<()> {
         self.update_state(STATE_CLOSED, STATE_LISTENING, || {
-            let bound_endpoint = self.bound_endpoint()?;
+            let bound_endpoint = self.bound_endpoint(None)?;
             unsafe {
                 (*self.local_addr.get()).port = bound_endpoint.port;
             }
-            LISTENING_TABLE.listen(bound_endpoint)?;
+            let listen_id = LISTENING_TABLE.listen(bound_endpoint, self.reuse_port())?;
+            unsafe { self.listen_id.get().write(listen_id) };
             info!("TCP socket listening on {}", bound_endpoint);
             Ok(())
         })
-        .unwrap_or(Ok(())) // ignore simultaneous `listen`s.
+        .unwrap_or(Ok(()))?; // ignore simultaneous `listen`s.
+        self.wake_idle_wakers();
+        Ok(())
     }
 
     /// Accepts a new connection.
@@ -242,11 +333,16 @@ impl TcpSocket {
             return Err(NetError::InvalidInput);
         }
 
-        // SAFETY: `self.local_addr` should be initialized after `bind()`.
+        // SAFETY: `self.local_addr` and `self.listen_id` should be initialized after `listen()`.
         let local_port = unsafe { self.local_addr.get().read().port };
-        self.block_on(|| {
-            let (handle, (local_addr, peer_addr)) = LISTENING_TABLE.accept(local_port)?;
+        let listen_id = unsafe { self.listen_id.get().read() };
+        block_on(self.is_nonblocking(), || {
+            let (handle, (local_addr, peer_addr)) =
+                LISTENING_TABLE.accept(local_port, listen_id)?;
             warn!("TCP socket accepted a new connection {}", peer_addr);
+            // it still notifies the listener, which would wake its acceptors
+            // for every segment of the new connection.
+            SOCKET_EVENTS.arm(handle, SocketKind::Tcp);
             Ok(TcpSocket::new_connected(handle, local_addr, peer_addr))
         })
     }
@@ -262,6 +358,9 @@ impl TcpSocket {
                 info!("TCP socket {}: shutting down", handle);
                 socket.close();
             });
+            let (local_addr, peer_addr) =
+                unsafe { (self.local_addr.get().read(), self.peer_addr.get().read()) };
+            MSS_TABLE.remove(local_addr, peer_addr);
             unsafe { self.local_addr.get().write(UNSPECIFIED_ENDPOINT) }; // clear bound address
             SOCKET_SET.poll_interfaces();
             Ok(())
@@ -273,8 +372,9 @@ impl TcpSocket {
             // SAFETY: `self.local_addr` should be initialized in a listening socket,
             // and no other threads can read or write it.
             let local_port = unsafe { self.local_addr.get().read().port };
+            let listen_id = unsafe { self.listen_id.get().read() };
             unsafe { self.local_addr.get().write(UNSPECIFIED_ENDPOINT) }; // clear bound address
-            LISTENING_TABLE.unlisten(local_port);
+            LISTENING_TABLE.unlisten(local_port, listen_id);
             SOCKET_SET.poll_interfaces();
             Ok(())
         })
@@ -288,35 +388,43 @@ impl TcpSocket {
     pub fn recv(&self, buf: &mut [u8]) -> NetResult<usize> {
         if self.is_connecting() {
             return Err(NetError::WouldBlock);
-        } else if !self.is_connected() {
+        }
+        block_on(self.is_nonblocking(), || self.recv_nonblocking(buf))
+    }
+
+    /// Like [`recv`](Self::recv), but never blocks.
+    pub(crate) fn recv_nonblocking(&self, buf: &mut [u8]) -> NetResult<usize> {
+        if self.is_connecting() {
+            return Err(NetError::WouldBlock);
+        }
+        // a reset closes the socket, and its error is reported once below.
+        self.check_reset();
+        if !self.is_connected() {
+            if let Some(err) = self.take_error() {
+                return Err(err);
+            }
             warn!("socket recv() failed: not connected");
             return Err(NetError::NotConnected);
         }
 
         // SAFETY: `self.handle` should be initialized in a connected socket.
         let handle = unsafe { self.handle.get().read().unwrap() };
-        self.block_on(|| {
-            SOCKET_SET.with_socket_mut::<tcp::Socket, _, _>(handle, |socket| {
-                if !socket.is_active() {
-                    // not open
-                    warn!("socket recv() failed: not open");
-                    Err(NetError::NotConnected)
-                } else if !socket.may_recv() {
-                    // connection closed
-                    Ok(0)
-                } else if socket.recv_queue() > 0 {
-                    // data available
-                    // TODO: use socket.recv(|buf| {...})
-                    let len = socket.recv_slice(buf).map_err(|_| {
-                        warn!("socket recv() failed: bad state");
-                        NetError::BadState
-                    })?;
-                    Ok(len)
-                } else {
-                    // no more data
-                    Err(NetError::WouldBlock)
-                }
-            })
+        SOCKET_SET.with_socket_mut::<tcp::Socket, _, _>(handle, |socket| {
+            if !socket.may_recv() {
+                // connection closed
+                Ok(0)
+            } else if socket.recv_queue() > 0 {
+                // data available
+                // TODO: use socket.recv(|buf| {...})
+                let len = socket.recv_slice(buf).map_err(|_| {
+                    warn!("socket recv() failed: bad state");
+                    NetError::BadState
+                })?;
+                Ok(len)
+            } else {
+                // no more data
+                Err(NetError::WouldBlock)
+            }
         })
     }
 
@@ -324,46 +432,56 @@ impl TcpSocket {
     pub fn send(&self, buf: &[u8]) -> NetResult<usize> {
         if self.is_connecting() {
             return Err(NetError::WouldBlock);
-        } else if !self.is_connected() {
+        }
+        block_on(self.is_nonblocking(), || self.send_nonblocking(buf))
+    }
+
+    /// Like [`send`](Self::send), but never blocks.
+    pub(crate) fn send_nonblocking(&self, buf: &[u8]) -> NetResult<usize> {
+        if self.is_connecting() {
+            return Err(NetError::WouldBlock);
+        }
+        self.check_reset();
+        if !self.is_connected() {
+            if let Some(err) = self.take_error() {
+                return Err(err);
+            }
             warn!("socket send() failed: not connected");
             return Err(NetError::NotConnected);
         }
 
         // SAFETY: `self.handle` should be initialized in a connected socket.
         let handle = unsafe { self.handle.get().read().unwrap() };
-        self.block_on(|| {
-            SOCKET_SET.with_socket_mut::<tcp::Socket, _, _>(handle, |socket| {
-                if !socket.is_active() || !socket.may_send() {
-                    // closed by remote
-                    warn!("socket send() failed: connection reset");
-                    Err(NetError::ConnectionReset)
-                } else if socket.can_send() {
-                    // connected, and the tx buffer is not full
-                    // TODO: use socket.send(|buf| {...})
-                    let len = socket.send_slice(buf).map_err(|_| {
-                        warn!("socket recv() failed: bad state");
-                        NetError::BadState
-                    })?;
-                    Ok(len)
-                } else {
-                    // tx buffer is full
-                    Err(NetError::WouldBlock)
-                }
-            })
+        SOCKET_SET.with_socket_mut::<tcp::Socket, _, _>(handle, |socket| {
+            if !socket.is_active() || !socket.may_send() {
+                // closed by remote
+                warn!("socket send() failed: connection reset");
+                Err(NetError::ConnectionReset)
+            } else if socket.can_send() {
+                // connected, and the tx buffer is not full
+                // TODO: use socket.send(|buf| {...})
+                let len = socket.send_slice(buf).map_err(|_| {
+                    warn!("socket recv() failed: bad state");
+                    NetError::BadState
+                })?;
+                Ok(len)
+            } else {
+                // tx buffer is full
+                Err(NetError::WouldBlock)
+            }
         })
     }
 
-    /// Whether the socket is readable or writable.
+    /// Whether the socket is readable or writable, and whether it has an
+    /// error pending or is hung up.
     pub fn poll(&self) -> NetResult<NetPollState> {
         warn!("socket state: {:?}", self.get_state());
         match self.get_state() {
             STATE_CONNECTING => self.poll_connect(),
-            STATE_CONNECTED | STATE_CLOSED => self.poll_stream(),
+            STATE_CONNECTED => self.poll_stream(),
+            STATE_CLOSED => self.poll_closed(),
             STATE_LISTENING => self.poll_listener(),
-            _ => Ok(NetPollState {
-                readable: false,
-                writable: false,
-            }),
+            _ => Ok(NetPollState::default()),
         }
     }
 }
@@ -424,13 +542,13 @@ impl TcpSocket {
         self.get_state() == STATE_LISTENING
     }
 
-    fn bound_endpoint(&self) -> NetResult<IpListenEndpoint> {
+    fn bound_endpoint(&self, remote: Option<IpEndpoint>) -> NetResult<IpListenEndpoint> {
         // SAFETY: no other threads can read or write `self.local_addr`.
         let local_addr = unsafe { self.local_addr.get().read() };
         let port = if local_addr.port != 0 {
             local_addr.port
         } else {
-            get_ephemeral_port()?
+            get_ephemeral_port(remote)?
         };
         assert_ne!(port, 0);
         let addr = if !is_unspecified(local_addr.addr) {
@@ -441,6 +559,21 @@ impl TcpSocket {
         Ok(IpListenEndpoint { addr, port })
     }
 
+    /// Check whether binding to `local` collides with another socket.
+    fn check_bind(&self, local: IpEndpoint) -> NetResult<()> {
+        let endpoint = IpListenEndpoint {
+            addr: (!is_unspecified(local.addr)).then_some(local.addr),
+            port: local.port,
+        };
+        if !LISTENING_TABLE.can_bind(endpoint, self.reuse_port())
+            || SOCKET_SET.tcp_endpoint_in_use(endpoint, !self.reuse_addr())
+        {
+            warn!("socket bind() failed: {} is in use", endpoint);
+            return Err(NetError::AddrInUse);
+        }
+        Ok(())
+    }
+
     fn poll_connect(&self) -> NetResult<NetPollState> {
         // SAFETY: `self.handle` should be initialized above.
         let handle = unsafe { self.handle.get().read().unwrap() };
@@ -448,6 +581,9 @@ impl TcpSocket {
             SOCKET_SET.with_socket::<tcp::Socket, _, _>(handle, |socket| match socket.state() {
                 State::SynSent => false, // wait for connection
                 State::Established => {
+                    let (local_addr, peer_addr) =
+                        unsafe { (self.local_addr.get().read(), self.peer_addr.get().read()) };
+                    CONNECT_TABLE.remove(local_addr, peer_addr);
                     self.set_state(STATE_CONNECTED);
                     // connected
                     warn!(
@@ -458,67 +594,136 @@ impl TcpSocket {
                     true
                 }
                 _ => {
-                    unsafe {
-                        self.local_addr.get().write(UNSPECIFIED_ENDPOINT);
-                        self.peer_addr.get().write(UNSPECIFIED_ENDPOINT);
-                    }
+                    let (local_addr, peer_addr) = unsafe {
+                        (
+                            self.local_addr.get().replace(UNSPECIFIED_ENDPOINT),
+                            self.peer_addr.get().replace(UNSPECIFIED_ENDPOINT),
+                        )
+                    };
+                    // an ICMP error may have aborted the attempt.
+                    let err = CONNECT_TABLE.remove(local_addr, peer_addr);
+                    self.set_error(err.unwrap_or(NetError::ConnectionRefused));
                     self.set_state(STATE_CLOSED); // connection failed
                     true
                 }
             });
         warn!("poll_connect: writable = {}", writable);
+        if self.get_state() == STATE_CLOSED {
+            return self.poll_closed();
+        }
         Ok(NetPollState {
-            readable: false,
             writable,
+            ..Default::default()
         })
     }
 
     fn poll_stream(&self) -> NetResult<NetPollState> {
+        if self.check_reset() {
+            return self.poll_closed();
+        }
         // SAFETY: `self.handle` should be initialized in a connected socket.
         let handle = unsafe { self.handle.get().read().unwrap() };
         SOCKET_SET.with_socket::<tcp::Socket, _, _>(handle, |socket| {
             Ok(NetPollState {
                 readable: !socket.may_recv() || socket.can_recv(),
                 writable: !socket.may_send() || socket.can_send(),
+                error: self.has_error(),
+                hangup: !socket.may_recv() && !socket.may_send(),
+                read_hangup: !socket.may_recv(),
             })
         })
     }
 
+    /// Whether the connection of a connected socket was reset.
+    ///
+    /// The first to see it closes the socket and leaves
+    /// [`ConnectionReset`](NetError::ConnectionReset) pending, so that it is
+    /// reported once, by [`take_error`](Self::take_error) or the next call.
+    fn check_reset(&self) -> bool {
+        if !self.is_connected() {
+            return false;
+        }
+        // SAFETY: `self.handle` should be initialized in a connected socket.
+        let handle = unsafe { self.handle.get().read().unwrap() };
+        let reset = SOCKET_SET.with_socket::<tcp::Socket, _, _>(handle, |socket| {
+            // We haven't sent our FIN yet, so a closed socket means the
+            // connection was aborted.
+            socket.state() == State::Closed
+        });
+        if reset {
+            self.update_state(STATE_CONNECTED, STATE_CLOSED, || {
+                warn!("TCP socket {}: connection reset", handle);
+                self.set_error(NetError::ConnectionReset);
+                Ok(())
+            })
+            .ok();
+        }
+        reset
+    }
+
+    /// A closed socket is always hung up. One that never had a connection
+    /// is still writable like on Linux, so that a nonblocking `connect` can
+    /// be started.
+    fn poll_closed(&self) -> NetResult<NetPollState> {
+        // SAFETY: no other threads can write `self.handle` in the closed state.
+        let had_connection = unsafe { self.handle.get().read() }.is_some();
+        Ok(NetPollState {
+            readable: had_connection,
+            writable: true,
+            error: self.has_error(),
+            hangup: true,
+            read_hangup: had_connection,
+        })
+    }
+
     fn poll_listener(&self) -> NetResult<NetPollState> {
         // SAFETY: `self.local_addr` should be initialized in a listening socket.
         let local_addr = unsafe { self.local_addr.get().read() };
+        let listen_id = unsafe { self.listen_id.get().read() };
         Ok(NetPollState {
-            readable: LISTENING_TABLE.can_accept(local_addr.port)?,
-            writable: false,
+            readable: LISTENING_TABLE.can_accept(local_addr.port, listen_id)?,
+            error: self.has_error(),
+            ..Default::default()
         })
     }
 
-    /// Block the current thread until the given function completes or fails.
-    ///
-    /// If the socket is non-blocking, it calls the function once and returns
-    /// immediately. Otherwise, it may call the function multiple times if it
-    /// returns [`Err(WouldBlock)`](AxError::WouldBlock).
-    fn block_on<F, T>(&self, mut f: F) -> NetResult<T>
-    where
-        F: FnMut() -> NetResult<T>,
-    {
-        if self.is_nonblocking() {
-            f()
+    /// Wake the wakers registered before the socket had anything to wait on.
+    fn wake_idle_wakers(&self) {
+        let wakers = core::mem::take(&mut *self.idle_wakers.lock());
+        wakers.into_iter().for_each(Waker::wake);
+    }
+
+    #[inline]
+    fn has_error(&self) -> bool {
+        self.pending_error.lock().is_some()
+    }
+
+    #[inline]
+    fn set_error(&self, err: NetError) {
+        *self.pending_error.lock() = Some(err);
+    }
+}
+
+impl NetPollable for TcpSocket {
+    fn poll(&self) -> NetResult<NetPollState> {
+        TcpSocket::poll(self)
+    }
+
+    fn register_waker(&self, waker: &Waker) {
+        if self.is_listening() {
+            // SAFETY: `self.listen_id` is initialized in a listening socket.
+            let listen_id = unsafe { self.listen_id.get().read() };
+            SOCKET_EVENTS.subscribe(WakeKey::Listener(listen_id), waker);
+            SOCKET_EVENTS.arm_listener(listen_id);
+        } else if let Some(handle) = unsafe { self.handle.get().read() } {
+            SOCKET_EVENTS.arm(handle, SocketKind::Tcp);
+            SOCKET_EVENTS.subscribe(WakeKey::Socket(handle), waker);
         } else {
-            loop {
-                SOCKET_SET.poll_interfaces();
-                match f() {
-                    Ok(t) => return Ok(t),
-                    Err(NetError::WouldBlock) => {
-                        let kernel_func = KERNEL_NET_FUNC.get().unwrap();
-                        let has_signal = kernel_func.yield_now();
-                        if !has_signal {
-                            continue;
-                        }
-                        return Err(NetError::Interrupted);
-                    }
-                    Err(e) => return Err(e),
-                }
+            // nothing can happen before `connect` or `listen`, which wake it.
+            self.idle_wakers.lock().push(waker.clone());
+            if self.get_state() != STATE_CLOSED {
+                // one of them ran meanwhile, and may have missed it.
+                waker.wake_by_ref();
             }
         }
     }
@@ -526,34 +731,26 @@ impl TcpSocket {
 
 impl Drop for TcpSocket {
     fn drop(&mut self) {
+        // read before `shutdown` clears the local address.
+        let (local_addr, peer_addr) =
+            unsafe { (self.local_addr.get().read(), self.peer_addr.get().read()) };
         self.shutdown().ok();
         // Safe because we have mut reference to `self`.
         if let Some(handle) = unsafe { self.handle.get().read() } {
+            CONNECT_TABLE.remove(local_addr, peer_addr);
+            MSS_TABLE.remove(local_addr, peer_addr);
             SOCKET_SET.remove(handle);
         }
     }
 }
 
-fn get_ephemeral_port() -> NetResult<u16> {
-    const PORT_START: u16 = 0xc000;
-    const PORT_END: u16 = 0xffff;
-    static CURR: Mutex<u16> = Mutex::new(PORT_START);
-
-    let mut curr = CURR.lock();
-    let mut tries = 0;
-    // TODO: more robust
-    while tries <= PORT_END - PORT_START {
-        let port = *curr;
-        if *curr == PORT_END {
-            *curr = PORT_START;
-        } else {
-            *curr += 1;
-        }
-        if LISTENING_TABLE.can_listen(port) {
-            return Ok(port);
-        }
-        tries += 1;
-    }
-    warn!("no avaliable ports!");
-    Err(NetError::AddrInUse)
+/// Picks a local port for a socket that connects to `remote`, or for a
+/// listener if `remote` is `None`.
+///
+/// A port is free if nobody listens on it and it doesn't repeat the 4-tuple of
+/// an existing connection. Without a remote, any connection using the port
+/// makes it busy.
+fn get_ephemeral_port(remote: Option<IpEndpoint>) -> NetResult<u16> {
+    let used = SOCKET_SET.tcp_ports_in_use(remote);
+    alloc_ephemeral_port(|port| !LISTENING_TABLE.can_listen(port) || used.contains(&port))
 }
@@ -1,14 +1,55 @@
-use super::addr::{from_core_sockaddr, into_core_sockaddr, is_unspecified, UNSPECIFIED_ENDPOINT};
+use super::addr::{
+    from_core_ipaddr, from_core_sockaddr, into_core_ipaddr, into_core_sockaddr, is_unspecified,
+    UNSPECIFIED_ENDPOINT,
+};
 use super::{SocketSetWrapper, SOCKET_SET};
-use crate::common::{NetError, NetPollState, NetResult};
-use crate::KERNEL_NET_FUNC;
-use core::net::SocketAddr;
-use core::sync::atomic::{AtomicBool, Ordering};
+use crate::common::{
+    block_on, copy_truncated, NetError, NetPollState, NetResult, IFACE_INDEX, UDP_TX_BUF_LEN,
+};
+use crate::event::{SocketKind, SocketUpcall, WakeKey};
+use crate::interface::NetInterface;
+use crate::poller::NetPollable;
+use crate::udp_table::UdpRecvMeta;
+use crate::{KERNEL_NET_FUNC, NET_INTERFACE, SOCKET_EVENTS, UDP_TABLE};
+use alloc::collections::BTreeSet;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::net::{IpAddr, Ipv4Addr, SocketAddr};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use core::task::Waker;
+use core::time::Duration;
 use kernel_sync::TicketMutex as Mutex;
 use log::{info, warn};
 use smoltcp::iface::SocketHandle;
+use smoltcp::phy::ChecksumCapabilities;
 use smoltcp::socket::udp::{self, BindError, SendError};
-use smoltcp::wire::{IpEndpoint, IpListenEndpoint};
+use smoltcp::wire::{
+    IpAddress, IpEndpoint, IpListenEndpoint, IpProtocol, Ipv4Packet, Ipv4Repr, UdpPacket, UdpRepr,
+};
+
+/// The TTL of unicast datagrams, smoltcp's default.
+const DEFAULT_TTL: u8 = 64;
+
+/// The ancillary data of a datagram received by
+/// [`recv_msg`](UdpSocket::recv_msg), like the control messages of `recvmsg`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UdpRecvInfo {
+    /// Where the datagram came from.
+    pub src: SocketAddr,
+    /// The destination address of the datagram, which tells a socket bound
+    /// to the wildcard address which of its addresses was used
+    /// (`IP_PKTINFO`).
+    pub dst: Option<IpAddr>,
+    /// The index of the receiving interface (`IP_PKTINFO`).
+    pub ifindex: u32,
+    /// The TTL of the datagram (`IP_RECVTTL`).
+    pub ttl: Option<u8>,
+    /// When the datagram was received, on the clock of
+    /// [`KernelNetFunc::now`](crate::KernelNetFunc::now) (`SO_TIMESTAMP`).
+    pub timestamp: Option<Duration>,
+    /// The datagram was longer than the buffer and got cut (`MSG_TRUNC`).
+    pub truncated: bool,
+}
 
 /// A UDP socket that provides POSIX-like APIs.
 pub struct UdpSocket {
@@ -16,6 +57,14 @@ pub struct UdpSocket {
     local_addr: Mutex<Option<IpEndpoint>>,
     peer_addr: Mutex<Option<IpEndpoint>>,
     nonblock: AtomicBool,
+    reuse_addr: AtomicBool,
+    reuse_port: AtomicBool,
+    broadcast: AtomicBool,
+    multicast_groups: Mutex<BTreeSet<IpAddress>>,
+    multicast_ttl: AtomicU8,
+    multicast_loop: AtomicBool,
+    /// Whether the socket was shut down, which `poll` reports as a hangup.
+    shut_down: AtomicBool,
 }
 
 impl UdpSocket {
@@ -29,16 +78,35 @@ impl UdpSocket {
             local_addr: Mutex::new(None),
             peer_addr: Mutex::new(None),
             nonblock: AtomicBool::new(false),
+            reuse_addr: AtomicBool::new(false),
+            reuse_port: AtomicBool::new(false),
+            broadcast: AtomicBool::new(false),
+            multicast_groups: Mutex::new(BTreeSet::new()),
+            multicast_ttl: AtomicU8::new(1),
+            multicast_loop: AtomicBool::new(true),
+            shut_down: AtomicBool::new(false),
         }
     }
 
+    /// Returns a socket over `handle` with the addresses and mode of this
+    /// one, and [`SO_REUSEADDR`](Self::set_reuse_addr) set.
+    #[deprecated(note = "call `set_reuse_addr` on each socket before `bind` instead")]
     pub fn reuse(&self, handle: SocketHandle) -> Self {
-        Self {
+        let socket = Self {
             handle,
             local_addr: Mutex::new(*self.local_addr.lock()),
             peer_addr: Mutex::new(*self.peer_addr.lock()),
-            nonblock: AtomicBool::new(self.nonblock.load(Ordering::Acquire)),
-        }
+            nonblock: AtomicBool::new(self.is_nonblocking()),
+            reuse_addr: AtomicBool::new(false),
+            reuse_port: AtomicBool::new(false),
+            broadcast: AtomicBool::new(false),
+            multicast_groups: Mutex::new(BTreeSet::new()),
+            multicast_ttl: AtomicU8::new(1),
+            multicast_loop: AtomicBool::new(true),
+            shut_down: AtomicBool::new(false),
+        };
+        socket.set_reuse_addr(true);
+        socket
     }
 
     /// Returns the local address and port, or
@@ -75,59 +143,185 @@ impl UdpSocket {
         self.nonblock.store(nonblocking, Ordering::Release);
     }
 
+    /// Returns whether `SO_REUSEADDR` is set on this socket.
+    #[inline]
+    pub fn reuse_addr(&self) -> bool {
+        self.reuse_addr.load(Ordering::Acquire)
+    }
+
+    /// Sets `SO_REUSEADDR`.
+    ///
+    /// Sockets that all set it may bind the same address and port. Each of
+    /// them receives its own copy of broadcast and multicast datagrams. It
+    /// must be set before binding.
+    #[inline]
+    pub fn set_reuse_addr(&self, reuse: bool) {
+        self.reuse_addr.store(reuse, Ordering::Release);
+    }
+
+    /// Returns whether `SO_REUSEPORT` is set on this socket.
+    #[inline]
+    pub fn reuse_port(&self) -> bool {
+        self.reuse_port.load(Ordering::Acquire)
+    }
+
+    /// Sets `SO_REUSEPORT`.
+    ///
+    /// Like [`set_reuse_addr`](Self::set_reuse_addr), sockets that all set it
+    /// may share an address and port. It must be set before binding.
+    #[inline]
+    pub fn set_reuse_port(&self, reuse: bool) {
+        self.reuse_port.store(reuse, Ordering::Release);
+    }
+
+    /// Returns whether `SO_BROADCAST` is set on this socket.
+    #[inline]
+    pub fn broadcast(&self) -> bool {
+        self.broadcast.load(Ordering::Acquire)
+    }
+
+    /// Sets `SO_BROADCAST`.
+    ///
+    /// Without it, sending to or connecting to the limited broadcast address
+    /// or the broadcast address of the subnet fails with
+    /// [`Err(PermissionDenied)`](NetError::PermissionDenied). Receiving
+    /// broadcast datagrams does not need it.
+    #[inline]
+    pub fn set_broadcast(&self, broadcast: bool) {
+        self.broadcast.store(broadcast, Ordering::Release);
+    }
+
+    /// Returns the TTL of the multicast datagrams sent by this socket
+    /// (`IP_MULTICAST_TTL`).
+    #[inline]
+    pub fn multicast_ttl_v4(&self) -> u8 {
+        self.multicast_ttl.load(Ordering::Acquire)
+    }
+
+    /// Sets `IP_MULTICAST_TTL`, 1 by default so that multicast datagrams
+    /// stay on the local network. With 0, they only reach this host.
+    #[inline]
+    pub fn set_multicast_ttl_v4(&self, ttl: u8) {
+        self.multicast_ttl.store(ttl, Ordering::Release);
+    }
+
+    /// Returns whether `IP_MULTICAST_LOOP` is set on this socket.
+    #[inline]
+    pub fn multicast_loop_v4(&self) -> bool {
+        self.multicast_loop.load(Ordering::Acquire)
+    }
+
+    /// Sets `IP_MULTICAST_LOOP`, on by default.
+    ///
+    /// With it, the multicast datagrams sent by this socket are also
+    /// delivered to the sockets of this host bound to their port, once a
+    /// socket of this host joined the group.
+    #[inline]
+    pub fn set_multicast_loop_v4(&self, multicast_loop: bool) {
+        self.multicast_loop.store(multicast_loop, Ordering::Release);
+    }
+
+    /// Joins the multicast group `multiaddr` (`IP_ADD_MEMBERSHIP`).
+    ///
+    /// `interface` must be the address of the interface or unspecified, as
+    /// netcore has a single interface. The interface reports the membership
+    /// with IGMP and answers the queries about it until the last socket
+    /// leaves the group. Returns [`Err(AddrInUse)`](NetError::AddrInUse) if
+    /// this socket already joined it.
+    pub fn join_multicast_v4(&self, multiaddr: Ipv4Addr, interface: Ipv4Addr) -> NetResult<()> {
+        let group = self.check_multicast_args(multiaddr, interface)?;
+        let mut groups = self.multicast_groups.lock();
+        if groups.contains(&group) {
+            warn!("UDP socket {}: already in group {}", self.handle, group);
+            return Err(NetError::AddrInUse);
+        }
+        NET_INTERFACE.get().unwrap().join_multicast_group(group)?;
+        groups.insert(group);
+        info!("UDP socket {}: joined group {}", self.handle, group);
+        Ok(())
+    }
+
+    /// Leaves the multicast group `multiaddr` (`IP_DROP_MEMBERSHIP`).
+    ///
+    /// Returns [`Err(NotFound)`](NetError::NotFound) if this socket did not
+    /// join it.
+    pub fn leave_multicast_v4(&self, multiaddr: Ipv4Addr, interface: Ipv4Addr) -> NetResult<()> {
+        let group = self.check_multicast_args(multiaddr, interface)?;
+        if !self.multicast_groups.lock().remove(&group) {
+            warn!("UDP socket {}: not in group {}", self.handle, group);
+            return Err(NetError::NotFound);
+        }
+        info!("UDP socket {}: left group {}", self.handle, group);
+        NET_INTERFACE.get().unwrap().leave_multicast_group(group)
+    }
+
+    /// Takes the pending error of the socket, like `SO_ERROR`.
+    ///
+    /// A connected socket gets the ICMP errors about the datagrams it sent to
+    /// its peer, such as [`ConnectionRefused`](NetError::ConnectionRefused)
+    /// for port unreachable. The next send, or receive with no datagram
+    /// queued, also returns the error, which is reported once.
+    pub fn take_error(&self) -> Option<NetError> {
+        let local_addr = (*self.local_addr.lock())?;
+        UDP_TABLE.take_error(local_addr.port, self.handle)
+    }
+
+    /// Attaches callbacks run when the socket becomes readable or writable,
+    /// or detaches them with `None`.
+    pub fn set_upcall(&self, upcall: Option<Arc<dyn SocketUpcall>>) {
+        SOCKET_EVENTS.set_upcall(WakeKey::Socket(self.handle), upcall);
+        SOCKET_EVENTS.arm(self.handle, SocketKind::Udp);
+    }
+
     /// Binds an unbound socket to the given address and port.
     ///
+    /// If the given port is 0, it generates one automatically. Returns
+    /// [`Err(AddrInUse)`](NetError::AddrInUse) if another socket owns the
+    /// address, unless both set [`SO_REUSEADDR`](Self::set_reuse_addr) or
+    /// [`SO_REUSEPORT`](Self::set_reuse_port).
+    ///
     /// It's must be called before [`send_to`](Self::send_to) and
     /// [`recv_from`](Self::recv_from).
-    pub fn bind(&self, mut local_addr: SocketAddr) -> NetResult<Option<UdpSocket>> {
+    pub fn bind(&self, local_addr: SocketAddr) -> NetResult<()> {
         let mut self_local_addr = self.local_addr.lock();
-
-        if local_addr.port() == 0 {
-            local_addr.set_port(get_ephemeral_port()?);
-        }
         if self_local_addr.is_some() {
             warn!("UDP socket {}: already bound", self.handle);
             return Err(NetError::InvalidInput);
         }
 
         let local_endpoint = from_core_sockaddr(local_addr);
-        let endpoint = IpListenEndpoint {
-            addr: (!is_unspecified(local_endpoint.addr)).then_some(local_endpoint.addr),
-            port: local_endpoint.port,
-        };
-
-        *self_local_addr = Some(local_endpoint);
-        drop(self_local_addr);
-
-        // let mut udp_reuse = UDP_PORT_REUSE.lock();
-        // // check if port is in reuse queue
-        // if udp_reuse.contains_key(&local_addr.port()) {
-        //     warn!("UDP socket {}: port {} already in use", self.handle, local_addr.port());
-        //     // reset self handle to reuse handle
-        //     let reuse_handle = udp_reuse.get(&local_addr.port()).unwrap();
-        //     let reuse = self.reuse(*reuse_handle);
-        //     // it share a  socket handle with reuse socket
-        //     return Ok(Some(reuse));
-        // }
-
-        SOCKET_SET.with_socket_mut::<udp::Socket, _, _>(self.handle, |socket| {
-            socket.bind(endpoint).map_err(|e| match e {
-                BindError::InvalidState => {
-                    warn!("UDP socket {}: already bound", self.handle);
-                    NetError::AlreadyExists
-                }
-                BindError::Unaddressable => {
-                    warn!("UDP socket {}: invalid address", self.handle);
-                    NetError::InvalidInput
-                }
+        let endpoint = UDP_TABLE.bind(
+            IpListenEndpoint {
+                addr: (!is_unspecified(local_endpoint.addr)).then_some(local_endpoint.addr),
+                port: local_endpoint.port,
+            },
+            self.handle,
+            self.reuse_addr(),
+            self.reuse_port(),
+        )?;
+
+        SOCKET_SET
+            .with_socket_mut::<udp::Socket, _, _>(self.handle, |socket| {
+                socket.bind(endpoint).map_err(|e| match e {
+                    BindError::InvalidState => {
+                        warn!("UDP socket {}: already bound", self.handle);
+                        NetError::AlreadyExists
+                    }
+                    BindError::Unaddressable => {
+                        warn!("UDP socket {}: invalid address", self.handle);
+                        NetError::InvalidInput
+                    }
+                })
             })
-        })?;
-
-        // insert to reuse queue
-        // udp_reuse.insert(local_endpoint.port, self.handle);
+            .map_err(|e| {
+                UDP_TABLE.unbind(endpoint.port, self.handle);
+                e
+            })?;
 
+        *self_local_addr = Some(IpEndpoint::new(local_endpoint.addr, endpoint.port));
+        self.shut_down.store(false, Ordering::Release);
         info!("UDP socket {}: bound on {}", self.handle, endpoint);
-        Ok(None)
+        Ok(())
     }
 
     /// Sends data on the socket to the given address. On success, returns the
@@ -143,47 +337,234 @@ impl UdpSocket {
     /// Receives a single datagram message on the socket. On success, returns
     /// the number of bytes read and the origin.
     pub fn recv_from(&self, buf: &mut [u8]) -> NetResult<(usize, SocketAddr)> {
-        self.recv_impl(|socket| match socket.recv_slice(buf) {
-            Ok((len, meta)) => Ok((len, into_core_sockaddr(meta.endpoint))),
-            Err(_e) => {
-                warn!("UDP socket {}: recv_from() failed", self.handle);
-                // Err(NetError::BadState)
-                Err(NetError::WouldBlock)
-            }
+        self.recv_impl(false, |data, src, _| {
+            let len = copy_truncated(buf, data);
+            Ok((len, into_core_sockaddr(src)))
         })
     }
 
     /// Receives a single datagram message on the socket, without removing it from
     /// the queue. On success, returns the number of bytes read and the origin.
     pub fn peek_from(&self, buf: &mut [u8]) -> NetResult<(usize, SocketAddr)> {
-        self.recv_impl(|socket| match socket.peek_slice(buf) {
-            Ok((len, meta)) => Ok((len, into_core_sockaddr(meta.endpoint))),
-            Err(_) => {
-                warn!("UDP socket {}: recv_from() failed", self.handle);
-                Err(NetError::BadState)
+        self.recv_impl(true, |data, src, _| {
+            let len = copy_truncated(buf, data);
+            Ok((len, into_core_sockaddr(src)))
+        })
+    }
+
+    /// Receives a single datagram message on the socket, along with its
+    /// ancillary data (`recvmsg`). On success, returns the number of bytes
+    /// read and the [`UdpRecvInfo`].
+    ///
+    /// The ancillary data is recorded by netcore as the datagram arrives, and
+    /// is `None` in the rare cases it couldn't be matched with the datagram.
+    pub fn recv_msg(&self, buf: &mut [u8]) -> NetResult<(usize, UdpRecvInfo)> {
+        self.recv_impl(false, |data, src, meta| {
+            Ok(recv_msg_into(buf, data, src, meta))
+        })
+    }
+
+    /// Receives several datagrams at once (`recvmmsg`), one into each of
+    /// `bufs`. On success, returns the number of bytes read and the
+    /// ancillary data of each datagram received.
+    ///
+    /// It waits for the first datagram, then takes the ones already queued
+    /// without waiting, all with a single poll of the interface and a single
+    /// lock of the socket set.
+    pub fn recv_mmsg(&self, bufs: &mut [&mut [u8]]) -> NetResult<Vec<(usize, UdpRecvInfo)>> {
+        let Some(local_addr) = *self.local_addr.lock() else {
+            warn!("UDP socket {}: recv() failed: not bound", self.handle);
+            return Err(NetError::NotConnected);
+        };
+        if bufs.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        block_on(self.is_nonblocking(), || {
+            SOCKET_SET.with_socket_mut::<udp::Socket, _, _>(self.handle, |socket| {
+                let mut msgs = Vec::new();
+                for buf in bufs.iter_mut() {
+                    let msg = if let Some(msg) =
+                        UDP_TABLE.recv_backlog(local_addr.port, self.handle, false, |datagram| {
+                            let meta = Some(datagram.meta);
+                            recv_msg_into(buf, &datagram.payload, datagram.src, meta)
+                        }) {
+                        msg
+                    } else if socket.can_recv() {
+                        let (data, meta) = socket.recv().map_err(|_| {
+                            warn!("UDP socket {}: recv() failed", self.handle);
+                            NetError::BadState
+                        })?;
+                        let src = meta.endpoint;
+                        let meta = UDP_TABLE.recv_meta(
+                            local_addr.port,
+                            self.handle,
+                            src,
+                            data.len(),
+                            false,
+                        );
+                        recv_msg_into(buf, data, src, meta)
+                    } else {
+                        break;
+                    };
+                    msgs.push(msg);
+                }
+                if !msgs.is_empty() {
+                    Ok(msgs)
+                } else if let Some(err) = UDP_TABLE.take_error(local_addr.port, self.handle) {
+                    Err(err)
+                } else if !socket.is_open() {
+                    warn!("UDP socket {}: recv() failed: not connected", self.handle);
+                    Err(NetError::NotConnected)
+                } else {
+                    // no more data
+                    Err(NetError::WouldBlock)
+                }
+            })
+        })
+    }
+
+    /// Sends several datagrams at once (`sendmmsg`), each to its address.
+    /// On success, returns how many were sent, from the start of `msgs`.
+    ///
+    /// It waits until the first datagram can be queued, then queues the
+    /// following ones while they fit in the send buffer, all under a single
+    /// lock of the socket set. A batch also stops before an invalid address,
+    /// whose error is returned if it comes first, and where the destinations
+    /// switch between unicast and multicast. Multicast datagrams, which have
+    /// their own TTL, are sent one by one.
+    pub fn send_mmsg(&self, msgs: &[(&[u8], SocketAddr)]) -> NetResult<usize> {
+        let Some(&(_, first_addr)) = msgs.first() else {
+            return Ok(0);
+        };
+        if self.local_addr.lock().is_none() {
+            self.bind(into_core_sockaddr(UNSPECIFIED_ENDPOINT))?;
+        }
+        self.check_error()?;
+
+        let multicast = first_addr.ip().is_multicast();
+        let mut len = 0;
+        for &(_, addr) in msgs {
+            if addr.ip().is_multicast() != multicast {
+                break;
+            }
+            if let Err(e) = self.check_send_addr(addr) {
+                if len == 0 {
+                    return Err(e);
+                }
+                break;
+            }
+            len += 1;
+        }
+        let batch = &msgs[..len];
+        if multicast {
+            // multicast datagrams carry their own TTL, so go one by one.
+            for (sent, &(buf, addr)) in batch.iter().enumerate() {
+                if let Err(e) = self.send_impl(buf, from_core_sockaddr(addr)) {
+                    return if sent == 0 { Err(e) } else { Ok(sent) };
+                }
             }
+            return Ok(len);
+        }
+        block_on(self.is_nonblocking(), || {
+            SOCKET_SET.with_socket_mut::<udp::Socket, _, _>(self.handle, |socket| {
+                let mut sent = 0;
+                for &(buf, addr) in batch {
+                    match socket.send_slice(buf, from_core_sockaddr(addr)) {
+                        Ok(()) => sent += 1,
+                        Err(SendError::BufferFull) => break,
+                        Err(SendError::Unaddressable) if sent == 0 => {
+                            warn!("UDP socket {}: send() failed: unaddressable", self.handle);
+                            return Err(NetError::ConnectionRefused);
+                        }
+                        Err(SendError::Unaddressable) => break,
+                    }
+                }
+                if sent > 0 {
+                    Ok(sent)
+                } else if !socket.is_open() {
+                    warn!("UDP socket {}: send() failed: not connected", self.handle);
+                    Err(NetError::NotConnected)
+                } else {
+                    // tx buffer is full
+                    Err(NetError::WouldBlock)
+                }
+            })
         })
     }
 
+    /// Sends a datagram to `remote_addr`, or to the connected address if
+    /// `None`, from the local address `src` (`sendmsg` with `IP_PKTINFO`).
+    ///
+    /// `src` must be an address of the interface, and match the bound
+    /// address unless the socket is bound to the wildcard address. If it is
+    /// `None` or unspecified, this is [`send_to`](Self::send_to) or
+    /// [`send`](Self::send). On success, returns the number of bytes
+    /// written.
+    pub fn send_msg(
+        &self,
+        buf: &[u8],
+        remote_addr: Option<SocketAddr>,
+        src: Option<IpAddr>,
+    ) -> NetResult<usize> {
+        let remote_endpoint = match remote_addr {
+            Some(addr) if addr.port() == 0 || addr.ip().is_unspecified() => {
+                warn!("socket send_msg() failed: invalid address");
+                return Err(NetError::InvalidInput);
+            }
+            Some(addr) => from_core_sockaddr(addr),
+            None => self.remote_endpoint()?,
+        };
+        match src.map(from_core_ipaddr) {
+            Some(src_addr) if !is_unspecified(src_addr) => {
+                self.send_from(buf, src_addr, remote_endpoint)
+            }
+            _ => self.send_impl(buf, remote_endpoint),
+        }
+    }
+
     /// Connects this UDP socket to a remote address, allowing the `send` and
     /// `recv` to be used to send data and also applies filters to only receive
     /// data from the specified address.
     ///
+    /// Datagrams from other addresses are dropped rather than queued, and
+    /// don't make the socket readable. Like on Linux, the ones queued before
+    /// connecting stay. Connecting again changes the address.
+    ///
     /// The local port will be generated automatically if the socket is not bound.
     /// It's must be called before [`send`](Self::send) and
     /// [`recv`](Self::recv).
     pub fn connect(&self, addr: SocketAddr) -> NetResult<()> {
         let mut self_peer_addr = self.peer_addr.lock();
+        self.check_broadcast(from_core_ipaddr(addr.ip()))?;
 
         if self.local_addr.lock().is_none() {
             self.bind(into_core_sockaddr(UNSPECIFIED_ENDPOINT))?;
         }
 
-        *self_peer_addr = Some(from_core_sockaddr(addr));
+        let peer = from_core_sockaddr(addr);
+        if let Some(local_addr) = *self.local_addr.lock() {
+            UDP_TABLE.connect(local_addr.port, self.handle, Some(peer));
+        }
+        *self_peer_addr = Some(peer);
         info!("UDP socket {}: connected to {}", self.handle, addr);
         Ok(())
     }
 
+    /// Dissolves the association made by [`connect`](Self::connect), like
+    /// connecting to `AF_UNSPEC`.
+    ///
+    /// The socket stays bound, and receives datagrams from any address again.
+    pub fn disconnect(&self) -> NetResult<()> {
+        let mut self_peer_addr = self.peer_addr.lock();
+        if let Some(local_addr) = *self.local_addr.lock() {
+            UDP_TABLE.connect(local_addr.port, self.handle, None);
+        }
+        *self_peer_addr = None;
+        info!("UDP socket {}: disconnected", self.handle);
+        Ok(())
+    }
+
     /// Sends data on the socket to the remote address to which it is connected.
     pub fn send(&self, buf: &[u8]) -> NetResult<usize> {
         let remote_endpoint = self.remote_endpoint()?;
@@ -193,20 +574,8 @@ impl UdpSocket {
     /// Receives a single datagram message on the socket from the remote address
     /// to which it is connected. On success, returns the number of bytes read.
     pub fn recv(&self, buf: &mut [u8]) -> NetResult<usize> {
-        let remote_endpoint = self.remote_endpoint()?;
-        self.recv_impl(|socket| {
-            let (len, meta) = socket.recv_slice(buf).map_err(|_| {
-                warn!("UDP socket {}: recv_from() failed", self.handle);
-                NetError::BadState
-            })?;
-            if !is_unspecified(remote_endpoint.addr) && remote_endpoint.addr != meta.endpoint.addr {
-                return Err(NetError::WouldBlock);
-            }
-            if remote_endpoint.port != 0 && remote_endpoint.port != meta.endpoint.port {
-                return Err(NetError::WouldBlock);
-            }
-            Ok(len)
-        })
+        self.remote_endpoint()?;
+        self.recv_impl(false, |data, _, _| Ok(copy_truncated(buf, data)))
     }
 
     /// Close the socket.
@@ -215,22 +584,42 @@ impl UdpSocket {
             info!("UDP socket {}: shutting down", self.handle);
             socket.close();
         });
+        if let Some(local_addr) = self.local_addr.lock().take() {
+            UDP_TABLE.unbind(local_addr.port, self.handle);
+        }
+        let groups = core::mem::take(&mut *self.multicast_groups.lock());
+        let iface = NET_INTERFACE.get().unwrap();
+        for group in groups {
+            iface.leave_multicast_group(group).ok();
+        }
+        self.shut_down.store(true, Ordering::Release);
         SOCKET_SET.poll_interfaces();
         Ok(())
     }
 
     /// Whether the socket is readable or writable.
+    ///
+    /// Like on Linux, a socket that was shut down is reported hung up, and
+    /// readable and writable, as neither would block.
     pub fn poll(&self) -> NetResult<NetPollState> {
-        if self.local_addr.lock().is_none() {
+        if self.shut_down.load(Ordering::Acquire) {
             return Ok(NetPollState {
-                readable: false,
-                writable: false,
+                readable: true,
+                writable: true,
+                hangup: true,
+                read_hangup: true,
+                ..Default::default()
             });
         }
+        let Some(local_addr) = *self.local_addr.lock() else {
+            return Ok(NetPollState::default());
+        };
         SOCKET_SET.with_socket_mut::<udp::Socket, _, _>(self.handle, |socket| {
             Ok(NetPollState {
-                readable: socket.can_recv(),
+                readable: socket.can_recv() || UDP_TABLE.has_backlog(local_addr.port, self.handle),
                 writable: socket.can_send(),
+                error: UDP_TABLE.has_error(local_addr.port, self.handle),
+                ..Default::default()
             })
         })
     }
@@ -252,8 +641,19 @@ impl UdpSocket {
             self.bind(into_core_sockaddr(UNSPECIFIED_ENDPOINT))?;
             // return Err(NetError::NotConnected);
         }
+        self.check_error()?;
+        self.check_broadcast(remote_endpoint.addr)?;
+
+        if remote_endpoint.addr.is_multicast() {
+            // smoltcp applies the hop limit of a socket when its datagrams
+            // leave, so the ones with the multicast TTL are built here.
+            let local_addr = self.local_addr.lock().ok_or(NetError::NotConnected)?;
+            let src_addr = self.source_addr(local_addr.addr)?;
+            let ttl = self.multicast_ttl_v4();
+            return self.send_built(buf, src_addr, local_addr.port, remote_endpoint, ttl);
+        }
 
-        self.block_on(|| {
+        block_on(self.is_nonblocking(), || {
             SOCKET_SET.with_socket_mut::<udp::Socket, _, _>(self.handle, |socket| {
                 if socket.can_send() {
                     socket
@@ -277,20 +677,236 @@ impl UdpSocket {
         })
     }
 
-    fn recv_impl<F, T>(&self, mut op: F) -> NetResult<T>
+    /// Sends a datagram from the local address `src_addr`, by building it
+    /// and sending it through a raw socket.
+    fn send_from(
+        &self,
+        buf: &[u8],
+        src_addr: IpAddress,
+        remote_endpoint: IpEndpoint,
+    ) -> NetResult<usize> {
+        if self.local_addr.lock().is_none() {
+            self.bind(into_core_sockaddr(UNSPECIFIED_ENDPOINT))?;
+        }
+        let local_addr = self.local_addr.lock().ok_or(NetError::NotConnected)?;
+        if !is_unspecified(local_addr.addr) {
+            if local_addr.addr != src_addr {
+                warn!(
+                    "UDP socket {}: send() failed: not bound to {}",
+                    self.handle, src_addr
+                );
+                return Err(NetError::InvalidInput);
+            }
+            return self.send_impl(buf, remote_endpoint);
+        }
+        self.check_error()?;
+        let iface = NET_INTERFACE.get().unwrap().raw_interface();
+        if !iface.lock().has_ip_addr(src_addr) {
+            warn!(
+                "UDP socket {}: send() failed: no address {}",
+                self.handle, src_addr
+            );
+            return Err(NetError::Unaddressable);
+        }
+        self.check_broadcast(remote_endpoint.addr)?;
+
+        let ttl = if remote_endpoint.addr.is_multicast() {
+            self.multicast_ttl_v4()
+        } else {
+            DEFAULT_TTL
+        };
+        self.send_built(buf, src_addr, local_addr.port, remote_endpoint, ttl)
+    }
+
+    /// Builds a datagram from `src_addr` and `src_port` with the TTL `ttl`,
+    /// and sends it through the raw socket of the interface, then delivers
+    /// it to this host too if it is a multicast one.
+    fn send_built(
+        &self,
+        buf: &[u8],
+        src_addr: IpAddress,
+        src_port: u16,
+        remote_endpoint: IpEndpoint,
+        ttl: u8,
+    ) -> NetResult<usize> {
+        let multicast = remote_endpoint.addr.is_multicast();
+        let udp_repr = UdpRepr {
+            src_port,
+            dst_port: remote_endpoint.port,
+        };
+        let (IpAddress::Ipv4(src), IpAddress::Ipv4(dst)) = (src_addr, remote_endpoint.addr);
+        let ip_repr = Ipv4Repr {
+            src_addr: src,
+            dst_addr: dst,
+            next_header: IpProtocol::Udp,
+            payload_len: udp_repr.header_len() + buf.len(),
+            hop_limit: ttl,
+        };
+        let packet_len = ip_repr.buffer_len() + ip_repr.payload_len;
+        if packet_len > UDP_TX_BUF_LEN {
+            warn!("UDP socket {}: send() failed: too large", self.handle);
+            return Err(NetError::InvalidInput);
+        }
+
+        if ttl > 0 {
+            // smoltcp UDP sockets pick the source address themselves.
+            let iface = NET_INTERFACE.get().unwrap();
+            block_on(self.is_nonblocking(), || {
+                iface.send_udp_packet(packet_len, &mut |tx_buf| {
+                    let caps = ChecksumCapabilities::default();
+                    let mut packet = Ipv4Packet::new_unchecked(tx_buf);
+                    ip_repr.emit(&mut packet, &caps);
+                    udp_repr.emit(
+                        &mut UdpPacket::new_unchecked(packet.payload_mut()),
+                        &src_addr,
+                        &remote_endpoint.addr,
+                        buf.len(),
+                        |payload| payload.copy_from_slice(buf),
+                        &caps,
+                    );
+                })
+            })?;
+        }
+        if multicast {
+            self.loop_multicast(buf, src_addr, remote_endpoint, ttl);
+        }
+        Ok(buf.len())
+    }
+
+    /// Checks a destination of [`send_mmsg`](Self::send_mmsg) like
+    /// [`send_to`](Self::send_to) does.
+    fn check_send_addr(&self, addr: SocketAddr) -> NetResult<()> {
+        if addr.port() == 0 || addr.ip().is_unspecified() {
+            warn!("socket send_to() failed: invalid address");
+            return Err(NetError::InvalidInput);
+        }
+        self.check_broadcast(from_core_ipaddr(addr.ip()))
+    }
+
+    /// Fails with the pending error, if any.
+    fn check_error(&self) -> NetResult<()> {
+        match self.take_error() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Fails if `addr` is a broadcast address and `SO_BROADCAST` is not set.
+    fn check_broadcast(&self, addr: IpAddress) -> NetResult<()> {
+        if !self.broadcast() && NET_INTERFACE.get().unwrap().is_broadcast(addr) {
+            warn!("UDP socket {}: {} needs SO_BROADCAST", self.handle, addr);
+            return Err(NetError::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    fn check_multicast_args(
+        &self,
+        multiaddr: Ipv4Addr,
+        interface: Ipv4Addr,
+    ) -> NetResult<IpAddress> {
+        if !multiaddr.is_multicast() {
+            warn!(
+                "UDP socket {}: {} is not a multicast group",
+                self.handle, multiaddr
+            );
+            return Err(NetError::InvalidInput);
+        }
+        if !interface.is_unspecified() {
+            let iface = NET_INTERFACE.get().unwrap().raw_interface();
+            let iface_addr = iface.lock().ipv4_addr();
+            if iface_addr.map_or(true, |addr| addr.0 != interface.octets()) {
+                warn!("UDP socket {}: no interface {}", self.handle, interface);
+                return Err(NetError::Unaddressable);
+            }
+        }
+        Ok(from_core_ipaddr(IpAddr::V4(multiaddr)))
+    }
+
+    /// The source address of a datagram from the bound address `bound`: the
+    /// bound one if specified, else the address of the interface.
+    fn source_addr(&self, bound: IpAddress) -> NetResult<IpAddress> {
+        if !is_unspecified(bound) {
+            return Ok(bound);
+        }
+        let iface = NET_INTERFACE.get().unwrap().raw_interface();
+        let addr = iface.lock().ipv4_addr();
+        addr.map(IpAddress::Ipv4).ok_or_else(|| {
+            warn!(
+                "UDP socket {}: send() failed: no source address",
+                self.handle
+            );
+            NetError::Unaddressable
+        })
+    }
+
+    /// Delivers a multicast datagram sent by this socket to this host, if
+    /// `IP_MULTICAST_LOOP` is set and a socket of this host joined the group.
+    fn loop_multicast(
+        &self,
+        buf: &[u8],
+        src_addr: IpAddress,
+        remote_endpoint: IpEndpoint,
+        ttl: u8,
+    ) {
+        let iface = NET_INTERFACE.get().unwrap();
+        if !self.multicast_loop_v4() || !iface.has_multicast_group(remote_endpoint.addr) {
+            return;
+        }
+        let Some(local_addr) = *self.local_addr.lock() else {
+            return;
+        };
+        let src = IpEndpoint::new(src_addr, local_addr.port);
+        let meta = UdpRecvMeta {
+            dst: remote_endpoint.addr,
+            ttl,
+            timestamp: KERNEL_NET_FUNC.get().unwrap().now().into(),
+        };
+        UDP_TABLE.loopback_multicast(src, remote_endpoint, buf, meta);
+        SOCKET_EVENTS.dispatch();
+    }
+
+    /// Receives one datagram and passes its payload, source and ancillary
+    /// data to `op`.
+    ///
+    /// Datagrams fanned out to this socket by the [`UdpTable`] are taken
+    /// before the ones queued in the smoltcp socket. With `peek`, the
+    /// datagram stays queued.
+    ///
+    /// [`UdpTable`]: crate::udp_table::UdpTable
+    fn recv_impl<F, T>(&self, peek: bool, mut op: F) -> NetResult<T>
     where
-        F: FnMut(&mut udp::Socket) -> NetResult<T>,
+        F: FnMut(&[u8], IpEndpoint, Option<UdpRecvMeta>) -> NetResult<T>,
     {
-        if self.local_addr.lock().is_none() {
+        let Some(local_addr) = *self.local_addr.lock() else {
             warn!("UDP socket {}: recv() failed: not bound", self.handle);
             return Err(NetError::NotConnected);
-        }
+        };
 
-        self.block_on(|| {
+        block_on(self.is_nonblocking(), || {
             SOCKET_SET.with_socket_mut::<udp::Socket, _, _>(self.handle, |socket| {
-                if socket.can_recv() {
+                if let Some(res) =
+                    UDP_TABLE.recv_backlog(local_addr.port, self.handle, peek, |datagram| {
+                        op(&datagram.payload, datagram.src, Some(datagram.meta))
+                    })
+                {
+                    res
+                } else if socket.can_recv() {
                     // data available
-                    op(socket)
+                    let (data, src) = if peek {
+                        socket.peek().map(|(data, meta)| (data, meta.endpoint))
+                    } else {
+                        socket.recv().map(|(data, meta)| (data, meta.endpoint))
+                    }
+                    .map_err(|_| {
+                        warn!("UDP socket {}: recv() failed", self.handle);
+                        NetError::BadState
+                    })?;
+                    let meta =
+                        UDP_TABLE.recv_meta(local_addr.port, self.handle, src, data.len(), peek);
+                    op(data, src, meta)
+                } else if let Some(err) = UDP_TABLE.take_error(local_addr.port, self.handle) {
+                    Err(err)
                 } else if !socket.is_open() {
                     warn!("UDP socket {}: recv() failed: not connected", self.handle);
                     Err(NetError::NotConnected)
@@ -302,30 +918,16 @@ impl UdpSocket {
             })
         })
     }
+}
 
-    fn block_on<F, T>(&self, mut f: F) -> NetResult<T>
-    where
-        F: FnMut() -> NetResult<T>,
-    {
-        if self.is_nonblocking() {
-            f()
-        } else {
-            loop {
-                SOCKET_SET.poll_interfaces();
-                match f() {
-                    Ok(t) => return Ok(t),
-                    Err(NetError::WouldBlock) => {
-                        let kernel_func = KERNEL_NET_FUNC.get().unwrap();
-                        let has_signal = kernel_func.yield_now();
-                        if !has_signal {
-                            continue;
-                        }
-                        return Err(NetError::Interrupted);
-                    }
-                    Err(e) => return Err(e),
-                }
-            }
-        }
+impl NetPollable for UdpSocket {
+    fn poll(&self) -> NetResult<NetPollState> {
+        UdpSocket::poll(self)
+    }
+
+    fn register_waker(&self, waker: &Waker) {
+        SOCKET_EVENTS.arm(self.handle, SocketKind::Udp);
+        SOCKET_EVENTS.subscribe(WakeKey::Socket(self.handle), waker);
     }
 }
 
@@ -337,17 +939,23 @@ impl Drop for UdpSocket {
     }
 }
 
-fn get_ephemeral_port() -> NetResult<u16> {
-    const PORT_START: u16 = 0xc000;
-    const PORT_END: u16 = 0xffff;
-    static CURR: Mutex<u16> = Mutex::new(PORT_START);
-    let mut curr = CURR.lock();
-
-    let port = *curr;
-    if *curr == PORT_END {
-        *curr = PORT_START;
-    } else {
-        *curr += 1;
-    }
-    Ok(port)
+/// Copies a datagram into `buf` like [`copy_truncated`], and gathers its
+/// ancillary data.
+fn recv_msg_into(
+    buf: &mut [u8],
+    data: &[u8],
+    src: IpEndpoint,
+    meta: Option<UdpRecvMeta>,
+) -> (usize, UdpRecvInfo) {
+    let len = copy_truncated(buf, data);
+    let timestamp = meta.map(|meta| meta.timestamp.total_micros() as u64);
+    let info = UdpRecvInfo {
+        src: into_core_sockaddr(src),
+        dst: meta.map(|meta| into_core_ipaddr(meta.dst)),
+        ifindex: IFACE_INDEX,
+        ttl: meta.map(|meta| meta.ttl),
+        timestamp: timestamp.map(Duration::from_micros),
+        truncated: data.len() > len,
+    };
+    (len, info)
 }
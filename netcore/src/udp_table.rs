@@ -0,0 +1,363 @@
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+
+use log::{info, warn};
+use smoltcp::iface::SocketHandle;
+use smoltcp::time::Instant;
+use smoltcp::wire::{IpAddress, IpEndpoint, IpListenEndpoint};
+
+use crate::addr::is_unspecified;
+use crate::common::{NetError, NetResult, UDP_RX_BUF_LEN};
+use crate::event::WakeKey;
+use crate::port::alloc_ephemeral_port;
+use crate::SOCKET_EVENTS;
+use kernel_sync::TicketMutex as Mutex;
+
+/// The ancillary data of a received datagram, which smoltcp doesn't keep.
+#[derive(Debug, Clone, Copy)]
+pub struct UdpRecvMeta {
+    /// The destination address of the IP header.
+    pub dst: IpAddress,
+    pub ttl: u8,
+    /// When the datagram was received.
+    pub timestamp: Instant,
+}
+
+/// How many [`UdpRecvMeta`] are kept per socket for the datagrams queued by
+/// smoltcp, which queues at most 8.
+const MAX_RECV_META: usize = 16;
+
+/// A datagram delivered by netcore rather than by smoltcp.
+pub struct UdpDatagram {
+    pub payload: Vec<u8>,
+    pub src: IpEndpoint,
+    pub meta: UdpRecvMeta,
+}
+
+struct UdpBinding {
+    handle: SocketHandle,
+    addr: Option<IpAddress>,
+    reuse_addr: bool,
+    reuse_port: bool,
+    /// The address the socket is connected to.
+    peer: Option<IpEndpoint>,
+    /// An error reported by ICMP about a datagram sent to `peer`.
+    error: Option<NetError>,
+    /// Copies of datagrams fanned out to this socket, waiting to be received.
+    backlog: VecDeque<UdpDatagram>,
+    backlog_bytes: usize,
+    /// The source, length and ancillary data of the datagrams delivered to
+    /// this socket by smoltcp, in arrival order.
+    recv_meta: VecDeque<(IpEndpoint, usize, UdpRecvMeta)>,
+}
+
+impl UdpBinding {
+    /// Whether a new binding may share the port with this one.
+    ///
+    /// Follows Linux: overlapping addresses (a wildcard overlaps with
+    /// everything) conflict unless both sockets set `SO_REUSEADDR`, or both
+    /// set `SO_REUSEPORT`.
+    fn conflicts_with(&self, addr: Option<IpAddress>, reuse_addr: bool, reuse_port: bool) -> bool {
+        let overlaps = match (self.addr, addr) {
+            (Some(a), Some(b)) => a == b,
+            _ => true,
+        };
+        overlaps && !(self.reuse_addr && reuse_addr) && !(self.reuse_port && reuse_port)
+    }
+
+    #[inline]
+    fn accepts(&self, dst: IpAddress) -> bool {
+        self.addr.map_or(true, |addr| addr == dst)
+    }
+
+    /// Whether a datagram from `src` passes the filter of `connect`.
+    #[inline]
+    fn accepts_from(&self, src: IpEndpoint) -> bool {
+        self.peer.map_or(true, |peer| peer_accepts(peer, src))
+    }
+
+    fn push_backlog(&mut self, payload: &[u8], src: IpEndpoint, meta: UdpRecvMeta) {
+        if self.backlog_bytes + payload.len() > UDP_RX_BUF_LEN {
+            warn!("UDP socket {}: fan-out backlog full, dropped", self.handle);
+            return;
+        }
+        self.backlog_bytes += payload.len();
+        self.backlog.push_back(UdpDatagram {
+            payload: payload.to_vec(),
+            src,
+            meta,
+        });
+    }
+
+    fn push_recv_meta(&mut self, src: IpEndpoint, len: usize, meta: UdpRecvMeta) {
+        if self.recv_meta.len() == MAX_RECV_META {
+            self.recv_meta.pop_front();
+        }
+        self.recv_meta.push_back((src, len, meta));
+    }
+}
+
+/// The bound UDP sockets, grouped by port.
+///
+/// smoltcp hands every datagram to exactly one socket, so this table is what
+/// detects bind conflicts and lets several sockets share a port.
+pub struct UdpTable {
+    udp: Mutex<BTreeMap<u16, Vec<UdpBinding>>>,
+}
+
+impl Default for UdpTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UdpTable {
+    pub const fn new() -> Self {
+        Self {
+            udp: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Bind the socket `handle` to `endpoint`.
+    ///
+    /// If the port is 0, a free ephemeral port is picked. Returns the bound
+    /// endpoint, or [`Err(AddrInUse)`](NetError::AddrInUse) if another socket
+    /// owns the address and the reuse options don't allow sharing it.
+    pub fn bind(
+        &self,
+        mut endpoint: IpListenEndpoint,
+        handle: SocketHandle,
+        reuse_addr: bool,
+        reuse_port: bool,
+    ) -> NetResult<IpListenEndpoint> {
+        let mut table = self.udp.lock();
+        if endpoint.port == 0 {
+            endpoint.port = alloc_ephemeral_port(|port| table.contains_key(&port))?;
+        }
+        let bindings = table.entry(endpoint.port).or_default();
+        if bindings
+            .iter()
+            .any(|binding| binding.conflicts_with(endpoint.addr, reuse_addr, reuse_port))
+        {
+            warn!("UDP socket {}: {} is in use", handle, endpoint);
+            return Err(NetError::AddrInUse);
+        }
+        bindings.push(UdpBinding {
+            handle,
+            addr: endpoint.addr,
+            reuse_addr,
+            reuse_port,
+            peer: None,
+            error: None,
+            backlog: VecDeque::new(),
+            backlog_bytes: 0,
+            recv_meta: VecDeque::new(),
+        });
+        Ok(endpoint)
+    }
+
+    /// Remove the binding of `handle` on `port`, dropping its pending datagrams.
+    pub fn unbind(&self, port: u16, handle: SocketHandle) {
+        let mut table = self.udp.lock();
+        if let Some(bindings) = table.get_mut(&port) {
+            bindings.retain(|binding| binding.handle != handle);
+            if bindings.is_empty() {
+                table.remove(&port);
+            }
+        }
+    }
+
+    /// Record that the socket `handle` is connected to `peer`, or no longer
+    /// connected with `None`.
+    pub fn connect(&self, port: u16, handle: SocketHandle, peer: Option<IpEndpoint>) {
+        self.with_binding(port, handle, |binding| {
+            binding.peer = peer;
+            binding.error = None;
+        });
+    }
+
+    /// Whether an ICMP error is pending for `handle`.
+    pub fn has_error(&self, port: u16, handle: SocketHandle) -> bool {
+        self.with_binding(port, handle, |binding| binding.error.is_some())
+            .unwrap_or(false)
+    }
+
+    /// Take the pending ICMP error of `handle`.
+    pub fn take_error(&self, port: u16, handle: SocketHandle) -> Option<NetError> {
+        self.with_binding(port, handle, |binding| binding.error.take())
+            .flatten()
+    }
+
+    /// Report an ICMP error about a datagram sent from `local` to `remote`.
+    ///
+    /// Like Linux without `IP_RECVERR`, only the sockets connected to
+    /// `remote` get it.
+    pub fn incoming_icmp_error(&self, local: IpEndpoint, remote: IpEndpoint, err: NetError) {
+        let mut table = self.udp.lock();
+        let Some(bindings) = table.get_mut(&local.port) else {
+            return;
+        };
+        for binding in bindings.iter_mut() {
+            if binding.peer == Some(remote) && binding.accepts(local.addr) {
+                info!("UDP socket {}: {:?} from {}", binding.handle, err, remote);
+                binding.error = Some(err);
+                SOCKET_EVENTS.notify_readable(WakeKey::Socket(binding.handle));
+                SOCKET_EVENTS.notify_writable(WakeKey::Socket(binding.handle));
+            }
+        }
+    }
+
+    /// Whether datagrams fanned out to `handle` are waiting.
+    pub fn has_backlog(&self, port: u16, handle: SocketHandle) -> bool {
+        self.with_binding(port, handle, |binding| !binding.backlog.is_empty())
+            .unwrap_or(false)
+    }
+
+    /// Take (or with `peek`, look at) the oldest fanned-out datagram of `handle`.
+    pub fn recv_backlog<F, T>(&self, port: u16, handle: SocketHandle, peek: bool, f: F) -> Option<T>
+    where
+        F: FnOnce(&UdpDatagram) -> T,
+    {
+        self.with_binding(port, handle, |binding| {
+            if peek {
+                return binding.backlog.front().map(f);
+            }
+            let datagram = binding.backlog.pop_front()?;
+            binding.backlog_bytes -= datagram.payload.len();
+            Some(f(&datagram))
+        })
+        .flatten()
+    }
+
+    /// Take (or with `peek`, look at) the ancillary data of a datagram from
+    /// `src` of `len` bytes that smoltcp delivered to `handle`.
+    ///
+    /// The data of older datagrams, which smoltcp dropped or which were
+    /// received without it, is discarded on the way.
+    pub fn recv_meta(
+        &self,
+        port: u16,
+        handle: SocketHandle,
+        src: IpEndpoint,
+        len: usize,
+        peek: bool,
+    ) -> Option<UdpRecvMeta> {
+        self.with_binding(port, handle, |binding| {
+            let pos = binding
+                .recv_meta
+                .iter()
+                .position(|&(meta_src, meta_len, _)| meta_src == src && meta_len == len)?;
+            binding.recv_meta.drain(..pos);
+            let (_, _, meta) = if peek {
+                *binding.recv_meta.front()?
+            } else {
+                binding.recv_meta.pop_front()?
+            };
+            Some(meta)
+        })
+        .flatten()
+    }
+
+    /// Record the ancillary data of a received datagram, and deliver a copy
+    /// of it to every socket sharing the port if it is a broadcast or
+    /// multicast one (`fan_out`).
+    ///
+    /// smoltcp itself only queues the datagram on the first socket bound to
+    /// the port, so the others get theirs through the backlog. Returns
+    /// whether smoltcp may queue it: not if that socket is connected
+    /// elsewhere, and the datagram then goes to another socket instead.
+    pub fn incoming_udp_packet(
+        &self,
+        src: IpEndpoint,
+        dst: IpEndpoint,
+        payload: &[u8],
+        meta: UdpRecvMeta,
+        fan_out: bool,
+    ) -> bool {
+        let mut table = self.udp.lock();
+        let Some(bindings) = table.get_mut(&dst.port) else {
+            return true;
+        };
+        let delivered = delivered_socket(bindings, dst, fan_out);
+        let mut redirect = !fan_out
+            && bindings
+                .iter()
+                .any(|binding| Some(binding.handle) == delivered && !binding.accepts_from(src));
+        let mut accepted = true;
+        for binding in bindings.iter_mut() {
+            if Some(binding.handle) == delivered {
+                accepted = binding.accepts_from(src);
+                if accepted {
+                    binding.push_recv_meta(src, payload.len(), meta);
+                }
+            } else if (fan_out || redirect)
+                && binding.accepts(dst.addr)
+                && binding.accepts_from(src)
+            {
+                info!("UDP socket {}: fan-out {} -> {}", binding.handle, src, dst);
+                binding.push_backlog(payload, src, meta);
+                SOCKET_EVENTS.notify_readable(WakeKey::Socket(binding.handle));
+                redirect = false;
+            }
+        }
+        accepted
+    }
+
+    /// Deliver a copy of a multicast datagram sent by this host to every
+    /// socket bound to its port (`IP_MULTICAST_LOOP`).
+    pub fn loopback_multicast(
+        &self,
+        src: IpEndpoint,
+        dst: IpEndpoint,
+        payload: &[u8],
+        meta: UdpRecvMeta,
+    ) {
+        let mut table = self.udp.lock();
+        let Some(bindings) = table.get_mut(&dst.port) else {
+            return;
+        };
+        for binding in bindings.iter_mut() {
+            if !binding.accepts(dst.addr) || !binding.accepts_from(src) {
+                continue;
+            }
+            info!("UDP socket {}: loopback {} -> {}", binding.handle, src, dst);
+            binding.push_backlog(payload, src, meta);
+            SOCKET_EVENTS.notify_readable(WakeKey::Socket(binding.handle));
+        }
+    }
+
+    fn with_binding<F, T>(&self, port: u16, handle: SocketHandle, f: F) -> Option<T>
+    where
+        F: FnOnce(&mut UdpBinding) -> T,
+    {
+        let mut table = self.udp.lock();
+        table
+            .get_mut(&port)?
+            .iter_mut()
+            .find(|binding| binding.handle == handle)
+            .map(f)
+    }
+}
+
+/// The UDP socket smoltcp will queue a datagram to `dst` on: the first one
+/// bound to the port, as smoltcp goes through the socket set in the order of
+/// the handles.
+fn delivered_socket(
+    bindings: &[UdpBinding],
+    dst: IpEndpoint,
+    fan_out: bool,
+) -> Option<SocketHandle> {
+    bindings
+        .iter()
+        .filter(|binding| fan_out || binding.accepts(dst.addr))
+        .map(|binding| binding.handle)
+        .min()
+}
+
+/// Whether a socket connected to `peer` accepts a datagram from `src`.
+///
+/// An unspecified address or port in `peer` matches any.
+fn peer_accepts(peer: IpEndpoint, src: IpEndpoint) -> bool {
+    (is_unspecified(peer.addr) || peer.addr == src.addr)
+        && (peer.port == 0 || peer.port == src.port)
+}
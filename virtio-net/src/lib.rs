@@ -2,20 +2,44 @@
 extern crate alloc;
 use alloc::boxed::Box;
 use core::any::Any;
-use netcore::common::NetError;
+use netcore::common::{NetError, IPV4_MIN_MTU, STANDARD_MTU};
 use netcore::{EthernetAddress, Medium, NetBufOps, NetDriverOps};
 use virtio_drivers::device::net::{RxBuffer, TxBuffer, VirtIONet};
 use virtio_drivers::transport::Transport;
 use virtio_drivers::Hal;
 
+/// The size of the `virtio_net_hdr` in front of every frame.
+const NET_HDR_LEN: usize = 12;
+/// The size of the Ethernet header.
+const ETHER_HDR_LEN: usize = 14;
+/// The MTU of jumbo frames, the largest the wrapper supports.
+pub const JUMBO_MTU: usize = 9000;
+/// The length of the receive buffers that fit jumbo frames.
+pub const JUMBO_BUF_LEN: usize = NET_HDR_LEN + ETHER_HDR_LEN + JUMBO_MTU;
+
 pub struct VirtIONetDeviceWrapper<H: Hal, T: Transport, const QS: usize> {
     inner: VirtIONet<H, T, QS>,
+    mtu: usize,
+    /// The largest MTU whose frames fit the receive buffers.
+    max_mtu: usize,
 }
 
 impl<H: Hal, T: Transport, const QS: usize> VirtIONetDeviceWrapper<H, T, QS> {
+    /// Creates the device with receive buffers of `buf_len` bytes.
+    ///
+    /// The MTU starts at [`STANDARD_MTU`]. It can be raised with `set_mtu` up
+    /// to what the buffers fit, and up to [`JUMBO_MTU`] with buffers of
+    /// [`JUMBO_BUF_LEN`].
     pub fn new(transport: T, buf_len: usize) -> Self {
         let device = VirtIONet::<H, T, QS>::new(transport, buf_len).unwrap();
-        VirtIONetDeviceWrapper { inner: device }
+        let max_mtu = buf_len
+            .saturating_sub(NET_HDR_LEN + ETHER_HDR_LEN)
+            .min(JUMBO_MTU);
+        VirtIONetDeviceWrapper {
+            inner: device,
+            mtu: STANDARD_MTU.min(max_mtu),
+            max_mtu,
+        }
     }
 }
 unsafe impl<H: Hal, T: Transport, const QS: usize> Sync for VirtIONetDeviceWrapper<H, T, QS> {}
@@ -75,6 +99,35 @@ impl<H: Hal, T: Transport, const QS: usize> NetDriverOps for VirtIONetDeviceWrap
         let buf = self.inner.new_tx_buffer(size);
         Ok(Box::new(TxBufWrapper(buf)))
     }
+
+    fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    fn set_mtu(&mut self, mtu: usize) -> Result<(), NetError> {
+        if !(IPV4_MIN_MTU..=self.max_mtu).contains(&mtu) {
+            return Err(NetError::InvalidInput);
+        }
+        self.mtu = mtu;
+        Ok(())
+    }
+
+    // TODO: offload checksums with `checksum_offload`, `checksum_verified`
+    // and `set_checksum_partial`. It needs virtio-drivers to negotiate
+    // `VIRTIO_NET_F_CSUM` and `VIRTIO_NET_F_GUEST_CSUM`, which `VirtIONet::new`
+    // leaves out of the fixed features it offers, and to expose the
+    // `virtio_net_hdr` of `RxBuffer` (`VIRTIO_NET_HDR_F_DATA_VALID`) and
+    // `TxBuffer` (`VIRTIO_NET_HDR_F_NEEDS_CSUM`, `csum_start`, `csum_offset`),
+    // which it fills and strips itself. Until then the checksums stay in
+    // software, the default.
+
+    // TODO: offload TCP segmentation with `tcp_segmentation_offload`,
+    // `set_segmentation` and `segmentation`, on top of the checksums. It needs
+    // virtio-drivers to negotiate `VIRTIO_NET_F_HOST_TSO4` and
+    // `VIRTIO_NET_F_GUEST_TSO4`, to expose `gso_type`, `gso_size` and
+    // `hdr_len` in the `virtio_net_hdr`, and to take receive buffers of 64 KiB
+    // for the coalesced segments. Until then segmentation stays in software,
+    // the default.
 }
 
 struct RxBufWrapper(RxBuffer);